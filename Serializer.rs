@@ -1,14 +1,29 @@
-/**
+/*
  * Luis Ferreirinha
  */
 
 
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::{BitOr, Index};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicUsize};
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
+#[cfg(feature = "serde")]
+extern crate serde;
 
-#[derive(Debug)]
+
+#[derive(Debug, Clone)]
 enum Json {
     Number(f64),
     String(String),
@@ -18,6 +33,129 @@ enum Json {
     Null,
 }
 
+
+/**
+ * Builds a Json using json syntax, e.g. json!({ "name": "Jason Ray", "age": 31, "languages": ["Java"] })
+ * Values can be any expression that converts into a Json and keys can be string literals or (expressions)
+ */
+macro_rules! json {
+    (null) => { Json::Null };
+    ([ $($elements:tt)* ]) => { Json::Array(json_internal!(@array [] $($elements)*)) };
+    ({}) => { Json::Object(::std::collections::BTreeMap::new()) };
+    ({ $($fields:tt)* }) => {{
+        let mut object = ::std::collections::BTreeMap::new();
+        json_internal!(@object object $($fields)*);
+        Json::Object(object)
+    }};
+    ($other:expr) => { Json::from($other) };
+}
+
+// Munches the elements of arrays and the fields of objects for json!, one value at a time, since values
+// can be expressions made of several tokens. Nested arrays and objects go back through json!
+macro_rules! json_internal {
+    (@array [$($done:expr,)*]) => { vec![$($done,)*] };
+    (@array [$($done:expr,)*] , $($rest:tt)*) => { json_internal!(@array [$($done,)*] $($rest)*) };
+    (@array [$($done:expr,)*] null $($rest:tt)*) => { json_internal!(@array [$($done,)* Json::Null,] $($rest)*) };
+    (@array [$($done:expr,)*] [$($array:tt)*] $($rest:tt)*) => { json_internal!(@array [$($done,)* json!([$($array)*]),] $($rest)*) };
+    (@array [$($done:expr,)*] {$($object:tt)*} $($rest:tt)*) => { json_internal!(@array [$($done,)* json!({$($object)*}),] $($rest)*) };
+    (@array [$($done:expr,)*] $next:expr , $($rest:tt)*) => { json_internal!(@array [$($done,)* json!($next),] $($rest)*) };
+    (@array [$($done:expr,)*] $last:expr) => { json_internal!(@array [$($done,)* json!($last),]) };
+
+    (@object $object:ident) => {};
+    (@object $object:ident , $($rest:tt)*) => { json_internal!(@object $object $($rest)*) };
+    (@object $object:ident $key:tt : null $($rest:tt)*) => {
+        $object.insert(String::from($key), Json::Null);
+        json_internal!(@object $object $($rest)*);
+    };
+    (@object $object:ident $key:tt : [$($array:tt)*] $($rest:tt)*) => {
+        $object.insert(String::from($key), json!([$($array)*]));
+        json_internal!(@object $object $($rest)*);
+    };
+    (@object $object:ident $key:tt : {$($fields:tt)*} $($rest:tt)*) => {
+        $object.insert(String::from($key), json!({$($fields)*}));
+        json_internal!(@object $object $($rest)*);
+    };
+    (@object $object:ident $key:tt : $value:expr , $($rest:tt)*) => {
+        $object.insert(String::from($key), json!($value));
+        json_internal!(@object $object $($rest)*);
+    };
+    (@object $object:ident $key:tt : $value:expr) => {
+        $object.insert(String::from($key), json!($value));
+    };
+}
+
+macro_rules! json_from_number {
+    ($($number:ty),*) => {
+        $(
+            impl From<$number> for Json {
+                fn from(n: $number) -> Json {
+                    Json::Number(n as f64)
+                }
+            }
+        )*
+    };
+}
+
+json_from_number!(f64, f32, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Boolean(b)
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl<'a> From<&'a str> for Json {
+    fn from(s: &'a str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl<'a> From<&'a String> for Json {
+    fn from(s: &'a String) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<()> for Json {
+    fn from(_: ()) -> Json {
+        Json::Null
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Json {
+        match value {
+            Some(value) => value.into(),
+            None => Json::Null,
+        }
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(values: Vec<T>) -> Json {
+        Json::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<K: Into<String>, V: Into<Json>> From<BTreeMap<K, V>> for Json {
+    fn from(map: BTreeMap<K, V>) -> Json {
+        Json::Object(map.into_iter().map(|(key, value)| (key.into(), value.into())).collect())
+    }
+}
+
+impl<K: Into<String>, V: Into<Json>> From<HashMap<K, V>> for Json {
+    fn from(map: HashMap<K, V>) -> Json {
+        Json::Object(map.into_iter().map(|(key, value)| (key.into(), value.into())).collect())
+    }
+}
+
+
 enum JC {
     Number(f64),
     String(String),
@@ -29,14 +167,19 @@ enum JC {
     ObjectStart,
     ObjectEnd,
     Stream(mpsc::Receiver<JC>), // Used to receive the values of Arrays or Objects
+    Skipped, // Sent instead of a value that is not needed, see serialise_json_with_projection
 }
 
 /**
  * Protocols:
  * Simple values: Sent in their own JC
  * Array: ArrayStart -> ArrayLen -> (Stream channel for each array value) -> ArrayEnd
- * Object: ObjectStart -> ArrayLen -> (for each object: String -> Stream channel) -> ArrayEnd
- * Note: Map acessor does not strictly follow the array protocol, but this is accounted for in the deserializer function
+ * Object: ObjectStart -> ArrayLen -> (for each object: String -> Stream channel) -> ObjectEnd
+ * Note: Map acessor with EvalOptions::legacy_map_shape does not strictly follow the array protocol, but this is accounted
+ * for in the deserializer function
+ * Streams coming from other producers can be checked against these protocols with validate_jc
+ * Streams sent by serialise_json_with_projection can have Skipped in place of any array or object value, whether it
+ * would have been a Stream or a simple value
  */
 fn serialise_json(val: &Json, sender: mpsc::Sender<JC>) {
 
@@ -83,383 +226,9298 @@ fn serialise_json(val: &Json, sender: mpsc::Sender<JC>) {
     }
 }
 
+/**
+ * Serialises the json into a channel that holds all of its packets, for tests that feed a single stage
+ */
+#[cfg(test)]
+fn jc_stream(val: &Json) -> mpsc::Receiver<JC> {
+    let (sender, receiver) = mpsc::channel();
+    serialise_json(val, sender);
+    receiver
+}
 
-fn deserialise_json(receiver: mpsc::Receiver<JC>) -> Json {
-    match receiver.recv().unwrap() {
-        JC::Null => return Json::Null,
-        JC::Number(num) => return Json::Number(num),
-        JC::String(str) => return Json::String(str.to_string()),
-        JC::Boolean(b) => return Json::Boolean(b),
-        JC::ArrayStart => {
-            let mut array: Vec<Json> = vec![];
-            match receiver.recv().unwrap() {
-                JC::ArrayLen(arrlen) => {
-                    for _ in 0..arrlen {
-                        match receiver.recv().unwrap() {
-                            // If we receive a stream we know its an object or Array so we will
-                            // deserialise recursively
-                            JC::Stream(value_receiver) => {
-                                let new_json_value = deserialise_json(value_receiver);
-                                array.push(new_json_value);
-                            },
-                            // Because Map Acessor does not send streams for values that are not arrays or objects
-                            // We need to expect the simpler values in deserialise
-                            JC::Number(num) => array.push(Json::Number(num)),
-                            JC::Null => array.push(Json::Null),
-                            JC::String(str) => array.push(Json::String(str)),
-                            JC::Boolean(bol) => array.push(Json::Boolean(bol)),
-                            _ => panic!("Expected a JC Stream or a simple value (Deserialise Array)"),
-                        }
-                    }
-                    match receiver.recv().unwrap() {
-                        JC::ArrayEnd => {
-                            return Json::Array(array);
-                        },
-                        _ => panic!("Expected ArrayEnd (Deserialise Array)")
-                        }
-                },
-                _ => panic!("Expected ArryLen (Deserialise Array)"),
+/**
+ * Xorshift generator for tests that run over many generated documents, seeded so failures can be reproduced
+ */
+#[cfg(test)]
+struct TestRng(u64);
+
+#[cfg(test)]
+impl TestRng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+
+    /**
+     * Small document with few distinct keys and values, so that generated documents often share parts
+     */
+    fn json(&mut self, depth: u32) -> Json {
+        match if depth == 0 { self.below(4) } else { self.below(7) } {
+            0 => Json::Null,
+            1 => Json::Boolean(self.below(2) == 0),
+            2 => Json::Number(self.below(10) as f64),
+            3 => Json::String(format!("s{}", self.below(5))),
+            4 | 5 => Json::Array((0..self.below(5)).map(|_| self.json(depth - 1)).collect()),
+            _ => Json::Object((0..self.below(4)).map(|_| (format!("k{}", self.below(4)), self.json(depth - 1))).collect()),
+        }
+    }
+}
+
+
+/**
+ * Callbacks for walking a json value, either deserialised (walk_json) or streamed through JC packets (walk_jc).
+ * Arrays and objects are entered with their size, and every object value is preceded by its key.
+ * Values that were skipped by serialise_json_with_projection are reported with on_skipped instead.
+ * All methods do nothing by default, so a visitor only implements the ones it needs
+ */
+trait JcVisitor {
+    fn on_scalar(&mut self, _value: Json) {}
+    fn enter_array(&mut self, _len: usize) {}
+    fn exit_array(&mut self) {}
+    fn enter_object(&mut self, _len: usize) {}
+    fn on_key(&mut self, _key: String) {}
+    fn exit_object(&mut self) {}
+    fn on_skipped(&mut self) {}
+
+    /**
+     * Called with the channel of every array or object value. Walks the value by default, visitors that only look
+     * at the top of a value keep the channel instead, so that the value can be read or forwarded later
+     */
+    fn on_stream(&mut self, value_receiver: mpsc::Receiver<JC>) {
+        walk_jc(value_receiver, self);
+    }
+}
+
+fn walk_json<V: JcVisitor + ?Sized>(json: &Json, visitor: &mut V) {
+    match json {
+        Json::Array(array) => {
+            visitor.enter_array(array.len());
+            for json_value in array {
+                walk_json(json_value, visitor);
             }
+            visitor.exit_array();
         },
-        JC::ObjectStart => {
-            let mut json_object: BTreeMap<String, Json> = BTreeMap::new();
-            match receiver.recv().unwrap() {
-                JC::ArrayLen(maplen) => {
-                    for _ in 0..maplen {
-                        match  receiver.recv().unwrap() {
-                            JC::String(key) => {
-                                match receiver.recv().unwrap() {
-                                    // If we receive a stream we know its an object or Array so we will
-                                    // deserialise recursively
-                                    JC::Stream(value_receiver) => {
-                                        let new_json_value = deserialise_json(value_receiver);
-                                        json_object.insert(key, new_json_value);
-                                    },
-                                    // If we get a regular values just add it to the map
-                                    // This is expected for arrays that result of Map acessor
-                                    // but not expected for objects, but I will leave it here
-                                    // just in case, since it doesnt hurt the implementation
-                                    JC::Number(num) => {
-                                        json_object.insert(key, Json::Number(num));
-                                    },
-                                    JC::Null => {
-                                        json_object.insert(key, Json::Null);
-                                    },
-                                    JC::String(str) => {
-                                        json_object.insert(key, Json::String(str));
-                                    },
-                                    JC::Boolean(bol) => {
-                                        json_object.insert(key, Json::Boolean(bol));
-                                    },
-                                    _ => panic!("Expected a JC Stream or a simple value (Deserialise Object)"),
-                                }
-                            },
-                            _ => panic!("Expected a key for a object field (Deserialise object)")
-                            
-                        }
-                    }
-                    match receiver.recv().unwrap() {
-                        JC::ObjectEnd => {
-                            return Json::Object(json_object);
-                        },
-                        _ => panic!("Expected a ObjectEnd (Deserialise Object)")
-                    }
-                },
-                _ => panic!("Expected ArrayLen after ObjectStart (Deserialise Object)")
+        Json::Object(map) => {
+            visitor.enter_object(map.len());
+            for (key, json_value) in map {
+                visitor.on_key(key.to_string());
+                walk_json(json_value, visitor);
             }
+            visitor.exit_object();
         },
-        _ => panic!("Unexpected ArrayEnd, ObjectEnd, ArryLen or Stream here. (deserialiser)")
+        _ => visitor.on_scalar(json.clone()),
     }
 }
 
-#[derive(Debug)]
-enum Acessor {
-    ObjectField(String, Box<Acessor>),
-    ArrayEntry(usize, Box<Acessor>),
-    Map(Box<Acessor>),
-    End,
+/**
+ * Walks a json value streamed through the receiver channel, following the protocols described above serialise_json
+ */
+fn walk_jc<V: JcVisitor + ?Sized>(receiver: mpsc::Receiver<JC>, visitor: &mut V) {
+    let first_packet = receiver.recv().unwrap();
+    walk_jc_from(first_packet, receiver, visitor);
 }
 
-
 /**
- * This function is used to completly consume a value that is streamed thought the receiver channel
- * Used when applying acessor to arrays and objects, and we need to get rid of the values that do not
- * match the index or label in the acessor. Since these are streamed thought a receiver channel by the
- * serializer and these channels are rendvouz, the serialiser thread will hang waiting to send the rest of the
- * json value throught the channel, so this prevents it.
+ * Same as walk_jc, but for values whose first packet was already received
  */
-fn consume_value(receiver: mpsc::Receiver<JC>) {
-    match receiver.recv().unwrap() {
-        JC::Number(_) => (), // Throw away the values
-        JC::String(_) => (),
-        JC::Boolean(_) => (),
-        JC::Null => (),
+fn walk_jc_from<V: JcVisitor + ?Sized>(first_packet: JC, receiver: mpsc::Receiver<JC>, visitor: &mut V) {
+    match first_packet {
+        JC::Null => visitor.on_scalar(Json::Null),
+        JC::Number(num) => visitor.on_scalar(Json::Number(num)),
+        JC::String(str) => visitor.on_scalar(Json::String(str)),
+        JC::Boolean(b) => visitor.on_scalar(Json::Boolean(b)),
         JC::ArrayStart => {
             match receiver.recv().unwrap() {
                 JC::ArrayLen(arrlen) => {
+                    visitor.enter_array(arrlen);
                     for _ in 0..arrlen {
-                        match receiver.recv().unwrap() {
-                            JC::Stream(val_stream) => {
-                                consume_value(val_stream);
-                            },
-                            _ => panic!("Something went wrong. Serialised Json packets are out of order")
-                        }
+                        let value_packet = receiver.recv().unwrap();
+                        walk_value_packet(value_packet, visitor, "Walk Array");
+                    }
+                    match receiver.recv().unwrap() {
+                        JC::ArrayEnd => visitor.exit_array(),
+                        _ => panic!("Expected ArrayEnd (Walk Array)")
                     }
                 },
-                _ => panic!("Something went wrong. Serialised Json packets are out of order")
+                _ => panic!("Expected ArryLen (Walk Array)"),
             }
         },
         JC::ObjectStart => {
             match receiver.recv().unwrap() {
                 JC::ArrayLen(maplen) => {
+                    visitor.enter_object(maplen);
                     for _ in 0..maplen {
                         match receiver.recv().unwrap() {
-                            JC::String(_) => {
-                                match receiver.recv().unwrap() {
-                                    JC::Stream(val_stream) => {
-                                        consume_value(val_stream);
-                                    },
-                                    _ => panic!("Something went wrong. Serialised Json packets are out of order")
-                                }
+                            JC::String(key) => {
+                                visitor.on_key(key);
+                                let value_packet = receiver.recv().unwrap();
+                                walk_value_packet(value_packet, visitor, "Walk Object");
                             },
-                            _ => panic!("Something went wrong. Serialised Json packets are out of order")
+                            _ => panic!("Expected a key for a object field (Walk Object)")
                         }
                     }
+                    match receiver.recv().unwrap() {
+                        JC::ObjectEnd => visitor.exit_object(),
+                        _ => panic!("Expected a ObjectEnd (Walk Object)")
+                    }
                 },
-                _ => panic!("Something went wrong. Serialised Json packets are out of order"),
+                _ => panic!("Expected ArrayLen after ObjectStart (Walk Object)")
             }
         },
-        _ => panic!("Something went wrong. Serialised Json packets are out of order"),
+        _ => panic!("Unexpected ArrayEnd, ObjectEnd, ArryLen or Stream here. (Walk)")
     }
 }
 
+/**
+ * Walks a value of an array or object. If we receive a stream we know its an object or array, so we walk it
+ * recursively. Because the legacy shape of the Map acessor does not send streams for the array values,
+ * simple values are expected here as well
+ */
+fn walk_value_packet<V: JcVisitor + ?Sized>(value_packet: JC, visitor: &mut V, context: &str) {
+    match value_packet {
+        JC::Stream(value_receiver) => visitor.on_stream(value_receiver),
+        JC::Skipped => visitor.on_skipped(),
+        JC::Number(num) => visitor.on_scalar(Json::Number(num)),
+        JC::Null => visitor.on_scalar(Json::Null),
+        JC::String(str) => visitor.on_scalar(Json::String(str)),
+        JC::Boolean(bol) => visitor.on_scalar(Json::Boolean(bol)),
+        _ => panic!("Expected a JC Stream or a simple value ({})", context),
+    }
+}
 
-fn eval(acessor: &Acessor, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) {
-    // Recursively apply acessor with each call of this function
-    match acessor {
-        Acessor::ObjectField(label , next_acessor) => {
-            match receiver.recv().unwrap() {
-                JC::ObjectStart => {
-                    match receiver.recv().unwrap() {
-                        JC::ArrayLen(maplen) => {
-                            for _ in 0..maplen {
-                                match receiver.recv().unwrap() {
-                                    JC::String(obj_label) => {
-                                        match receiver.recv().unwrap() {
-                                            JC::Stream(value_stream) => {
-                                                if obj_label.eq(label) {
-                                                    // recursively handle the values obtained by applying an acesor
-                                                    let sender_clone = sender.clone();
-                                                    eval(next_acessor, value_stream, sender_clone);
-                                                } else {
-                                                    consume_value(value_stream); // Consume the stream and therefore the json value
-                                                }
-                                            },
-                                            _ => panic!("Expected Object Stream (Eval ObjectField)")
-                                        }
-                                    },
-                                    _ => panic!("Expected Object Label (Eval ObjectField)")
-                                }
-                            }
-                            match receiver.recv().unwrap() {
-                                JC::ObjectEnd => (), // consume object end
-                                _ => panic!("Expected Object End (eval objectfield)")
-                            }
-                        },
-                        _ => panic!("Expected ArrayLen (Eval ObjectField)")
-                    }
-                },
-                _ => panic!("Cannot apply ObjectField Acessor to this Json Value")
-            }
-        },
-        Acessor::ArrayEntry(index, next_acessor) => {
-            match receiver.recv().unwrap() {
-                JC::ArrayStart => {
-                    match receiver.recv().unwrap() {
-                        JC::ArrayLen(arrlen) => {
-                            for i in 0..arrlen {
-                                match receiver.recv().unwrap() {
-                                    JC::Stream(value_stream) => {
-                                        if i == *index {
-                                            // recursively handle the values obtained by applying an acesor
-                                            let sender_clone = sender.clone();
-                                            eval(next_acessor, value_stream, sender_clone);
-                                        } else {
-                                            consume_value(value_stream); // consume the stream and the value
-                                        }
-                                    },
-                                    _ => panic!("Expected Array Value Stream (Eval ArrayEntry)")
-                                }
-                            }
-                            match receiver.recv().unwrap() {
-                                JC::ArrayEnd => (), // Consume Array End
-                                _ => panic!("Expected Array End (Eval ArrayEntry)")
-                            }
-                        },
-                        _ => panic!("Expected ArrayLen (Eval ArrayEntry)")
-                    }
-                },
-                _ => panic!("Cannot apply Index accessor to this Json Value")
-            }
-        },
-        Acessor::Map(next_acessor) => {
-            // Must send a new array composed of the results of the acessor
-            match receiver.recv().unwrap() {
-                JC::ArrayStart => {
-                    sender.send(JC::ArrayStart).unwrap(); // Signal the start of a new array
-                    match receiver.recv().unwrap() {
-                        JC::ArrayLen(arrlen) => {
-                            sender.send(JC::ArrayLen(arrlen)).unwrap(); // send length of array
-                            for _ in 0..arrlen {
-                                match receiver.recv().unwrap() {
-                                    JC::Stream(value_stream) => {
-                                        // recursively handle the values obtained by applying the next acessor to each
-                                        // value of the array
-                                        let sender_clone = sender.clone();
-                                        eval(next_acessor, value_stream, sender_clone);
-                                    },
-                                    _ => panic!("Expected Value Stream (Eval Map)")
-                                }
-                            }
-                            match receiver.recv().unwrap() {
-                                JC::ArrayEnd => sender.send(JC::ArrayEnd).unwrap(), // send array end
-                                _ => panic!("Expected array end (Eval Map)")
-                            }
-                        },
-                        _ => panic!("Expected ArrayLen (Eval Map)")
-                    }
-                },
-                _ => panic!("Cannot apply Map to this Json Value")
-            }
-        },
-        Acessor::End => {
-            // When End acessor is reached, we simply pass along the result of applying the previous acessors
-            // to the sender channel
-            let serial_json_packet = receiver.recv().unwrap();
-            match serial_json_packet {
-                JC::Number(_) => sender.send(serial_json_packet).unwrap(),
-                JC::String(_) => sender.send(serial_json_packet).unwrap(),
-                JC::Boolean(_) => sender.send(serial_json_packet).unwrap(),
-                JC::Null => sender.send(serial_json_packet).unwrap(),
-                JC::ArrayStart => {
-                    sender.send(serial_json_packet).unwrap(); // Send Array Start
-                    match receiver.recv().unwrap() {
-                        JC::ArrayLen(arrlen) => {
-                            sender.send(JC::ArrayLen(arrlen)).unwrap(); // Send Array Len
-                            for _ in 0..arrlen {
-                                match receiver.recv().unwrap() {
-                                    JC::Stream(value_stream) => sender.send(JC::Stream(value_stream)).unwrap(),
-                                    _ => panic!("Expected Array Value Stream (eval end array)")
-                                }
-                            }
-                            match receiver.recv().unwrap() {
-                                JC::ArrayEnd => sender.send(JC::ArrayEnd).unwrap(), // Send Array End
-                                _ => panic!("Expected Array End (Eval End Array)")
-                            }
-                        },
-                        _ => panic!("Expected a ArrayLen (Eval End Array)")
-                    }
-                },
-                JC::ObjectStart => {
-                    sender.send(serial_json_packet).unwrap(); // Send Object Start
-                    match receiver.recv().unwrap() {
-                        JC::ArrayLen(maplen) => {
-                            sender.send(JC::ArrayLen(maplen)).unwrap(); // Send Array Len
-                            for _ in 0..maplen {
-                                let key = receiver.recv().unwrap();
-                                match key {
-                                    JC::String(_) => sender.send(key).unwrap(), // Send key
-                                    _ => panic!("Expected key for object value (Eval End of Object)")
-                                }
-                                match receiver.recv().unwrap() {
-                                    JC::Stream(value_stream) => sender.send(JC::Stream(value_stream)).unwrap(),
-                                    _ => panic!("Expected Object Value Stream (Eval End of Object)")
-                                }
-                            }
-                            match receiver.recv().unwrap() {
-                                JC::ObjectEnd => sender.send(JC::ObjectEnd).unwrap(), // Send Object End
-                                _ => panic!("Expected Object End (Eval End of Object)")
-                            }
-                        },
-                        _ => panic!("Expected ArrayLen (Eval End of Object)")
-                    }
-                },
-                _ => panic!("Unexpected ArrayEnd, ObjectEnd, ArryLen or Stream here. (eval End)")
 
-            }
-        }
-    }
+/**
+ * Visitor that builds the Json being walked
+ */
+struct JsonBuilder {
+    stack: Vec<(Json, Option<String>)>, // arrays and objects being built, with the key of the next object value
+    result: Option<Json>,
 }
 
-fn Printer(json: &Json, depth: usize) {
-    let mut indent = "".to_string();
-    for i in 0..depth {
-        indent = indent + " ";
+impl JsonBuilder {
+    fn new() -> Self {
+        JsonBuilder { stack: vec![], result: None }
     }
-    match json {
-        Json::Null => println!("{}", indent),
-        Json::String(s) => println!("{}\"{}\"", indent, s),
-        Json::Number(n) => println!("{}{}", indent, n),
-        Json::Boolean(b) => println!("{}{}", indent, b),
-        Json::Array(arr) => {
-            println!("{}[", indent);
-            for jval in arr {
-                Printer(jval, depth+1);
-            }
-            println!("{}]", indent);
-        },
-        Json::Object(map) => {
-            println!("{}{{", indent);
-            for (key, jval) in map.iter() {
-                print!(" {}\"{}\": ", indent, key);
-                Printer(jval, depth+1);
-            }
-            println!("{}}}", indent);
+
+    fn add_value(&mut self, value: Json) {
+        match self.stack.last_mut() {
+            Some((Json::Array(array), _)) => array.push(value),
+            Some((Json::Object(map), key)) => {
+                map.insert(key.take().expect("Expected a key for a object field (Json Builder)"), value);
+            },
+            _ => self.result = Some(value),
         }
     }
 }
 
-fn main() {
-    
+impl JcVisitor for JsonBuilder {
+    fn on_scalar(&mut self, value: Json) {
+        self.add_value(value);
+    }
+
+    fn enter_array(&mut self, len: usize) {
+        self.stack.push((Json::Array(Vec::with_capacity(len)), None));
+    }
+
+    fn enter_object(&mut self, _len: usize) {
+        self.stack.push((Json::Object(BTreeMap::new()), None));
+    }
+
+    fn on_key(&mut self, key: String) {
+        if let Some((_, pending_key)) = self.stack.last_mut() {
+            *pending_key = Some(key);
+        }
+    }
+
+    fn exit_array(&mut self) {
+        let (array, _) = self.stack.pop().unwrap();
+        self.add_value(array);
+    }
+
+    fn exit_object(&mut self) {
+        let (object, _) = self.stack.pop().unwrap();
+        self.add_value(object);
+    }
+
+    // A Json built with null in place of the Skipped values would look complete while missing parts of the value
+    fn on_skipped(&mut self) {
+        panic!("Skipped values cannot be deserialised (Json Builder)");
+    }
+}
+
+fn deserialise_json(receiver: mpsc::Receiver<JC>) -> Json {
+    let mut builder = JsonBuilder::new();
+    walk_jc(receiver, &mut builder);
+    builder.result.unwrap()
+}
+
+
+#[derive(Debug)]
+struct ProtocolError {
+    packet: usize, // Index of the offending packet, counting depth first through the streams
+    path: Acessor,
+    message: String,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "packet {} at {}: {}", self.packet, self.path, self.message)
+    }
+}
+
+fn jc_packet_name(packet: &JC) -> &'static str {
+    match packet {
+        JC::Number(_) => "Number",
+        JC::String(_) => "String",
+        JC::Boolean(_) => "Boolean",
+        JC::ArrayLen(_) => "ArrayLen",
+        JC::Null => "Null",
+        JC::ArrayStart => "ArrayStart",
+        JC::ArrayEnd => "ArrayEnd",
+        JC::ObjectStart => "ObjectStart",
+        JC::ObjectEnd => "ObjectEnd",
+        JC::Stream(_) => "Stream",
+        JC::Skipped => "Skipped",
+    }
+}
+
+/**
+ * A stream that validate_jc passed along without finding anything that breaks the protocols
+ */
+#[derive(Debug)]
+struct ValidatedStream {
+    packets: usize, // Number of packets that were checked, counting depth first through the streams
+}
+
+/**
+ * Validation stage of the pipeline. Checks the framing of every array and object, that the amount of values matches
+ * ArrayLen, that every object value comes after its key, and that values of arrays and objects are sent in their
+ * own Stream channel (or are Skipped), with nothing left in any channel after its value.
+ * Packets are passed along to the sender channel as soon as they are checked.
+ * When a packet breaks the protocols the error is returned, and the arrays and objects that were being sent are
+ * completed so that whoever reads them is not left waiting. Nothing is made up for the values that are missing:
+ * they are sent as Skipped, so that they are not mistaken for data (the keys of missing object fields are their
+ * index, and only there to keep the framing). When the first packet of the whole value is already broken,
+ * nothing is sent
+ */
+fn validate_jc(receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<ValidatedStream, ProtocolError> {
+    let mut validator = JcValidator { packet: 0, path: vec![] };
+    let (first_packet, len) = validator.read_header(&receiver)?;
+    validator.forward_value(first_packet, len, &receiver, &sender)?;
+    Ok(ValidatedStream { packets: validator.packet })
+}
+
+struct JcValidator {
+    packet: usize,
+    path: Vec<PathStep>,
+}
+
+impl JcValidator {
+    fn error(&self, message: String) -> ProtocolError {
+        ProtocolError { packet: self.packet, path: path_acessor(&self.path), message }
+    }
+
+    fn next(&mut self, receiver: &mpsc::Receiver<JC>) -> Result<JC, ProtocolError> {
+        match receiver.recv() {
+            Ok(packet) => {
+                self.packet += 1;
+                Ok(packet)
+            },
+            Err(_) => Err(self.error("stream ended before the value was complete".to_string())),
+        }
+    }
+
+    fn unexpected(&self, packet: &JC, expected: &str) -> ProtocolError {
+        // The packet was already counted by next
+        let mut error = self.error(format!("expected {} but received {}", expected, jc_packet_name(packet)));
+        error.packet -= 1;
+        error
+    }
+
+    /**
+     * Checks the first packet of a value, and the ArrayLen that follows it for arrays and objects.
+     * Nothing is sent, so a value that is broken from the start can be left out
+     */
+    fn read_header(&mut self, receiver: &mpsc::Receiver<JC>) -> Result<(JC, usize), ProtocolError> {
+        match self.next(receiver)? {
+            first_packet @ (JC::Null | JC::Number(_) | JC::String(_) | JC::Boolean(_)) => Ok((first_packet, 0)),
+            first_packet @ (JC::ArrayStart | JC::ObjectStart) => match self.next(receiver)? {
+                JC::ArrayLen(len) => Ok((first_packet, len)),
+                packet => Err(self.unexpected(&packet, "ArrayLen")),
+            },
+            packet => Err(self.unexpected(&packet, "a value")),
+        }
+    }
+
+    /**
+     * Checks the rest of a value whose header was read and passes it along. The value is always completed,
+     * even when an error is returned
+     */
+    fn forward_value(&mut self, first_packet: JC, len: usize, receiver: &mpsc::Receiver<JC>, sender: &mpsc::Sender<JC>) -> Result<(), ProtocolError> {
+        match first_packet {
+            JC::ArrayStart => {
+                sender.send(JC::ArrayStart).unwrap();
+                sender.send(JC::ArrayLen(len)).unwrap();
+                for index in 0..len {
+                    self.path.push(PathStep::Index(index));
+                    let result = self.forward_stream(receiver, sender);
+                    self.path.pop();
+                    if let Err(error) = result {
+                        for _ in index + 1..len {
+                            sender.send(JC::Skipped).unwrap();
+                        }
+                        sender.send(JC::ArrayEnd).unwrap();
+                        return Err(error);
+                    }
+                }
+                sender.send(JC::ArrayEnd).unwrap();
+                match self.next(receiver)? {
+                    JC::ArrayEnd => Ok(()),
+                    packet => Err(self.unexpected(&packet, &format!("ArrayEnd after {} values", len))),
+                }
+            },
+            JC::ObjectStart => {
+                sender.send(JC::ObjectStart).unwrap();
+                sender.send(JC::ArrayLen(len)).unwrap();
+                for index in 0..len {
+                    let result = match self.next(receiver) {
+                        Ok(JC::String(key)) => {
+                            sender.send(JC::String(key.to_string())).unwrap();
+                            self.path.push(PathStep::Field(key));
+                            let result = self.forward_stream(receiver, sender);
+                            self.path.pop();
+                            result.map_err(|error| (error, index + 1))
+                        },
+                        Ok(packet) => Err((self.unexpected(&packet, "a String key for a object field"), index)),
+                        Err(error) => Err((error, index)),
+                    };
+                    if let Err((error, missing)) = result {
+                        for missing_index in missing..len {
+                            sender.send(JC::String(missing_index.to_string())).unwrap();
+                            sender.send(JC::Skipped).unwrap();
+                        }
+                        sender.send(JC::ObjectEnd).unwrap();
+                        return Err(error);
+                    }
+                }
+                sender.send(JC::ObjectEnd).unwrap();
+                match self.next(receiver)? {
+                    JC::ObjectEnd => Ok(()),
+                    packet => Err(self.unexpected(&packet, &format!("ObjectEnd after {} fields", len))),
+                }
+            },
+            simple_value => {
+                sender.send(simple_value).unwrap();
+                Ok(())
+            },
+        }
+    }
+
+    /**
+     * Checks a value of an array or object, which must be sent in its own channel and be the only thing sent there,
+     * and passes it along in a new channel. Something is always sent in place of the value, Skipped when the value
+     * is broken from the start
+     */
+    fn forward_stream(&mut self, receiver: &mpsc::Receiver<JC>, sender: &mpsc::Sender<JC>) -> Result<(), ProtocolError> {
+        match self.next(receiver) {
+            Ok(JC::Stream(value_receiver)) => {
+                let (first_packet, len) = match self.read_header(&value_receiver) {
+                    Ok(header) => header,
+                    Err(error) => {
+                        sender.send(JC::Skipped).unwrap();
+                        return Err(error);
+                    }
+                };
+                let (value_sender, forwarded_receiver) = mpsc::channel::<JC>();
+                sender.send(JC::Stream(forwarded_receiver)).unwrap();
+                self.forward_value(first_packet, len, &value_receiver, &value_sender)?;
+                if let Ok(packet) = value_receiver.recv() {
+                    return Err(self.error(format!("unexpected {} after the end of the value", jc_packet_name(&packet))));
+                }
+                Ok(())
+            },
+            Ok(JC::Skipped) => {
+                sender.send(JC::Skipped).unwrap();
+                Ok(())
+            },
+            Ok(packet) => {
+                sender.send(JC::Skipped).unwrap();
+                Err(self.unexpected(&packet, "a Stream channel"))
+            },
+            Err(error) => {
+                sender.send(JC::Skipped).unwrap();
+                Err(error)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod validate_jc_tests {
+    use super::*;
+
+    // Builds the forwarded value with the Skipped values as "<skipped>", so the tests can tell them from null
+    struct SkippedAsText(JsonBuilder);
+
+    impl JcVisitor for SkippedAsText {
+        fn on_scalar(&mut self, value: Json) {
+            self.0.on_scalar(value);
+        }
+
+        fn enter_array(&mut self, len: usize) {
+            self.0.enter_array(len);
+        }
+
+        fn exit_array(&mut self) {
+            self.0.exit_array();
+        }
+
+        fn enter_object(&mut self, len: usize) {
+            self.0.enter_object(len);
+        }
+
+        fn on_key(&mut self, key: String) {
+            self.0.on_key(key);
+        }
+
+        fn exit_object(&mut self) {
+            self.0.exit_object();
+        }
+
+        fn on_skipped(&mut self) {
+            self.0.on_scalar(Json::String("<skipped>".to_string()));
+        }
+    }
+
+    fn validate(packets: Vec<JC>) -> (Result<ValidatedStream, ProtocolError>, Option<Json>) {
+        let (sender, receiver) = mpsc::channel::<JC>();
+        for packet in packets {
+            sender.send(packet).unwrap();
+        }
+        drop(sender);
+        let (forward_sender, forward_receiver) = mpsc::channel::<JC>();
+        let result = validate_jc(receiver, forward_sender);
+        let forwarded = forward_receiver.recv().ok().map(|first_packet| {
+            let mut builder = SkippedAsText(JsonBuilder::new());
+            walk_jc_from(first_packet, forward_receiver, &mut builder);
+            builder.0.result.unwrap()
+        });
+        (result, forwarded)
+    }
+
+    fn stream(packets: Vec<JC>) -> JC {
+        let (sender, receiver) = mpsc::channel::<JC>();
+        for packet in packets {
+            sender.send(packet).unwrap();
+        }
+        JC::Stream(receiver)
+    }
+
+    #[test]
+    fn valid_streams_are_passed_along() {
+        let value = json!({"name": "Jason", "tags": ["a", [1, 2], {}], "age": null});
+        let (forward_sender, forward_receiver) = mpsc::channel::<JC>();
+        let validated = validate_jc(jc_stream(&value), forward_sender).unwrap();
+        assert_eq!(deserialise_json(forward_receiver), value);
+        assert_eq!(validated.packets, 28);
+
+        let skipped = vec![JC::ArrayStart, JC::ArrayLen(2), JC::Skipped, stream(vec![JC::Number(1.0)]), JC::ArrayEnd];
+        let (sender, receiver) = mpsc::channel::<JC>();
+        skipped.into_iter().for_each(|packet| sender.send(packet).unwrap());
+        let (forward_sender, forward_receiver) = mpsc::channel::<JC>();
+        assert_eq!(validate_jc(receiver, forward_sender).unwrap().packets, 6);
+        let forwarded: Vec<bool> = forward_receiver.iter().map(|packet| matches!(packet, JC::Skipped)).collect();
+        assert_eq!(forwarded, vec![false, false, true, false, false]);
+    }
+
+    #[test]
+    fn broken_streams_are_completed_and_reported() {
+        let (result, forwarded) = validate(vec![JC::ArrayStart, JC::ArrayLen(3), stream(vec![JC::Number(1.0)]), JC::Number(2.0)]);
+        let error = result.unwrap_err();
+        assert_eq!(error.to_string(), "packet 4 at [1]: expected a Stream channel but received Number");
+        assert_eq!(forwarded, Some(json!([1, "<skipped>", "<skipped>"])));
+
+        let (result, forwarded) = validate(vec![JC::ObjectStart, JC::ArrayLen(2), JC::String("a".to_string()), stream(vec![JC::Boolean(true), JC::Null])]);
+        assert_eq!(result.unwrap_err().to_string(), "packet 5 at .\"a\": unexpected Null after the end of the value");
+        assert_eq!(forwarded, Some(json!({"a": true, "1": "<skipped>"})));
+
+        let (result, forwarded) = validate(vec![JC::ObjectStart, JC::ArrayLen(1)]);
+        assert_eq!(result.unwrap_err().to_string(), "packet 2 at .: stream ended before the value was complete");
+        assert_eq!(forwarded, Some(json!({"0": "<skipped>"})));
+
+        let (result, forwarded) = validate(vec![JC::ArrayEnd]);
+        assert_eq!(result.unwrap_err().to_string(), "packet 0 at .: expected a value but received ArrayEnd");
+        assert_eq!(forwarded, None);
+
+        let (result, forwarded) = validate(vec![JC::ArrayStart, JC::ArrayLen(2), stream(vec![JC::ObjectStart, JC::Null]), stream(vec![JC::Number(2.0)]), JC::ArrayEnd]);
+        assert_eq!(result.unwrap_err().to_string(), "packet 4 at [0]: expected ArrayLen but received Null");
+        assert_eq!(forwarded, Some(json!(["<skipped>", "<skipped>"])));
+    }
+}
+
+#[derive(Debug)]
+enum Acessor {
+    ObjectField(String, Box<Acessor>),
+    ArrayEntry(usize, Box<Acessor>),
+    Map(Box<Acessor>),
+    End,
+    Construct(Vec<(String, Acessor)>), // Builds a new object with the result of each acessor, or null when it has none
+    ConstructArray(Vec<Acessor>),      // Builds a new array with the result of each acessor, or null when it has none
+    Pipe(Box<Acessor>, Box<Acessor>),  // Applies the second acessor to the result of the first
+    // Aggregates over the values of an array or object
+    Length, // Number of values, also the number of characters of a string, and 0 for null
+    Keys,   // Array of the keys of an object, or of the indexes of an array
+    Values, // Array of the values of an object, or the array itself
+    Sum,    // Sum of the numbers, ignoring nulls
+    Min,    // Smallest value in the order of Json values, or null when there are none
+    Max,    // Largest value in the order of Json values, or null when there are none
+    Count,  // Number of values that are not null
+    Any,    // Whether some value is neither null nor false
+    All,    // Whether every value is neither null nor false
+    // Transforms of arrays, using the result of the acessor on each value as its key (null when there is none)
+    SortBy(Box<Acessor>),   // Sorts the values by their keys, keeping the order of values with equal keys
+    UniqueBy(Box<Acessor>), // The first value for each key, sorted by key
+    GroupBy(Box<Acessor>),  // Arrays of the values with equal keys, sorted by key
+    Optional(Box<Acessor>),                          // Reaches no value instead of failing when the types or arguments do not match
+    Alternative(Box<Acessor>, Box<Acessor>),         // The first result, or the second when the first is null or missing
+    If(Box<Acessor>, Box<Acessor>, Box<Acessor>),    // Applies the second or third acessor depending on the first result
+    Literal(Json),                                   // A constant value
+    Call(String, Vec<Acessor>),                      // Calls a function of the FunctionRegistry with the results of the arguments
+    Binary(BinaryOperator, Box<Acessor>, Box<Acessor>), // Combines the results of both acessors, e.g. ."age" + 1
+    Var(String),                                     // The value bound to the variable, by eval_with or a Bind
+    Bind(Box<Acessor>, String, Box<Acessor>),        // Applies the last acessor with the variable bound to the first result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOperator {
+    Add,          // Numbers are added, strings and arrays concatenated, objects merged, and null is ignored
+    Subtract,     // Numbers are subtracted, and arrays lose the values of the second array
+    Multiply,
+    Divide,
+    Modulo,
+    Equal,
+    NotEqual,
+    Less,         // Comparisons use the order of Json values
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,          // Whether both values are neither null nor false, the second is only applied when the first is
+    Or,           // Whether either value is neither null nor false, the second is only applied when the first is not
+}
+
+impl BinaryOperator {
+    fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Modulo => "%",
+            BinaryOperator::Equal => "==",
+            BinaryOperator::NotEqual => "!=",
+            BinaryOperator::Less => "<",
+            BinaryOperator::LessEqual => "<=",
+            BinaryOperator::Greater => ">",
+            BinaryOperator::GreaterEqual => ">=",
+            BinaryOperator::And => "and",
+            BinaryOperator::Or => "or",
+        }
+    }
+
+    /**
+     * How tightly the operator binds, operators with a higher precedence are applied first
+     */
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOperator::Or => 2,
+            BinaryOperator::And => 3,
+            BinaryOperator::Equal | BinaryOperator::NotEqual | BinaryOperator::Less
+                | BinaryOperator::LessEqual | BinaryOperator::Greater | BinaryOperator::GreaterEqual => 4,
+            BinaryOperator::Add | BinaryOperator::Subtract => 5,
+            BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Modulo => 6,
+        }
+    }
+
+    fn apply(&self, left: &Json, right: &Json) -> Result<Json, EvalError> {
+        Ok(match (self, left, right) {
+            (BinaryOperator::Add, Json::Null, value) | (BinaryOperator::Add, value, Json::Null) => value.clone(),
+            (BinaryOperator::Add, Json::Number(a), Json::Number(b)) => Json::Number(a + b),
+            (BinaryOperator::Add, Json::String(a), Json::String(b)) => Json::String(format!("{}{}", a, b)),
+            (BinaryOperator::Add, Json::Array(a), Json::Array(b)) => Json::Array(a.iter().chain(b).cloned().collect()),
+            (BinaryOperator::Add, Json::Object(a), Json::Object(b)) => {
+                let mut map = a.clone();
+                map.extend(b.iter().map(|(key, value)| (key.to_string(), value.clone())));
+                Json::Object(map)
+            },
+            (BinaryOperator::Subtract, Json::Number(a), Json::Number(b)) => Json::Number(a - b),
+            (BinaryOperator::Subtract, Json::Array(a), Json::Array(b)) => Json::Array(a.iter().filter(|value| !b.contains(value)).cloned().collect()),
+            (BinaryOperator::Multiply, Json::Number(a), Json::Number(b)) => Json::Number(a * b),
+            (BinaryOperator::Divide, Json::Number(a), Json::Number(b)) | (BinaryOperator::Modulo, Json::Number(a), Json::Number(b)) => {
+                if *b == 0.0 {
+                    return Err(EvalError::InvalidArgument(format!("Cannot divide {} by zero", left)));
+                }
+                Json::Number(if let BinaryOperator::Divide = self { a / b } else { a % b })
+            },
+            (BinaryOperator::Equal, _, _) => Json::Boolean(left == right),
+            (BinaryOperator::NotEqual, _, _) => Json::Boolean(left != right),
+            (BinaryOperator::Less, _, _) => Json::Boolean(left < right),
+            (BinaryOperator::LessEqual, _, _) => Json::Boolean(left <= right),
+            (BinaryOperator::Greater, _, _) => Json::Boolean(left > right),
+            (BinaryOperator::GreaterEqual, _, _) => Json::Boolean(left >= right),
+            (BinaryOperator::And, _, _) => Json::Boolean(left.is_truthy() && right.is_truthy()),
+            (BinaryOperator::Or, _, _) => Json::Boolean(left.is_truthy() || right.is_truthy()),
+            _ => return Err(EvalError::TypeMismatch(format!("Cannot apply {} to {} and {}", self.symbol(), json_type_name(left), json_type_name(right)))),
+        })
+    }
+}
+
+
+/**
+ * Writes a string as a json string literal, escaping quotes, backslashes and control characters
+ */
+fn write_json_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            '\u{8}' => write!(f, "\\b")?,
+            '\u{c}' => write!(f, "\\f")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/**
+ * Compact json text, e.g. {"age":31,"name":"Jason Ray"}
+ * Numbers that cannot be represented in json (NaN and infinities) are written as null
+ */
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Boolean(b) => write!(f, "{}", b),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write_json_string(f, s),
+            Json::Array(array) => {
+                write!(f, "[")?;
+                for (i, value) in array.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(map) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+/**
+ * Acessors written the same way as in the comments of main, but without the End,
+ * e.g. ."socialProfiles"[0]."name" or ."socialProfiles"[]."link" for Map. End on its own is written as .
+ */
+impl fmt::Display for Acessor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Acessor::ObjectField(label, next_acessor) => {
+                write!(f, ".")?;
+                write_json_string(f, label)?;
+                write_next_acessor(f, next_acessor)
+            },
+            Acessor::ArrayEntry(index, next_acessor) => {
+                write!(f, "[{}]", index)?;
+                write_next_acessor(f, next_acessor)
+            },
+            // A Map that does more than follow a path is written as map(...), since [] | ... would apply the rest
+            // to the whole array
+            Acessor::Map(next_acessor) if !next_acessor.is_path() => write!(f, "map({})", next_acessor),
+            Acessor::Map(next_acessor) => {
+                write!(f, "[]")?;
+                write_next_acessor(f, next_acessor)
+            },
+            Acessor::End => write!(f, "."),
+            Acessor::Construct(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, field_acessor)) in fields.iter().enumerate() {
+                    write!(f, "{} ", if i == 0 { "" } else { "," })?;
+                    if is_query_identifier(key) {
+                        write!(f, "{}", key)?;
+                    } else {
+                        write_json_string(f, key)?;
+                    }
+                    write!(f, ": {}", field_acessor)?;
+                }
+                write!(f, " }}")
+            },
+            Acessor::ConstructArray(values) => {
+                write!(f, "[")?;
+                for (i, value_acessor) in values.iter().enumerate() {
+                    write!(f, "{} {}", if i == 0 { "" } else { "," }, value_acessor)?;
+                }
+                write!(f, " ]")
+            },
+            Acessor::Pipe(first, second) => {
+                write_closed(f, first)?;
+                write!(f, " | {}", second)
+            },
+            Acessor::Length => write!(f, "length"),
+            Acessor::Keys => write!(f, "keys"),
+            Acessor::Values => write!(f, "values"),
+            Acessor::Sum => write!(f, "sum"),
+            Acessor::Min => write!(f, "min"),
+            Acessor::Max => write!(f, "max"),
+            Acessor::Count => write!(f, "count"),
+            Acessor::Any => write!(f, "any"),
+            Acessor::All => write!(f, "all"),
+            Acessor::SortBy(key_acessor) => write!(f, "sort_by({})", key_acessor),
+            Acessor::UniqueBy(key_acessor) => write!(f, "unique_by({})", key_acessor),
+            Acessor::GroupBy(key_acessor) => write!(f, "group_by({})", key_acessor),
+            Acessor::Optional(optional_acessor) => {
+                write_operand(f, optional_acessor, TERM_PRECEDENCE)?;
+                write!(f, "?")
+            },
+            Acessor::Alternative(first, second) => {
+                write_operand(f, first, 1)?;
+                write!(f, " // ")?;
+                write_operand(f, second, 2)
+            },
+            Acessor::If(condition, then_acessor, else_acessor) => {
+                write!(f, "if {} then {} else {} end", condition, then_acessor, else_acessor)
+            },
+            Acessor::Literal(value) => write_literal(f, value),
+            Acessor::Call(name, arguments) => {
+                write!(f, "{}", name)?;
+                for (i, argument) in arguments.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "(" } else { "; " }, argument)?;
+                }
+                if arguments.is_empty() { Ok(()) } else { write!(f, ")") }
+            },
+            // Operators are left associative, so only the right operand needs parentheses at the same precedence.
+            // Comparisons need them on both sides, since a == b == c is not allowed
+            Acessor::Binary(operator, left, right) => {
+                let precedence = operator.precedence();
+                write_operand(f, left, if precedence == 4 { 5 } else { precedence })?;
+                write!(f, " {} ", operator.symbol())?;
+                write_operand(f, right, precedence + 1)
+            },
+            Acessor::Var(name) => write!(f, "${}", name),
+            Acessor::Bind(source, name, body) => {
+                write_operand(f, source, TERM_PRECEDENCE)?;
+                write!(f, " as ${} | {}", name, body)
+            },
+        }
+    }
+}
+
+/**
+ * Writes an acessor that is followed by more stages. The body of a Bind goes on until the end of the query,
+ * so a Bind at the end of the acessor is put in parentheses
+ */
+fn write_closed(f: &mut fmt::Formatter, acessor: &Acessor) -> fmt::Result {
+    match acessor {
+        _ if !acessor.ends_with_bind() => write!(f, "{}", acessor),
+        Acessor::Pipe(first, second) => {
+            write_closed(f, first)?;
+            write!(f, " | ")?;
+            write_closed(f, second)
+        },
+        // The next acessor ends with a Bind, so it is not a path step and is written after a |
+        Acessor::ObjectField(label, next_acessor) => {
+            write!(f, ".")?;
+            write_json_string(f, label)?;
+            write!(f, " | ")?;
+            write_closed(f, next_acessor)
+        },
+        Acessor::ArrayEntry(index, next_acessor) => {
+            write!(f, "[{}] | ", index)?;
+            write_closed(f, next_acessor)
+        },
+        _ => write!(f, "({})", acessor),
+    }
+}
+
+// Precedence of acessors that are written as a single term, like paths, literals and function calls
+const TERM_PRECEDENCE: u8 = 7;
+
+/**
+ * Writes an operand of an operator, in parentheses when it binds less tightly than the operator needs
+ */
+fn write_operand(f: &mut fmt::Formatter, operand: &Acessor, min_precedence: u8) -> fmt::Result {
+    if operand.precedence() < min_precedence {
+        write!(f, "({})", operand)
+    } else {
+        write!(f, "{}", operand)
+    }
+}
+
+/**
+ * Literals are written like json, with arrays and objects in the same form as ConstructArray and Construct
+ */
+fn write_literal(f: &mut fmt::Formatter, value: &Json) -> fmt::Result {
+    match value {
+        Json::Array(array) => {
+            write!(f, "[")?;
+            for (i, json_value) in array.iter().enumerate() {
+                write!(f, "{} ", if i == 0 { "" } else { "," })?;
+                write_literal(f, json_value)?;
+            }
+            write!(f, " ]")
+        },
+        Json::Object(map) => {
+            write!(f, "{{")?;
+            for (i, (key, json_value)) in map.iter().enumerate() {
+                write!(f, "{} ", if i == 0 { "" } else { "," })?;
+                if is_query_identifier(key) {
+                    write!(f, "{}", key)?;
+                } else {
+                    write_json_string(f, key)?;
+                }
+                write!(f, ": ")?;
+                write_literal(f, json_value)?;
+            }
+            write!(f, " }}")
+        },
+        _ => write!(f, "{}", value),
+    }
+}
+
+fn write_next_acessor(f: &mut fmt::Formatter, next_acessor: &Acessor) -> fmt::Result {
+    match next_acessor {
+        Acessor::End => Ok(()),
+        Acessor::ObjectField(_, _) | Acessor::ArrayEntry(_, _) => write!(f, "{}", next_acessor),
+        Acessor::Map(map_acessor) if map_acessor.is_path() => write!(f, "{}", next_acessor),
+        // The others do not start with a path step, so they are written as a new stage
+        _ => write!(f, " | {}", next_acessor),
+    }
+}
+
+/**
+ * Object keys of a Construct that can be written without quotes
+ */
+fn is_query_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+
+/**
+ * Json values have a total order, so they can be sorted and used in ordered collections. Values of different
+ * types are ordered by type: null < booleans < numbers < strings < arrays < objects.
+ * Booleans have false before true, strings are compared by their characters, arrays element by element and
+ * objects by their sorted (key, value) pairs.
+ * Numbers are compared by value, so -0.0 is equal to 0.0. NaN is equal to itself and comes after every
+ * other number, which makes equality reflexive and lets Json be Eq and Hash
+ */
+impl Ord for Json {
+    fn cmp(&self, other: &Json) -> Ordering {
+        let type_rank = |json: &Json| match json {
+            Json::Null => 0,
+            Json::Boolean(_) => 1,
+            Json::Number(_) => 2,
+            Json::String(_) => 3,
+            Json::Array(_) => 4,
+            Json::Object(_) => 5,
+        };
+        match (self, other) {
+            (Json::Boolean(a), Json::Boolean(b)) => a.cmp(b),
+            (Json::Number(a), Json::Number(b)) => match (a.is_nan(), b.is_nan()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => a.partial_cmp(b).unwrap(),
+            },
+            (Json::String(a), Json::String(b)) => a.cmp(b),
+            (Json::Array(a), Json::Array(b)) => a.cmp(b),
+            (Json::Object(a), Json::Object(b)) => a.iter().cmp(b.iter()),
+            _ => type_rank(self).cmp(&type_rank(other)),
+        }
+    }
+}
+
+impl PartialOrd for Json {
+    fn partial_cmp(&self, other: &Json) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Json {
+    fn eq(&self, other: &Json) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Json {}
+
+/**
+ * Consistent with equality, -0.0 hashes like 0.0 and every NaN hashes the same
+ */
+impl Hash for Json {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Json::Null => 0u8.hash(state),
+            Json::Boolean(b) => {
+                1u8.hash(state);
+                b.hash(state);
+            },
+            Json::Number(n) => {
+                2u8.hash(state);
+                let normalised = if *n == 0.0 { 0.0 } else if n.is_nan() { f64::NAN } else { *n };
+                normalised.to_bits().hash(state);
+            },
+            Json::String(s) => {
+                3u8.hash(state);
+                s.hash(state);
+            },
+            Json::Array(array) => {
+                4u8.hash(state);
+                array.hash(state);
+            },
+            Json::Object(map) => {
+                5u8.hash(state);
+                map.hash(state);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod json_order_tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashSet;
+
+    fn hash_of(json: &Json) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        json.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn values_are_ordered_by_type_then_value() {
+        let ordered = vec![
+            json!(null), json!(false), json!(true), json!(-1.5), json!(0), json!(2), json!(f64::NAN),
+            json!(""), json!("a"), json!("b"), json!([]), json!([1]), json!([1, 2]), json!([2]),
+            json!({}), json!({"a": 1}), json!({"a": 2}), json!({"b": 0}),
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(a.cmp(b), i.cmp(&j), "{} and {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn zeros_and_nans_are_equal_and_hash_alike() {
+        assert_eq!(json!(-0.0), json!(0.0));
+        assert_eq!(json!(f64::NAN), json!(-f64::NAN));
+        assert_eq!(hash_of(&json!(-0.0)), hash_of(&json!(0.0)));
+        assert_eq!(hash_of(&json!([f64::NAN])), hash_of(&json!([-f64::NAN])));
+        assert_ne!(hash_of(&json!(0)), hash_of(&json!(false)));
+        assert_ne!(hash_of(&json!([])), hash_of(&json!({})));
+        let set: HashSet<Json> = vec![json!(0.0), json!(-0.0), json!(f64::NAN), json!(f64::NAN), json!("0")].into_iter().collect();
+        assert_eq!(set.len(), 3);
+    }
+
+    #[test]
+    fn patch_test_and_diff_use_json_equality() {
+        let mut json = json!({"zero": -0.0, "nan": f64::NAN});
+        let patch = vec![
+            PatchOperation::Test { path: String::from("/zero"), value: json!(0.0) },
+            PatchOperation::Test { path: String::from("/nan"), value: json!(f64::NAN) },
+        ];
+        assert_eq!(apply_patch(&mut json, &patch), Ok(()));
+        assert!(diff(&json!([0.0]), &json!([-0.0])).is_empty());
+    }
+}
+
+
+// Returned when indexing a Json with a key or index that does not exist
+static NULL: Json = Json::Null;
+
+impl Json {
+    fn is_null(&self) -> bool {
+        matches!(self, Json::Null)
+    }
+
+    fn is_boolean(&self) -> bool {
+        self.as_bool().is_some()
+    }
+
+    fn is_number(&self) -> bool {
+        self.as_f64().is_some()
+    }
+
+    fn is_string(&self) -> bool {
+        self.as_str().is_some()
+    }
+
+    fn is_array(&self) -> bool {
+        self.as_array().is_some()
+    }
+
+    fn is_object(&self) -> bool {
+        self.as_object().is_some()
+    }
+
+    /**
+     * Null and false are false, every other value is true
+     */
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Json::Null | Json::Boolean(false))
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self { Json::Boolean(b) => Some(*b), _ => None }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self { Json::Number(n) => Some(*n), _ => None }
+    }
+
+    /**
+     * Only numbers without a fractional part that fit in an i64 are returned
+     */
+    fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) if n.fract() == 0.0 && *n >= -9223372036854775808.0 && *n < 9223372036854775808.0 => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self { Json::String(s) => Some(s), _ => None }
+    }
+
+    fn as_array(&self) -> Option<&Vec<Json>> {
+        match self { Json::Array(array) => Some(array), _ => None }
+    }
+
+    fn as_object(&self) -> Option<&BTreeMap<String, Json>> {
+        match self { Json::Object(map) => Some(map), _ => None }
+    }
+
+    /**
+     * Applies an acessor to a deserialised json, without going through the serialiser and eval threads.
+     * Returns None when the acessor does not reach a value, e.g. a missing field or an index out of bounds,
+     * or when it cannot be applied, e.g. ObjectField on an array. See Acessor::apply
+     */
+    fn get_path(&self, acessor: &Acessor) -> Option<Cow<'_, Json>> {
+        acessor.apply(self).ok()
+    }
+}
+
+/**
+ * json["name"] returns the value of the field, or Null when the field is missing or the json is not an object
+ */
+impl<'a> Index<&'a str> for Json {
+    type Output = Json;
+
+    fn index(&self, key: &'a str) -> &Json {
+        match self {
+            Json::Object(map) => map.get(key).unwrap_or(&NULL),
+            _ => &NULL,
+        }
+    }
+}
+
+/**
+ * json[0] returns the value at the index, or Null when the index is out of bounds or the json is not an array
+ */
+impl Index<usize> for Json {
+    type Output = Json;
+
+    fn index(&self, index: usize) -> &Json {
+        match self {
+            Json::Array(array) => array.get(index).unwrap_or(&NULL),
+            _ => &NULL,
+        }
+    }
+}
+
+#[cfg(test)]
+mod json_access_tests {
+    use super::*;
+
+    #[test]
+    fn indexing_and_typed_accessors() {
+        let json = json!({"name": "Jason", "age": 31, "height": 1.8, "admin": false, "languages": ["Java", "Rust"], "city": null});
+        assert_eq!(json["name"].as_str(), Some("Jason"));
+        assert_eq!(json["age"].as_i64(), Some(31));
+        assert_eq!(json["height"].as_i64(), None);
+        assert_eq!(json["height"].as_f64(), Some(1.8));
+        assert_eq!(json["admin"].as_bool(), Some(false));
+        assert_eq!(json["languages"][1], json!("Rust"));
+        assert!(json["languages"][2].is_null() && json["missing"]["deeper"].is_null() && json[0].is_null());
+        assert!(json.is_object() && json["languages"].is_array() && json["age"].is_number() && json["name"].is_string());
+        assert!(json["admin"].is_boolean() && !json["admin"].is_truthy() && !json["city"].is_truthy() && json["age"].is_truthy());
+        assert_eq!(json.as_object().map(|fields| fields.len()), Some(6));
+    }
+
+    #[test]
+    fn get_path_evaluates_in_memory() {
+        let json = json!({"profiles": [{"name": "Twitter"}, {"name": "Facebook"}]});
+        let names: Acessor = r#"."profiles"[]."name""#.parse().unwrap();
+        assert_eq!(json.get_path(&names).unwrap().into_owned(), json!(["Twitter", "Facebook"]));
+        assert_eq!(json.get_path(&r#"."missing""#.parse().unwrap()), None);
+        assert_eq!(json.get_path(&"[0]".parse().unwrap()), None);
+    }
+}
+
+
+/**
+ * This function is used to completly consume a value that is streamed thought the receiver channel
+ * Used when applying acessor to arrays and objects, and we need to get rid of the values that do not
+ * match the index or label in the acessor. Since these are streamed thought a receiver channel by the
+ * serializer and these channels are rendvouz, the serialiser thread will hang waiting to send the rest of the
+ * json value throught the channel, so this prevents it.
+ */
+fn consume_value(receiver: mpsc::Receiver<JC>) {
+    let first_packet = receiver.recv().unwrap();
+    consume_value_from(first_packet, receiver);
+}
+
+/**
+ * Same as consume_value, but for values whose first packet was already received
+ */
+fn consume_value_from(first_packet: JC, receiver: mpsc::Receiver<JC>) {
+    walk_jc_from(first_packet, receiver, &mut Discard); // Throw away the values
+}
+
+/**
+ * Visitor that ignores everything, used to consume values
+ */
+struct Discard;
+
+impl JcVisitor for Discard {}
+
+/**
+ * Top of a value, with the channels of its array or object values left unread. Skipped values are kept as
+ * JC::Skipped, and the simple values that the legacy Map sends without a channel are given their own channel
+ */
+enum ValueTop {
+    Scalar(Json),
+    Array(Vec<JC>),
+    Object(Vec<(String, JC)>),
+}
+
+impl ValueTop {
+    fn read(receiver: mpsc::Receiver<JC>) -> ValueTop {
+        let mut reader = ValueTopReader { top: None, key: None };
+        walk_jc(receiver, &mut reader);
+        reader.top.unwrap()
+    }
+
+    /**
+     * Sends the value again, with the values that were not read passed along untouched
+     */
+    fn send(self, sender: &mpsc::Sender<JC>) {
+        match self {
+            ValueTop::Scalar(value) => serialise_json(&value, sender.clone()),
+            ValueTop::Array(values) => {
+                sender.send(JC::ArrayStart).unwrap();
+                sender.send(JC::ArrayLen(values.len())).unwrap();
+                values.into_iter().for_each(|value_packet| sender.send(value_packet).unwrap());
+                sender.send(JC::ArrayEnd).unwrap();
+            },
+            ValueTop::Object(fields) => {
+                sender.send(JC::ObjectStart).unwrap();
+                sender.send(JC::ArrayLen(fields.len())).unwrap();
+                for (key, value_packet) in fields {
+                    sender.send(JC::String(key)).unwrap();
+                    sender.send(value_packet).unwrap();
+                }
+                sender.send(JC::ObjectEnd).unwrap();
+            },
+        }
+    }
+
+    /**
+     * Throws away the values that were not read
+     */
+    fn consume(self) {
+        match self {
+            ValueTop::Scalar(_) => (),
+            ValueTop::Array(values) => values.into_iter().for_each(consume_packet),
+            ValueTop::Object(fields) => fields.into_iter().for_each(|(_, value)| consume_packet(value)),
+        }
+    }
+}
+
+fn consume_packet(value_packet: JC) {
+    if let JC::Stream(value_stream) = value_packet {
+        consume_value(value_stream);
+    }
+}
+
+struct ValueTopReader {
+    top: Option<ValueTop>,
+    key: Option<String>, // key of the next object value
+}
+
+impl ValueTopReader {
+    fn add_value(&mut self, value_packet: JC) {
+        match &mut self.top {
+            Some(ValueTop::Array(values)) => values.push(value_packet),
+            Some(ValueTop::Object(fields)) => {
+                fields.push((self.key.take().expect("Expected a key for a object field (Value Top)"), value_packet));
+            },
+            _ => panic!("Expected a value inside an array or object (Value Top)"),
+        }
+    }
+}
+
+impl JcVisitor for ValueTopReader {
+    fn on_scalar(&mut self, value: Json) {
+        if self.top.is_none() {
+            self.top = Some(ValueTop::Scalar(value));
+        } else {
+            let (value_sender, value_receiver) = mpsc::channel::<JC>();
+            serialise_json(&value, value_sender);
+            self.add_value(JC::Stream(value_receiver));
+        }
+    }
+
+    fn enter_array(&mut self, len: usize) {
+        self.top = Some(ValueTop::Array(Vec::with_capacity(len)));
+    }
+
+    fn enter_object(&mut self, len: usize) {
+        self.top = Some(ValueTop::Object(Vec::with_capacity(len)));
+    }
+
+    fn on_key(&mut self, key: String) {
+        self.key = Some(key);
+    }
+
+    fn on_skipped(&mut self) {
+        self.add_value(JC::Skipped);
+    }
+
+    fn on_stream(&mut self, value_receiver: mpsc::Receiver<JC>) {
+        self.add_value(JC::Stream(value_receiver));
+    }
+}
+
+
+/**
+ * Options that change how acessors are evaluated
+ */
+#[derive(Debug, Clone)]
+struct EvalOptions {
+    // Map sends the results of the next acessor directly instead of in a Stream channel for each array value.
+    // This only works for simple values, and the output can not be passed to another eval. The values are always
+    // evaluated one after the other, whatever the parallelism, since the results share the array channel
+    legacy_map_shape: bool,
+    // Approximate amount of memory, in bytes, that SortBy, UniqueBy and GroupBy can use for the values of an array.
+    // Larger arrays are sorted in runs that are written to files in the spill directory and merged
+    memory_budget: usize,
+    spill_directory: Option<PathBuf>, // Defaults to the temporary directory of the system
+    functions: FunctionRegistry,      // Functions that Call acessors can use
+    bindings: Bindings,               // Values of the variables that Var acessors reach
+    // Number of threads Map uses to evaluate the array values at the same time. With 1 they are evaluated one
+    // after the other in the current thread, which is cheaper unless the next acessor does a lot of work on each value.
+    // Ignored with legacy_map_shape
+    parallelism: usize,
+}
+
+impl Default for EvalOptions {
+    fn default() -> Self {
+        EvalOptions {
+            legacy_map_shape: false, memory_budget: 64 * 1024 * 1024, spill_directory: None,
+            functions: FunctionRegistry::default(), bindings: Bindings::new(), parallelism: 1,
+        }
+    }
+}
+
+impl EvalOptions {
+    /**
+     * The same options with one more variable bound. The registry and the other bindings are shared, not copied
+     */
+    fn with_binding(&self, name: &str, value: Json) -> EvalOptions {
+        EvalOptions { bindings: self.bindings.with(name, value), ..self.clone() }
+    }
+}
+
+/**
+ * Values of variables by name, without the $. The bindings are a persistent stack, so binding a variable shares the
+ * bindings below it instead of copying them, and a Bind applied to each array value only adds its own value
+ */
+#[derive(Debug, Clone, Default)]
+struct Bindings {
+    top: Option<Arc<Binding>>,
+}
+
+#[derive(Debug)]
+struct Binding {
+    name: String,
+    value: Json,
+    below: Option<Arc<Binding>>,
+}
+
+impl Bindings {
+    fn new() -> Self {
+        Bindings { top: None }
+    }
+
+    /**
+     * The value of the variable from the binding nearest the top, which shadows the ones below
+     */
+    fn get(&self, name: &str) -> Option<&Json> {
+        let mut binding = self.top.as_deref();
+        while let Some(Binding { name: bound_name, value, below }) = binding {
+            if bound_name == name {
+                return Some(value);
+            }
+            binding = below.as_deref();
+        }
+        None
+    }
+
+    /**
+     * These bindings with the variable bound on top of them, leaving them unchanged
+     */
+    fn with(&self, name: &str, value: Json) -> Bindings {
+        Bindings { top: Some(Arc::new(Binding { name: name.to_string(), value, below: self.top.clone() })) }
+    }
+
+    fn insert(&mut self, name: String, value: Json) {
+        *self = self.with(&name, value);
+    }
+}
+
+/**
+ * A function receives the value the Call acessor is applied to, and the results of its arguments
+ */
+type Function = Arc<dyn Fn(&Json, &[Json]) -> Result<Json, EvalError> + Send + Sync>;
+
+/**
+ * Functions that Call acessors can use, by name. The default registry has the builtin functions:
+ * ascii_downcase, ascii_upcase, split(separator), join(separator), to_number, to_string, not,
+ * substring(start; end), test(regex) and capture(regex)
+ */
+#[derive(Clone)]
+struct FunctionRegistry {
+    functions: Arc<HashMap<String, Function>>, // Shared by the clones of the options, e.g. for each Bind
+}
+
+impl FunctionRegistry {
+    /**
+     * A registry without any function, not even the builtins
+     */
+    fn empty() -> Self {
+        FunctionRegistry { functions: Arc::new(HashMap::new()) }
+    }
+
+    /**
+     * Adds a function, replacing any function with the same name, including builtins
+     */
+    fn register<F>(&mut self, name: &str, function: F)
+        where F: Fn(&Json, &[Json]) -> Result<Json, EvalError> + Send + Sync + 'static {
+        Arc::make_mut(&mut self.functions).insert(name.to_string(), Arc::new(function));
+    }
+
+    fn get(&self, name: &str) -> Result<&Function, EvalError> {
+        self.functions.get(name).ok_or_else(|| EvalError::UnknownFunction(name.to_string()))
+    }
+}
+
+impl fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&String> = self.functions.keys().collect();
+        names.sort();
+        f.debug_set().entries(names).finish()
+    }
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        let mut registry = FunctionRegistry::empty();
+        registry.register("ascii_downcase", |input, arguments| {
+            expect_arguments("ascii_downcase", arguments, 0, 0)?;
+            Ok(Json::String(string_input("ascii_downcase", input)?.to_ascii_lowercase()))
+        });
+        registry.register("ascii_upcase", |input, arguments| {
+            expect_arguments("ascii_upcase", arguments, 0, 0)?;
+            Ok(Json::String(string_input("ascii_upcase", input)?.to_ascii_uppercase()))
+        });
+        registry.register("split", |input, arguments| {
+            expect_arguments("split", arguments, 1, 1)?;
+            let str = string_input("split", input)?;
+            let parts: Vec<Json> = match string_input("split", &arguments[0])? {
+                // An empty separator splits the string into its characters
+                "" => str.chars().map(|c| Json::String(c.to_string())).collect(),
+                separator => str.split(separator).map(Json::from).collect(),
+            };
+            Ok(Json::Array(parts))
+        });
+        registry.register("join", |input, arguments| {
+            expect_arguments("join", arguments, 1, 1)?;
+            let separator = string_input("join", &arguments[0])?;
+            let values = input.as_array().ok_or_else(|| EvalError::TypeMismatch(format!("Cannot apply join to {}", json_type_name(input))))?;
+            let mut parts = Vec::with_capacity(values.len());
+            for value in values {
+                parts.push(match value {
+                    Json::Null => String::new(),
+                    Json::String(str) => str.to_string(),
+                    Json::Number(_) | Json::Boolean(_) => value.to_string(),
+                    _ => return Err(EvalError::TypeMismatch(format!("Cannot join {}", json_type_name(value)))),
+                });
+            }
+            Ok(Json::String(parts.join(separator)))
+        });
+        registry.register("to_number", |input, arguments| {
+            expect_arguments("to_number", arguments, 0, 0)?;
+            match input {
+                Json::Number(_) => Ok(input.clone()),
+                Json::String(str) => match str.parse::<f64>() {
+                    Ok(num) if num.is_finite() => Ok(Json::Number(num)),
+                    _ => Err(EvalError::InvalidArgument(format!("Cannot parse {} as a number", input))),
+                },
+                _ => Err(EvalError::TypeMismatch(format!("Cannot apply to_number to {}", json_type_name(input)))),
+            }
+        });
+        registry.register("to_string", |input, arguments| {
+            expect_arguments("to_string", arguments, 0, 0)?;
+            match input {
+                Json::String(_) => Ok(input.clone()),
+                _ => Ok(Json::String(input.to_string())),
+            }
+        });
+        registry.register("not", |input, arguments| {
+            expect_arguments("not", arguments, 0, 0)?;
+            Ok(Json::Boolean(!input.is_truthy()))
+        });
+        registry.register("substring", |input, arguments| {
+            expect_arguments("substring", arguments, 1, 2)?;
+            let chars: Vec<char> = string_input("substring", input)?.chars().collect();
+            // Negative positions count from the end, and positions past either end are clamped
+            let position = |argument: &Json| match argument.as_i64() {
+                Some(index) if index < 0 => Ok(chars.len().saturating_sub(index.unsigned_abs() as usize)),
+                Some(index) => Ok((index as usize).min(chars.len())),
+                None => Err(EvalError::InvalidArgument(format!("substring positions must be integers, not {}", argument))),
+            };
+            let start = position(&arguments[0])?;
+            let end = match arguments.get(1) {
+                Some(argument) => position(argument)?,
+                None => chars.len(),
+            };
+            Ok(Json::String(if start < end { chars[start..end].iter().collect() } else { String::new() }))
+        });
+        registry.register("test", |input, arguments| {
+            expect_arguments("test", arguments, 1, 1)?;
+            let regex = regex_argument("test", &arguments[0])?;
+            let matched = regex.is_match(string_input("test", input)?).map_err(EvalError::InvalidArgument)?;
+            Ok(Json::Boolean(matched))
+        });
+        registry.register("capture", |input, arguments| {
+            expect_arguments("capture", arguments, 1, 1)?;
+            let regex = regex_argument("capture", &arguments[0])?;
+            // Only named groups are captured, and there is no value when the regex does not match
+            let captures = regex.captures(string_input("capture", input)?).map_err(EvalError::InvalidArgument)?.ok_or(EvalError::NoValue)?;
+            Ok(Json::Object(regex.group_names.iter().zip(captures.into_iter().skip(1))
+                .filter_map(|(name, capture)| name.as_ref().map(|name| (name.to_string(), Json::from(capture))))
+                .collect()))
+        });
+        registry
+    }
+}
+
+fn expect_arguments(name: &str, arguments: &[Json], min: usize, max: usize) -> Result<(), EvalError> {
+    if arguments.len() < min || arguments.len() > max {
+        let expected = if min == max { min.to_string() } else { format!("{} to {}", min, max) };
+        return Err(EvalError::InvalidArgument(format!("{} takes {} arguments, not {}", name, expected, arguments.len())));
+    }
+    Ok(())
+}
+
+fn string_input<'a>(name: &str, value: &'a Json) -> Result<&'a str, EvalError> {
+    value.as_str().ok_or_else(|| EvalError::TypeMismatch(format!("Cannot apply {} to {}", name, json_type_name(value))))
+}
+
+fn regex_argument(name: &str, argument: &Json) -> Result<Regex, EvalError> {
+    let pattern = argument.as_str()
+        .ok_or_else(|| EvalError::InvalidArgument(format!("The regex of {} must be a string, not {}", name, json_type_name(argument))))?;
+    Regex::new(pattern).map_err(|message| EvalError::InvalidArgument(format!("Invalid regex {}: {}", argument, message)))
+}
+
+#[cfg(test)]
+mod function_tests {
+    use super::*;
+
+    /**
+     * Evaluates the query on the streamed value, checking that it agrees with the in memory version
+     */
+    fn query(text: &str, json: &Json) -> Result<Json, EvalError> {
+        let acessor: Acessor = text.parse().unwrap();
+        let (sender, receiver) = mpsc::channel::<JC>();
+        let streamed = eval(&acessor, jc_stream(json), sender)
+            .and_then(|_| produced_value(receiver).map(deserialise_json).ok_or(EvalError::NoValue));
+        assert_eq!(streamed, acessor.apply(json).map(Cow::into_owned), "{}", text);
+        streamed
+    }
+
+    fn person() -> Json {
+        json!({"name": "Jason Ray", "age": 31, "admin": false, "tags": ["a", "b"], "city": "New-York"})
+    }
+
+    #[test]
+    fn builtin_functions() {
+        let json = person();
+        assert_eq!(query(r#"."name" | ascii_downcase"#, &json), Ok(json!("jason ray")));
+        assert_eq!(query(r#"."name" | ascii_upcase"#, &json), Ok(json!("JASON RAY")));
+        assert_eq!(query(r#"."city" | split("-")"#, &json), Ok(json!(["New", "York"])));
+        assert_eq!(query(r#"."tags" | join(", ")"#, &json), Ok(json!("a, b")));
+        assert_eq!(query(r#""12.5" | to_number"#, &json), Ok(json!(12.5)));
+        assert_eq!(query(r#"."age" | to_string"#, &json), Ok(json!("31")));
+        assert_eq!(query(r#"."admin" | not"#, &json), Ok(json!(true)));
+        assert_eq!(query(r#"."name" | substring(0; 5)"#, &json), Ok(json!("Jason")));
+        assert_eq!(query(r#"."name" | substring(-3)"#, &json), Ok(json!("Ray")));
+        assert_eq!(query(r#"."name" | test("^J[a-z]+ R")"#, &json), Ok(json!(true)));
+        assert_eq!(query(r#"."name" | capture("(?<first>[A-Za-z]+) (?<last>[A-Za-z]+)")"#, &json), Ok(json!({"first": "Jason", "last": "Ray"})));
+        assert_eq!(query(r#"."name" | capture("^x")"#, &json), Err(EvalError::NoValue));
+        assert!(matches!(query(r#"."age" | ascii_downcase"#, &json), Err(EvalError::TypeMismatch(_))));
+        assert!(matches!(query(r#""x" | to_number"#, &json), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(query(r#"."name" | split"#, &json), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(query(r#"."name" | test("(")"#, &json), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn binary_operators() {
+        let json = person();
+        assert_eq!(query(r#"."age" + 1"#, &json), Ok(json!(32)));
+        assert_eq!(query(r#"."age" * 2 - 2 / 4 % 3"#, &json), Ok(json!(61.5)));
+        assert_eq!(query(r#"."name" + "!""#, &json), Ok(json!("Jason Ray!")));
+        assert_eq!(query(r#"."tags" + ["c"] - ["a"]"#, &json), Ok(json!(["b", "c"])));
+        assert_eq!(query(r#"{ "a": 1 } + { "b": 2 }"#, &json), Ok(json!({"a": 1, "b": 2})));
+        assert_eq!(query(r#"."missing" + 1"#, &json), Err(EvalError::NoValue));
+        assert_eq!(query(r#"."age" >= 18 and ."name" != "Bob""#, &json), Ok(json!(true)));
+        assert_eq!(query(r#"."tags"[5] == 1"#, &json), Err(EvalError::NoValue));
+        // The second operand is not evaluated when the first decides the result
+        assert_eq!(query(r#"."admin" and 1 / 0"#, &json), Ok(json!(false)));
+        assert_eq!(query(r#"."age" or 1 / 0"#, &json), Ok(json!(true)));
+        assert!(matches!(query(r#"."age" / 0"#, &json), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(query(r#"."name" - 1"#, &json), Err(EvalError::TypeMismatch(_))));
+    }
+
+    #[test]
+    fn operators_only_read_the_parts_of_the_value_they_use() {
+        // The other fields are Skipped, which the operators never see since only their operands are copied
+        let json = person();
+        let compiled = CompiledQuery::parse(r#"."age" + 1 > 30 and (."tags" | length) == 2"#).unwrap();
+        let (sender, receiver) = mpsc::channel::<JC>();
+        serialise_json_with_projection(&json, &compiled, sender);
+        let (result_sender, result_receiver) = mpsc::channel::<JC>();
+        compiled.eval(receiver, result_sender).unwrap();
+        assert_eq!(deserialise_json(result_receiver), json!(true));
+    }
+
+    #[test]
+    fn unknown_names_are_rejected() {
+        for text in ["map", "sort_by", "length(.a)", "keys(1)"] {
+            assert!(text.parse::<Acessor>().is_err(), "{}", text);
+        }
+        match CompiledQuery::parse(r#"."name" | ascii_dowcase"#) {
+            Err(CompileError::UnknownFunction(name)) => assert_eq!(name, "ascii_dowcase"),
+            other => panic!("Expected an unknown function, got {:?}", other),
+        }
+        assert!(matches!(CompiledQuery::parse(r#"[ ."name", { "a": shout } ]"#), Err(CompileError::UnknownFunction(_))));
+        let mut functions = FunctionRegistry::default();
+        functions.register("shout", |input, _| Ok(Json::String(format!("{}!", input.as_str().unwrap_or_default()))));
+        assert!(r#"."name" | shout"#.parse::<Acessor>().unwrap().compile_with(&functions).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod binding_tests {
+    use super::*;
+
+    /**
+     * Evaluates the query with the bindings on the streamed value, checking that it agrees with the in memory version
+     */
+    fn query_with(text: &str, bindings: &Bindings, json: &Json) -> Result<Json, EvalError> {
+        let acessor: Acessor = text.parse().unwrap();
+        let (sender, receiver) = mpsc::channel::<JC>();
+        let streamed = eval_with(&acessor, bindings, jc_stream(json), sender)
+            .and_then(|_| produced_value(receiver).map(deserialise_json).ok_or(EvalError::NoValue));
+        let options = EvalOptions { bindings: bindings.clone(), ..EvalOptions::default() };
+        assert_eq!(streamed, acessor.apply_with(json, &options).map(Cow::into_owned), "{}", text);
+        streamed
+    }
+
+    fn query(text: &str, json: &Json) -> Result<Json, EvalError> {
+        query_with(text, &Bindings::new(), json)
+    }
+
+    #[test]
+    fn bound_values_are_reached_inside_the_body() {
+        let json = json!({"age": 31, "tags": ["a", "b"]});
+        assert_eq!(query(r#"."age" as $age | ."tags" | map({ "tag": ., "age": $age })"#, &json),
+            Ok(json!([{"tag": "a", "age": 31}, {"tag": "b", "age": 31}])));
+        assert_eq!(query(r#"."tags" as $tags | ."age" as $age | [ $age, ($tags | [1]) ]"#, &json), Ok(json!([31, "b"])));
+        // The source is applied to the same value as the body
+        assert_eq!(query(r#". as $all | ."age" | [ ., ($all | ."tags"[0]) ]"#, &json), Ok(json!([31, "a"])));
+        assert_eq!(query(r#"."missing" as $m | 1"#, &json), Err(EvalError::NoValue));
+        assert_eq!(query("$m", &json), Err(EvalError::UnboundVariable(String::from("m"))));
+        assert_eq!(query(r#"."age" as $age | $agee"#, &json), Err(EvalError::UnboundVariable(String::from("agee"))));
+    }
+
+    #[test]
+    fn inner_bindings_shadow_outer_ones() {
+        let json = json!(null);
+        assert_eq!(query("1 as $x | [ $x, (2 as $x | $x), $x ]", &json), Ok(json!([1, 2, 1])));
+        let mut bindings = Bindings::new();
+        bindings.insert(String::from("x"), json!(1));
+        bindings.insert(String::from("y"), json!(2));
+        assert_eq!(query_with("[ $x, $y, (3 as $y | $y) ]", &bindings, &json), Ok(json!([1, 2, 3])));
+        // Binding on top of other bindings leaves them unchanged
+        let inner = bindings.with("x", json!(4));
+        assert_eq!(inner.get("x"), Some(&json!(4)));
+        assert_eq!(inner.get("y"), Some(&json!(2)));
+        assert_eq!(bindings.get("x"), Some(&json!(1)));
+        assert_eq!(bindings.get("z"), None);
+    }
+
+    #[test]
+    fn bind_only_reads_the_parts_of_the_value_it_uses() {
+        // The other fields are Skipped, which neither the source nor the body see since they get projected copies
+        let json = json!({"name": "Jason Ray", "age": 31, "tags": ["a", "b"], "friends": [{"name": "Bob"}]});
+        let compiled = CompiledQuery::parse(r#"."age" as $age | ."tags" | map([ ., $age ])"#).unwrap();
+        let (sender, receiver) = mpsc::channel::<JC>();
+        serialise_json_with_projection(&json, &compiled, sender);
+        let (result_sender, result_receiver) = mpsc::channel::<JC>();
+        compiled.eval(receiver, result_sender).unwrap();
+        assert_eq!(deserialise_json(result_receiver), json!([["a", 31], ["b", 31]]));
+    }
+}
+
+/**
+ * Applies the acessor to the value streamed through the receiver, sending the result through the sender.
+ * Acessors that reach no value, e.g. a missing field, send nothing. When the acessor cannot be applied to the value
+ * the rest of the value is consumed and an error is returned. Whatever was already sent is then completed into a whole
+ * value, with null in place of the parts that failed, so that whoever reads it is not left waiting
+ */
+fn eval(acessor: &Acessor, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<(), EvalError> {
+    eval_with_options(acessor, &EvalOptions::default(), receiver, sender)
+}
+
+/**
+ * Evaluates the acessor with values for its variables, so the same acessor can be used with different parameters,
+ * e.g. ."age" >= $min_age with a binding for min_age
+ */
+fn eval_with(acessor: &Acessor, bindings: &Bindings, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<(), EvalError> {
+    let options = EvalOptions { bindings: bindings.clone(), ..EvalOptions::default() };
+    eval_with_options(acessor, &options, receiver, sender)
+}
+
+fn eval_with_options(acessor: &Acessor, options: &EvalOptions, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<(), EvalError> {
+    // Recursively apply acessor with each call of this function
+    match acessor {
+        Acessor::ObjectField(label , next_acessor) => {
+            match receiver.recv().unwrap() {
+                JC::ObjectStart => {
+                    let mut result = Ok(());
+                    match receiver.recv().unwrap() {
+                        JC::ArrayLen(maplen) => {
+                            for _ in 0..maplen {
+                                match receiver.recv().unwrap() {
+                                    JC::String(obj_label) => {
+                                        match receiver.recv().unwrap() {
+                                            JC::Stream(value_stream) => {
+                                                if obj_label.eq(label) && result.is_ok() {
+                                                    // recursively handle the values obtained by applying an acesor
+                                                    let sender_clone = sender.clone();
+                                                    result = eval_with_options(next_acessor, options, value_stream, sender_clone);
+                                                } else {
+                                                    consume_value(value_stream); // Consume the stream and therefore the json value
+                                                }
+                                            },
+                                            JC::Skipped => (), // Not needed by the acessor, so it was not sent
+                                            _ => panic!("Expected Object Stream (Eval ObjectField)")
+                                        }
+                                    },
+                                    _ => panic!("Expected Object Label (Eval ObjectField)")
+                                }
+                            }
+                            match receiver.recv().unwrap() {
+                                JC::ObjectEnd => (), // consume object end
+                                _ => panic!("Expected Object End (eval objectfield)")
+                            }
+                        },
+                        _ => panic!("Expected ArrayLen (Eval ObjectField)")
+                    }
+                    result
+                },
+                first_packet => type_mismatch("Cannot apply ObjectField Acessor to this Json Value", first_packet, receiver),
+            }
+        },
+        Acessor::ArrayEntry(index, next_acessor) => {
+            match receiver.recv().unwrap() {
+                JC::ArrayStart => {
+                    let mut result = Ok(());
+                    match receiver.recv().unwrap() {
+                        JC::ArrayLen(arrlen) => {
+                            for i in 0..arrlen {
+                                match receiver.recv().unwrap() {
+                                    JC::Stream(value_stream) => {
+                                        if i == *index {
+                                            // recursively handle the values obtained by applying an acesor
+                                            let sender_clone = sender.clone();
+                                            result = eval_with_options(next_acessor, options, value_stream, sender_clone);
+                                        } else {
+                                            consume_value(value_stream); // consume the stream and the value
+                                        }
+                                    },
+                                    JC::Skipped => (), // Not needed by the acessor, so it was not sent
+                                    _ => panic!("Expected Array Value Stream (Eval ArrayEntry)")
+                                }
+                            }
+                            match receiver.recv().unwrap() {
+                                JC::ArrayEnd => (), // Consume Array End
+                                _ => panic!("Expected Array End (Eval ArrayEntry)")
+                            }
+                        },
+                        _ => panic!("Expected ArrayLen (Eval ArrayEntry)")
+                    }
+                    result
+                },
+                first_packet => type_mismatch("Cannot apply Index accessor to this Json Value", first_packet, receiver),
+            }
+        },
+        // The results are sent straight into the array channel, so they can not be evaluated in parallel
+        Acessor::Map(next_acessor) if options.legacy_map_shape => {
+            // Must send a new array composed of the results of the acessor
+            match receiver.recv().unwrap() {
+                JC::ArrayStart => {
+                    let mut result = Ok(());
+                    sender.send(JC::ArrayStart).unwrap(); // Signal the start of a new array
+                    match receiver.recv().unwrap() {
+                        JC::ArrayLen(arrlen) => {
+                            sender.send(JC::ArrayLen(arrlen)).unwrap(); // send length of array
+                            for _ in 0..arrlen {
+                                match receiver.recv().unwrap() {
+                                    JC::Stream(value_stream) => {
+                                        // recursively handle the values obtained by applying the next acessor to each
+                                        // value of the array
+                                        if result.is_ok() {
+                                            let sender_clone = sender.clone();
+                                            result = eval_with_options(next_acessor, options, value_stream, sender_clone);
+                                        } else {
+                                            consume_value(value_stream);
+                                            sender.send(JC::Null).unwrap();
+                                        }
+                                    },
+                                    JC::Skipped => sender.send(JC::Skipped).unwrap(), // Not needed, so it has no result either
+                                    _ => panic!("Expected Value Stream (Eval Map)")
+                                }
+                            }
+                            match receiver.recv().unwrap() {
+                                JC::ArrayEnd => sender.send(JC::ArrayEnd).unwrap(), // send array end
+                                _ => panic!("Expected array end (Eval Map)")
+                            }
+                        },
+                        _ => panic!("Expected ArrayLen (Eval Map)")
+                    }
+                    result
+                },
+                first_packet => type_mismatch("Cannot apply Map to this Json Value", first_packet, receiver),
+            }
+        },
+        Acessor::Map(next_acessor) => {
+            // Each value of the new array is sent in its own channel as soon as the array value is received, and the
+            // result of the next acessor is sent through it once evaluated. Values for which the next acessor reaches
+            // nothing are null, so the new array always has the length of the original one
+            match receiver.recv().unwrap() {
+                JC::ArrayStart => {
+                    let mut result = Ok(());
+                    sender.send(JC::ArrayStart).unwrap();
+                    match receiver.recv().unwrap() {
+                        JC::ArrayLen(arrlen) => {
+                            sender.send(JC::ArrayLen(arrlen)).unwrap();
+                            // Values that were Skipped are not needed, so Skipped is sent in place of their result
+                            let value_streams = (0..arrlen).map(|_| match receiver.recv().unwrap() {
+                                JC::Stream(value_stream) => Some(value_stream),
+                                JC::Skipped => None,
+                                _ => panic!("Expected Value Stream (Eval Map)")
+                            });
+                            if options.parallelism > 1 && arrlen > 1 {
+                                result = eval_map_parallel(next_acessor, options, value_streams, &sender);
+                            } else {
+                                for value_stream in value_streams {
+                                    let value_stream = match value_stream {
+                                        Some(value_stream) => value_stream,
+                                        None => {
+                                            sender.send(JC::Skipped).unwrap();
+                                            continue;
+                                        }
+                                    };
+                                    if result.is_ok() {
+                                        let (result_sender, result_receiver) = mpsc::channel::<JC>();
+                                        sender.send(JC::Stream(result_receiver)).unwrap();
+                                        result = eval_map_value(next_acessor, options, value_stream, result_sender);
+                                    } else {
+                                        consume_value(value_stream);
+                                        sender.send(JC::Stream(null_stream())).unwrap();
+                                    }
+                                }
+                            }
+                            match receiver.recv().unwrap() {
+                                JC::ArrayEnd => (),
+                                _ => panic!("Expected array end (Eval Map)")
+                            }
+                        },
+                        _ => panic!("Expected ArrayLen (Eval Map)")
+                    }
+                    sender.send(JC::ArrayEnd).unwrap();
+                    result
+                },
+                first_packet => type_mismatch("Cannot apply Map to this Json Value", first_packet, receiver),
+            }
+        },
+        Acessor::End => {
+            // When End acessor is reached, we simply pass along the result of applying the previous acessors
+            // to the sender channel. The values of arrays and objects are forwarded without reading them
+            walk_jc(receiver, &mut JcWriter::forwarding(sender));
+            Ok(())
+        },
+        Acessor::Construct(fields) => {
+            let field_acessors: Vec<&Acessor> = fields.iter().map(|(_, field_acessor)| field_acessor).collect();
+            let results = eval_all(&field_acessors, options, receiver)?;
+            sender.send(JC::ObjectStart).unwrap();
+            sender.send(JC::ArrayLen(fields.len())).unwrap();
+            for ((key, _), result) in fields.iter().zip(results) {
+                sender.send(JC::String(key.to_string())).unwrap();
+                sender.send(JC::Stream(result.unwrap_or_else(null_stream))).unwrap();
+            }
+            sender.send(JC::ObjectEnd).unwrap();
+            Ok(())
+        },
+        Acessor::ConstructArray(values) => {
+            let value_acessors: Vec<&Acessor> = values.iter().collect();
+            let results = eval_all(&value_acessors, options, receiver)?;
+            sender.send(JC::ArrayStart).unwrap();
+            sender.send(JC::ArrayLen(values.len())).unwrap();
+            for result in results {
+                sender.send(JC::Stream(result.unwrap_or_else(null_stream))).unwrap();
+            }
+            sender.send(JC::ArrayEnd).unwrap();
+            Ok(())
+        },
+        Acessor::Pipe(first, second) => {
+            let (first_sender, first_receiver) = mpsc::channel::<JC>();
+            eval_with_options(first, options, receiver, first_sender)?;
+            // When the first acessor reaches no value, neither does the second
+            match produced_value(first_receiver) {
+                Some(first_result) => eval_with_options(second, options, first_result, sender),
+                None => Ok(()),
+            }
+        },
+        Acessor::SortBy(key_acessor) | Acessor::UniqueBy(key_acessor) | Acessor::GroupBy(key_acessor) => {
+            eval_sort(acessor, key_acessor, options, receiver, sender)
+        },
+        Acessor::Optional(optional_acessor) => {
+            // The result is only sent once it is known to be complete
+            let (optional_sender, optional_receiver) = mpsc::channel::<JC>();
+            match eval_with_options(optional_acessor, options, receiver, optional_sender) {
+                Ok(()) => {
+                    for packet in optional_receiver.try_iter() {
+                        sender.send(packet).unwrap();
+                    }
+                    Ok(())
+                },
+                Err(EvalError::TypeMismatch(_)) | Err(EvalError::InvalidArgument(_)) => Ok(()),
+                Err(error) => Err(error),
+            }
+        },
+        Acessor::Alternative(first, second) => {
+            let projections = [first.projection(Projection::All), second.projection(Projection::All)];
+            let mut copies = tee_projected(receiver, &projections).into_iter();
+            let (first_sender, first_receiver) = mpsc::channel::<JC>();
+            eval_with_options(first, options, copies.next().unwrap(), first_sender)?;
+            match first_receiver.try_recv() {
+                Ok(JC::Null) | Err(_) => eval_with_options(second, options, copies.next().unwrap(), sender),
+                Ok(first_packet) => {
+                    sender.send(first_packet).unwrap();
+                    for packet in first_receiver.try_iter() {
+                        sender.send(packet).unwrap();
+                    }
+                    Ok(())
+                },
+            }
+        },
+        Acessor::If(condition, then_acessor, else_acessor) => {
+            let projections = [
+                condition.projection(Projection::All),
+                then_acessor.projection(Projection::All).union(else_acessor.projection(Projection::All)),
+            ];
+            let mut copies = tee_projected(receiver, &projections).into_iter();
+            let (condition_sender, condition_receiver) = mpsc::channel::<JC>();
+            eval_with_options(condition, options, copies.next().unwrap(), condition_sender)?;
+            // When the condition reaches no value, neither does the If
+            match condition_receiver.try_recv() {
+                Ok(ref packet) if is_truthy(packet) => eval_with_options(then_acessor, options, copies.next().unwrap(), sender),
+                Ok(_) => eval_with_options(else_acessor, options, copies.next().unwrap(), sender),
+                Err(_) => Ok(()),
+            }
+        },
+        Acessor::Literal(value) => {
+            consume_value(receiver);
+            serialise_json(value, sender);
+            Ok(())
+        },
+        // The operands are evaluated on copies with only the parts they read, so ."age" + 1 only deserialises the age
+        Acessor::Binary(operator, left, right) => {
+            let projections = [left.projection(Projection::All), right.projection(Projection::All)];
+            let mut copies = tee_projected(receiver, &projections).into_iter();
+            let left_value = match eval_value(left, options, copies.next().unwrap())? {
+                Some(left_value) => left_value,
+                None => return Ok(()),
+            };
+            let result = match operator {
+                // The second acessor is not applied when the first value decides the result
+                BinaryOperator::And if !left_value.is_truthy() => Json::Boolean(false),
+                BinaryOperator::Or if left_value.is_truthy() => Json::Boolean(true),
+                _ => match eval_value(right, options, copies.next().unwrap())? {
+                    Some(right_value) => operator.apply(&left_value, &right_value)?,
+                    None => return Ok(()),
+                },
+            };
+            serialise_json(&result, sender);
+            Ok(())
+        },
+        // Functions are given the whole value they are applied to, so it is deserialised. It is usually a scalar,
+        // since the path before a function is applied by a Pipe, e.g. ."name" | ascii_downcase
+        Acessor::Call(_, _) => {
+            let value = deserialise_json(receiver);
+            match acessor.apply_with(&value, options) {
+                Ok(result) => {
+                    serialise_json(&result, sender);
+                    Ok(())
+                },
+                Err(EvalError::NoValue) => Ok(()),
+                Err(error) => Err(error),
+            }
+        },
+        Acessor::Var(name) => {
+            consume_value(receiver);
+            match options.bindings.get(name) {
+                Some(value) => {
+                    serialise_json(value, sender);
+                    Ok(())
+                },
+                None => Err(EvalError::UnboundVariable(name.to_string())),
+            }
+        },
+        // The source and the body are evaluated on copies with only the parts they read
+        Acessor::Bind(source, name, body) => {
+            let projections = [source.projection(Projection::All), body.projection(Projection::All)];
+            let mut copies = tee_projected(receiver, &projections).into_iter();
+            // When the source reaches no value, neither does the Bind
+            match eval_value(source, options, copies.next().unwrap())? {
+                Some(value) => eval_with_options(body, &options.with_binding(name, value), copies.next().unwrap(), sender),
+                None => Ok(()),
+            }
+        },
+        _ => eval_aggregate(acessor, receiver, sender),
+    }
+}
+
+/**
+ * Evaluates an acessor and deserialises its result, which is None when the acessor reaches no value
+ */
+fn eval_value(acessor: &Acessor, options: &EvalOptions, receiver: mpsc::Receiver<JC>) -> Result<Option<Json>, EvalError> {
+    let (sender, result_receiver) = mpsc::channel::<JC>();
+    eval_with_options(acessor, options, receiver, sender)?;
+    Ok(produced_value(result_receiver).map(deserialise_json))
+}
+
+/**
+ * Evaluates the next acessor of a Map on one array value, sending the result through the sender, or null when the
+ * acessor reaches nothing or fails
+ */
+fn eval_map_value(next_acessor: &Acessor, options: &EvalOptions, value_stream: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<(), EvalError> {
+    let (value_sender, value_receiver) = mpsc::channel::<JC>();
+    if let Err(error) = eval_with_options(next_acessor, options, value_stream, value_sender) {
+        sender.send(JC::Null).unwrap();
+        return Err(error);
+    }
+    // The evaluation finished, so every packet it sent is already in the channel
+    let mut packets = value_receiver.try_iter().peekable();
+    if packets.peek().is_none() {
+        sender.send(JC::Null).unwrap();
+    }
+    for packet in packets {
+        sender.send(packet).unwrap();
+    }
+    Ok(())
+}
+
+/**
+ * Evaluates the next acessor of a Map on the array values with options.parallelism worker threads, starting as soon as
+ * each value is received. The channel of each result is sent in the order of the array before the value is handed to
+ * a worker, so the results keep their order. When values fail, the ones after the first failure are only consumed,
+ * and the error of the first one is returned, the same as evaluating them in order. The results that failed or
+ * were not evaluated are null, so the new array is complete either way. Values that were Skipped have
+ * Skipped in place of their result.
+ * The workers evaluate without parallelism, so a Map never uses more threads than the limit
+ */
+fn eval_map_parallel<I>(next_acessor: &Acessor, options: &EvalOptions, value_streams: I, sender: &mpsc::Sender<JC>) -> Result<(), EvalError>
+    where I: Iterator<Item = Option<mpsc::Receiver<JC>>> {
+    let worker_options = EvalOptions { parallelism: 1, ..options.clone() };
+    let first_failure = AtomicUsize::new(usize::MAX); // Index of the first value that failed so far
+    let (job_sender, job_receiver) = mpsc::channel::<(usize, mpsc::Receiver<JC>, mpsc::Sender<JC>)>();
+    let job_receiver = Mutex::new(job_receiver);
+    let (error_sender, error_receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..options.parallelism {
+            let error_sender = error_sender.clone();
+            let (job_receiver, first_failure, worker_options) = (&job_receiver, &first_failure, &worker_options);
+            scope.spawn(move || loop {
+                // The lock is released once the job is received, so the other workers can take the next one
+                let job = job_receiver.lock().unwrap().recv();
+                let (index, value_stream, result_sender) = match job {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                if index > first_failure.load(atomic::Ordering::SeqCst) {
+                    consume_value(value_stream);
+                    result_sender.send(JC::Null).unwrap();
+                    continue;
+                }
+                if let Err(error) = eval_map_value(next_acessor, worker_options, value_stream, result_sender) {
+                    first_failure.fetch_min(index, atomic::Ordering::SeqCst);
+                    error_sender.send((index, error)).unwrap();
+                }
+            });
+        }
+        for (index, value_stream) in value_streams.enumerate() {
+            let value_stream = match value_stream {
+                Some(value_stream) => value_stream,
+                None => {
+                    sender.send(JC::Skipped).unwrap();
+                    continue;
+                }
+            };
+            if index > first_failure.load(atomic::Ordering::SeqCst) {
+                consume_value(value_stream);
+                sender.send(JC::Stream(null_stream())).unwrap();
+                continue;
+            }
+            let (result_sender, result_receiver) = mpsc::channel::<JC>();
+            sender.send(JC::Stream(result_receiver)).unwrap();
+            job_sender.send((index, value_stream, result_sender)).unwrap();
+        }
+        drop(job_sender); // Lets the workers finish once the jobs run out
+    });
+    drop(error_sender);
+    // Every value before the first failure was evaluated, so the error with the smallest index is the first failure
+    match error_receiver.into_iter().min_by_key(|&(index, _)| index) {
+        Some((_, error)) => Err(error),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod map_tests {
+    use super::*;
+
+    fn packets(packets: Vec<JC>) -> mpsc::Receiver<JC> {
+        let (sender, receiver) = mpsc::channel::<JC>();
+        for packet in packets {
+            sender.send(packet).unwrap();
+        }
+        receiver
+    }
+
+    fn map(options: &EvalOptions, receiver: mpsc::Receiver<JC>) -> mpsc::Receiver<JC> {
+        let acessor: Acessor = "map(length)".parse().unwrap();
+        let (sender, result_receiver) = mpsc::channel::<JC>();
+        eval_with_options(&acessor, options, receiver, sender).unwrap();
+        result_receiver
+    }
+
+    #[test]
+    fn legacy_and_new_shapes_give_the_same_json() {
+        let json = json!(["a", "bb", [1, 2, 3], null]);
+        let legacy = EvalOptions { legacy_map_shape: true, ..EvalOptions::default() };
+        assert_eq!(deserialise_json(map(&legacy, jc_stream(&json))), json!([1, 2, 3, 0]));
+        assert_eq!(deserialise_json(map(&EvalOptions::default(), jc_stream(&json))), json!([1, 2, 3, 0]));
+
+        // Only the new shape follows the array protocol, so only it can be read by another stage
+        let (sender, _receiver) = mpsc::channel::<JC>();
+        assert!(validate_jc(map(&EvalOptions::default(), jc_stream(&json)), sender).is_ok());
+        let (sender, _receiver) = mpsc::channel::<JC>();
+        let error = validate_jc(map(&legacy, jc_stream(&json)), sender).unwrap_err();
+        assert_eq!(error.to_string(), "packet 2 at [0]: expected a Stream channel but received Number");
+    }
+
+    #[test]
+    fn skipped_values_have_skipped_results() {
+        for options in [
+            EvalOptions::default(),
+            EvalOptions { parallelism: 2, ..EvalOptions::default() },
+            EvalOptions { legacy_map_shape: true, ..EvalOptions::default() },
+        ] {
+            let value = packets(vec![JC::String("ab".to_string())]);
+            let receiver = map(&options, packets(vec![JC::ArrayStart, JC::ArrayLen(2), JC::Skipped, JC::Stream(value), JC::ArrayEnd]));
+            let results: Vec<&str> = receiver.iter().map(|packet| match packet {
+                JC::Stream(result) => match result.recv().unwrap() {
+                    JC::Number(2.0) => "2",
+                    _ => "other",
+                },
+                JC::Number(2.0) => "2",
+                packet => jc_packet_name(&packet),
+            }).collect();
+            assert_eq!(results, vec!["ArrayStart", "ArrayLen", "Skipped", "2", "ArrayEnd"], "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn failed_values_leave_a_complete_array() {
+        let acessor: Acessor = r#"map(."a")"#.parse().unwrap();
+        let json = json!([{"a": 1}, 5, {"a": 2}, {"a": 3}]);
+        for parallelism in [1, 2, 4] {
+            let options = EvalOptions { parallelism, ..EvalOptions::default() };
+            let (sender, receiver) = mpsc::channel::<JC>();
+            let result = eval_with_options(&acessor, &options, jc_stream(&json), sender);
+            assert!(matches!(result, Err(EvalError::TypeMismatch(_))), "parallelism {}", parallelism);
+            let results = deserialise_json(receiver);
+            assert_eq!(results[0], json!(1), "parallelism {}", parallelism);
+            assert_eq!(results[1], Json::Null, "parallelism {}", parallelism);
+            // The values after the failure may or may not have been evaluated by the other workers
+            assert_eq!(results.as_array().map(|results| results.len()), Some(4), "parallelism {}", parallelism);
+        }
+        let (sender, receiver) = mpsc::channel::<JC>();
+        assert!(eval(&acessor, jc_stream(&json), sender).is_err());
+        assert_eq!(deserialise_json(receiver), json!([1, null, null, null]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected Value Stream, but the value was Skipped although it is needed (Value Stream)")]
+    fn skipped_values_are_not_read_one_at_a_time() {
+        let acessor: Acessor = "sum".parse().unwrap();
+        let (sender, _receiver) = mpsc::channel::<JC>();
+        let _ = eval(&acessor, packets(vec![JC::ArrayStart, JC::ArrayLen(1), JC::Skipped, JC::ArrayEnd]), sender);
+    }
+}
+
+#[cfg(test)]
+mod parallel_map_tests {
+    use super::*;
+    use std::time::Duration;
+
+    /**
+     * Options with a slow function, which takes longer for the first values of 0..10 so that the later ones finish
+     * first, and fails for 3 and 7
+     */
+    fn slow_options(parallelism: usize) -> EvalOptions {
+        let mut functions = FunctionRegistry::default();
+        functions.register("slow", |input, _| {
+            let value = input.as_f64().unwrap_or_default();
+            thread::sleep(Duration::from_millis(2 * (10.0 - value) as u64));
+            match value as u64 {
+                3 | 7 => Err(EvalError::InvalidArgument(format!("{} is not allowed", value))),
+                _ => Ok(Json::Number(value * 10.0)),
+            }
+        });
+        EvalOptions { functions, parallelism, ..EvalOptions::default() }
+    }
+
+    fn map_slow(options: &EvalOptions, json: &Json) -> Result<Json, EvalError> {
+        let acessor: Acessor = "map(slow)".parse().unwrap();
+        let (sender, receiver) = mpsc::channel::<JC>();
+        eval_with_options(&acessor, options, jc_stream(json), sender)?;
+        Ok(deserialise_json(receiver))
+    }
+
+    #[test]
+    fn results_keep_the_order_of_the_array() {
+        let json = json!([0, 1, 2, 4, 5, 6, 8, 9]);
+        let expected = json!([0, 10, 20, 40, 50, 60, 80, 90]);
+        for parallelism in [1, 2, 4, 8, 16] {
+            assert_eq!(map_slow(&slow_options(parallelism), &json), Ok(expected.clone()), "parallelism {}", parallelism);
+        }
+    }
+
+    #[test]
+    fn the_first_failure_is_reported() {
+        // 7 fails before 3 with parallel workers, but 3 comes first in the array
+        let json = json!([0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        for parallelism in [1, 2, 4, 10] {
+            assert_eq!(map_slow(&slow_options(parallelism), &json),
+                Err(EvalError::InvalidArgument(String::from("3 is not allowed"))), "parallelism {}", parallelism);
+        }
+        assert_eq!(map_slow(&slow_options(4), &json!([9, 8, 7])), Err(EvalError::InvalidArgument(String::from("7 is not allowed"))));
+    }
+}
+
+/**
+ * Consumes the rest of a value the acessor cannot be applied to
+ */
+fn type_mismatch(message: &str, first_packet: JC, receiver: mpsc::Receiver<JC>) -> Result<(), EvalError> {
+    consume_value_from(first_packet, receiver);
+    Err(EvalError::TypeMismatch(message.to_string()))
+}
+
+/**
+ * Iterator over the values of a streamed array or object, with the key of each object value.
+ * The end of the array or object is read after the last value
+ */
+struct ValueStream {
+    receiver: mpsc::Receiver<JC>,
+    is_object: bool,
+    len: usize,
+    position: usize,
+    finished: bool,
+}
+
+impl ValueStream {
+    /**
+     * Starts reading an array or object. Simple values are given back as Err, and have nothing left to read
+     */
+    fn open(first_packet: JC, receiver: mpsc::Receiver<JC>) -> Result<ValueStream, JC> {
+        let is_object = match first_packet {
+            JC::ArrayStart => false,
+            JC::ObjectStart => true,
+            JC::Number(_) | JC::String(_) | JC::Boolean(_) | JC::Null => return Err(first_packet),
+            _ => panic!("Unexpected ArrayEnd, ObjectEnd, ArryLen or Stream here. (Value Stream)")
+        };
+        match receiver.recv().unwrap() {
+            JC::ArrayLen(len) => Ok(ValueStream { receiver, is_object, len, position: 0, finished: false }),
+            _ => panic!("Expected ArrayLen (Value Stream)")
+        }
+    }
+}
+
+impl Iterator for ValueStream {
+    type Item = (Option<String>, mpsc::Receiver<JC>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position == self.len {
+            if !self.finished {
+                self.finished = true;
+                match (self.receiver.recv().unwrap(), self.is_object) {
+                    (JC::ArrayEnd, false) | (JC::ObjectEnd, true) => (),
+                    _ => panic!("Expected Array End or Object End (Value Stream)")
+                }
+            }
+            return None;
+        }
+        let key = if self.is_object {
+            match self.receiver.recv().unwrap() {
+                JC::String(key) => Some(key),
+                _ => panic!("Expected Object Label (Value Stream)")
+            }
+        } else {
+            None
+        };
+        self.position += 1;
+        match self.receiver.recv().unwrap() {
+            JC::Stream(value_stream) => Some((key, value_stream)),
+            // The acessors that read the values one at a time need all of them, so their projection is All
+            JC::Skipped => panic!("Expected Value Stream, but the value was Skipped although it is needed (Value Stream)"),
+            _ => panic!("Expected Value Stream (Value Stream)")
+        }
+    }
+}
+
+/**
+ * Null and false are false, every other value is true
+ */
+fn is_truthy(first_packet: &JC) -> bool {
+    !matches!(first_packet, JC::Null | JC::Boolean(false))
+}
+
+/**
+ * Evaluates the aggregate acessors, reading one value at a time so only the current result is kept in memory
+ */
+fn eval_aggregate(acessor: &Acessor, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<(), EvalError> {
+    let first_packet = receiver.recv().unwrap();
+    let values = match (ValueStream::open(first_packet, receiver), acessor) {
+        (Ok(values), _) => values,
+        (Err(JC::String(str)), Acessor::Length) => {
+            sender.send(JC::Number(str.chars().count() as f64)).unwrap();
+            return Ok(());
+        },
+        (Err(JC::Null), Acessor::Length) => {
+            sender.send(JC::Number(0.0)).unwrap();
+            return Ok(());
+        },
+        (Err(_), _) => return Err(EvalError::TypeMismatch(format!("Cannot apply {} to this Json Value", acessor))),
+    };
+    match acessor {
+        Acessor::Length => {
+            let len = values.len;
+            values.for_each(|(_, value_stream)| consume_value(value_stream));
+            sender.send(JC::Number(len as f64)).unwrap();
+        },
+        Acessor::Keys => {
+            sender.send(JC::ArrayStart).unwrap();
+            sender.send(JC::ArrayLen(values.len)).unwrap();
+            for (i, (key, value_stream)) in values.enumerate() {
+                consume_value(value_stream);
+                let (key_sender, key_receiver) = mpsc::channel::<JC>();
+                sender.send(JC::Stream(key_receiver)).unwrap();
+                key_sender.send(match key {
+                    Some(key) => JC::String(key),
+                    None => JC::Number(i as f64),
+                }).unwrap();
+            }
+            sender.send(JC::ArrayEnd).unwrap();
+        },
+        Acessor::Values => {
+            sender.send(JC::ArrayStart).unwrap();
+            sender.send(JC::ArrayLen(values.len)).unwrap();
+            for (_, value_stream) in values {
+                sender.send(JC::Stream(value_stream)).unwrap();
+            }
+            sender.send(JC::ArrayEnd).unwrap();
+        },
+        Acessor::Sum => {
+            let mut sum = Ok(0.0);
+            for (_, value_stream) in values {
+                match (value_stream.recv().unwrap(), &mut sum) {
+                    (JC::Number(num), Ok(sum)) => *sum += num,
+                    (JC::Null, _) => (),
+                    (first_packet, _) => {
+                        consume_value_from(first_packet, value_stream);
+                        sum = Err(EvalError::TypeMismatch("Sum can only add numbers".to_string()));
+                    },
+                }
+            }
+            sender.send(JC::Number(sum?)).unwrap();
+        },
+        Acessor::Min | Acessor::Max => {
+            let mut best: Option<Json> = None;
+            for (_, value_stream) in values {
+                let value = deserialise_json(value_stream);
+                let better = match (&best, acessor) {
+                    (None, _) => true,
+                    (Some(best), Acessor::Min) => value < *best,
+                    (Some(best), _) => value >= *best, // Keeps the last of equal values, like Iterator::max
+                };
+                if better {
+                    best = Some(value);
+                }
+            }
+            serialise_json(&best.unwrap_or(Json::Null), sender);
+        },
+        Acessor::Count | Acessor::Any | Acessor::All => {
+            let (mut count, mut truthy, mut len) = (0, 0, 0);
+            for (_, value_stream) in values {
+                let first_packet = value_stream.recv().unwrap();
+                match first_packet {
+                    JC::Null => (),
+                    _ => count += 1,
+                }
+                if is_truthy(&first_packet) {
+                    truthy += 1;
+                }
+                len += 1;
+                consume_value_from(first_packet, value_stream);
+            }
+            sender.send(match acessor {
+                Acessor::Count => JC::Number(count as f64),
+                Acessor::Any => JC::Boolean(truthy > 0),
+                _ => JC::Boolean(truthy == len),
+            }).unwrap();
+        },
+        _ => panic!("Not an aggregate acessor (Eval Aggregate)")
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use super::*;
+    use std::mem;
+
+    /**
+     * Evaluates the aggregate on the streamed value, checking that apply gives the same result (the messages of
+     * the errors are worded differently)
+     */
+    fn aggregate(query: &str, json: &Json) -> Result<Json, EvalError> {
+        let acessor: Acessor = query.parse().unwrap();
+        let (sender, receiver) = mpsc::channel::<JC>();
+        let streamed = eval(&acessor, jc_stream(json), sender).map(|_| deserialise_json(receiver));
+        let applied = acessor.apply(json).map(Cow::into_owned);
+        assert_eq!(streamed.as_ref().ok(), applied.as_ref().ok(), "{} on {}", query, json);
+        assert_eq!(streamed.as_ref().err().map(mem::discriminant), applied.as_ref().err().map(mem::discriminant), "{} on {}", query, json);
+        streamed
+    }
+
+    #[test]
+    fn count_skips_nulls() {
+        assert_eq!(aggregate("count", &json!([1, null, "a", false, null, [null]])), Ok(json!(4)));
+        assert_eq!(aggregate("count", &json!({"a": null, "b": 0})), Ok(json!(1)));
+        assert_eq!(aggregate("count", &json!([null, null])), Ok(json!(0)));
+    }
+
+    #[test]
+    fn min_and_max_of_empty_and_mixed_arrays() {
+        assert_eq!(aggregate("min", &json!([])), Ok(Json::Null));
+        assert_eq!(aggregate("max", &json!([])), Ok(Json::Null));
+        // Values of different types follow the order of Json values
+        let mixed = json!(["b", 3, null, [1], true, {"a": 1}, 1.5]);
+        assert_eq!(aggregate("min", &mixed), mixed.as_array().unwrap().iter().min().cloned().ok_or(EvalError::NoValue));
+        assert_eq!(aggregate("max", &mixed), mixed.as_array().unwrap().iter().max().cloned().ok_or(EvalError::NoValue));
+        assert_eq!(aggregate("min", &json!([3, 1.5, 2])), Ok(json!(1.5)));
+        assert_eq!(aggregate("max", &json!({"a": "x", "b": "y"})), Ok(json!("y")));
+    }
+
+    #[test]
+    fn keys_and_values_of_objects() {
+        let json = json!({"b": [1, 2], "a": null, "c": {"d": true}});
+        assert_eq!(aggregate("keys", &json), Ok(json!(["a", "b", "c"])));
+        assert_eq!(aggregate("values", &json), Ok(json!([null, [1, 2], {"d": true}])));
+        assert_eq!(aggregate("keys", &json!(["x", "y"])), Ok(json!([0, 1])));
+        assert_eq!(aggregate("keys", &json!({})), Ok(json!([])));
+    }
+
+    #[test]
+    fn length_of_strings_and_null() {
+        assert_eq!(aggregate("length", &json!("héllo")), Ok(json!(5)));
+        assert_eq!(aggregate("length", &json!("")), Ok(json!(0)));
+        assert_eq!(aggregate("length", &Json::Null), Ok(json!(0)));
+        assert_eq!(aggregate("length", &json!({"a": 1, "b": 2})), Ok(json!(2)));
+        assert!(matches!(aggregate("length", &json!(true)), Err(EvalError::TypeMismatch(_))));
+    }
+
+    #[test]
+    fn any_and_all_of_empty_arrays() {
+        assert_eq!(aggregate("any", &json!([])), Ok(json!(false)));
+        assert_eq!(aggregate("all", &json!([])), Ok(json!(true)));
+        assert_eq!(aggregate("any", &json!([null, false, 0])), Ok(json!(true)));
+        assert_eq!(aggregate("all", &json!([1, "a", null])), Ok(json!(false)));
+    }
+}
+
+/**
+ * Evaluates SortBy, UniqueBy and GroupBy. The values of the array are buffered with their keys, and when they go over
+ * the memory budget they are sorted and written to a run file. The runs are merged at the end, reading one value of
+ * each run at a time.
+ * The values are not deserialised: they are kept in the binary encoding of write_json_binary as they stream, and only
+ * the parts the key acessor reads are copied to evaluate their key.
+ * Run files that can not be written or read are an Io error
+ */
+fn eval_sort(acessor: &Acessor, key_acessor: &Acessor, options: &EvalOptions, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<(), EvalError> {
+    let first_packet = receiver.recv().unwrap();
+    let values = match ValueStream::open(first_packet, receiver) {
+        Ok(values) => values,
+        Err(_) => return Err(EvalError::TypeMismatch(format!("Cannot apply {} to this Json Value", acessor))),
+    };
+    if values.is_object {
+        values.for_each(|(_, value_stream)| consume_value(value_stream));
+        return Err(EvalError::TypeMismatch(format!("Cannot apply {} to an object", acessor)));
+    }
+    let len = values.len;
+    let key_projection = key_acessor.projection(Projection::All);
+    let mut buffer = SortBuffer::new(options);
+    let mut result = Ok(());
+    for (_, value_stream) in values {
+        if result.is_err() {
+            consume_value(value_stream);
+            continue;
+        }
+        let (key_sender, key_input) = mpsc::channel::<JC>();
+        let mut writers = (ProjectedWriter::new(key_sender, &key_projection), BinaryWriter { bytes: vec![] });
+        walk_jc(value_stream, &mut writers);
+        let (_, value) = writers;
+        let (key_sender, key_receiver) = mpsc::channel::<JC>();
+        match eval_with_options(key_acessor, options, key_input, key_sender) {
+            // Values the key acessor reaches nothing for have a null key
+            Ok(()) => result = buffer.push(produced_value(key_receiver).map_or(Json::Null, deserialise_json), value.bytes),
+            Err(error) => result = Err(error),
+        }
+    }
+    result?;
+    let sorted = buffer.finish()?;
+    let (len, sorted) = match acessor {
+        Acessor::SortBy(_) => (len, sorted),
+        _ => sorted.count_distinct_keys()?,
+    };
+    send_sorted(acessor, sorted, len, &sender)
+}
+
+/**
+ * Sends the sorted array, with len values or groups. When a run can not be read the rest of the values are null,
+ * so the array is still complete, and the error is returned
+ */
+fn send_sorted(acessor: &Acessor, sorted: SortedEntries, len: usize, sender: &mpsc::Sender<JC>) -> Result<(), EvalError> {
+    sender.send(JC::ArrayStart).unwrap();
+    sender.send(JC::ArrayLen(len)).unwrap();
+    let mut sent = 0;
+    let result = send_sorted_values(acessor, sorted, sender, &mut sent);
+    for _ in sent..len {
+        sender.send(JC::Stream(null_stream())).unwrap();
+    }
+    sender.send(JC::ArrayEnd).unwrap();
+    result
+}
+
+fn send_sorted_values(acessor: &Acessor, sorted: SortedEntries, sender: &mpsc::Sender<JC>, sent: &mut usize) -> Result<(), EvalError> {
+    match acessor {
+        Acessor::SortBy(_) => {
+            for entry in sorted.into_entries() {
+                let (_, value) = entry.map_err(sort_run_error)?;
+                send_binary_value(&value, sender)?;
+                *sent += 1;
+            }
+        },
+        Acessor::UniqueBy(_) => {
+            let mut last_key = None;
+            for entry in sorted.into_entries() {
+                let (key, value) = entry.map_err(sort_run_error)?;
+                if last_key.as_ref() != Some(&key) {
+                    send_binary_value(&value, sender)?;
+                    *sent += 1;
+                    last_key = Some(key);
+                }
+            }
+        },
+        _ => {
+            // The group is only sent once all of its values could be read
+            let send_group = |values: Vec<Vec<u8>>| -> Result<(), EvalError> {
+                let (group_sender, group_receiver) = mpsc::channel::<JC>();
+                group_sender.send(JC::ArrayStart).unwrap();
+                group_sender.send(JC::ArrayLen(values.len())).unwrap();
+                for value in values {
+                    send_binary_value(&value, &group_sender)?;
+                }
+                group_sender.send(JC::ArrayEnd).unwrap();
+                sender.send(JC::Stream(group_receiver)).unwrap();
+                Ok(())
+            };
+            let mut group: Option<(Json, Vec<Vec<u8>>)> = None;
+            for entry in sorted.into_entries() {
+                let (key, value) = entry.map_err(sort_run_error)?;
+                match group {
+                    Some((ref group_key, ref mut values)) if *group_key == key => values.push(value),
+                    _ => {
+                        if let Some((_, values)) = group.take() {
+                            send_group(values)?;
+                            *sent += 1;
+                        }
+                        group = Some((key, vec![value]));
+                    },
+                }
+            }
+            if let Some((_, values)) = group {
+                send_group(values)?;
+                *sent += 1;
+            }
+        },
+    }
+    Ok(())
+}
+
+fn sort_run_error(error: io::Error) -> EvalError {
+    EvalError::Io(format!("Could not read sort run: {}", error))
+}
+
+/**
+ * Sends a value encoded with write_json_binary in its own channel of the array being sent. The channel is only sent
+ * once the whole value could be decoded
+ */
+fn send_binary_value(value: &[u8], sender: &mpsc::Sender<JC>) -> Result<(), EvalError> {
+    let (value_sender, value_receiver) = mpsc::channel::<JC>();
+    send_json_binary(&mut &value[..], value_sender).map_err(|error| EvalError::Io(format!("Could not read sorted value: {}", error)))?;
+    sender.send(JC::Stream(value_receiver)).unwrap();
+    Ok(())
+}
+
+/**
+ * Rough amount of memory used by a value, to decide when a SortBuffer goes over the memory budget
+ */
+fn approximate_size(json: &Json) -> usize {
+    std::mem::size_of::<Json>() + match json {
+        Json::String(str) => str.len(),
+        Json::Array(array) => array.iter().map(approximate_size).sum(),
+        Json::Object(map) => map.iter().map(|(key, value)| key.len() + approximate_size(value)).sum(),
+        _ => 0,
+    }
+}
+
+/**
+ * (key, position in the array, value encoded with write_json_binary) entries being sorted.
+ * The position keeps the sort stable
+ */
+type SortEntry = (Json, u64, Vec<u8>);
+
+struct SortBuffer<'a> {
+    options: &'a EvalOptions,
+    entries: Vec<SortEntry>,
+    size: usize,
+    pushed: u64,
+    runs: Vec<SpillRun>,
+}
+
+impl<'a> SortBuffer<'a> {
+    fn new(options: &'a EvalOptions) -> Self {
+        SortBuffer { options, entries: vec![], size: 0, pushed: 0, runs: vec![] }
+    }
+
+    fn push(&mut self, key: Json, value: Vec<u8>) -> Result<(), EvalError> {
+        self.size += approximate_size(&key) + std::mem::size_of::<SortEntry>() + value.len();
+        self.entries.push((key, self.pushed, value));
+        self.pushed += 1;
+        if self.size > self.options.memory_budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn directory(&self) -> PathBuf {
+        self.options.spill_directory.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    fn spill(&mut self) -> Result<(), EvalError> {
+        self.entries.sort();
+        let run = SpillRun::write(&self.directory(), self.entries.drain(..).map(Ok))
+            .map_err(|error| EvalError::Io(format!("Could not write sort run: {}", error)))?;
+        self.runs.push(run);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<SortedEntries, EvalError> {
+        if self.runs.is_empty() {
+            self.entries.sort();
+            return Ok(SortedEntries::Memory(self.entries));
+        }
+        if !self.entries.is_empty() {
+            self.spill()?;
+        }
+        let directory = self.directory();
+        Ok(SortedEntries::Runs(self.runs, directory))
+    }
+}
+
+enum SortedEntries {
+    Memory(Vec<SortEntry>),
+    Runs(Vec<SpillRun>, PathBuf), // Sorted runs, with the directory they are in
+}
+
+impl SortedEntries {
+    /**
+     * Number of distinct keys, for UniqueBy and GroupBy which send it before the values. The runs are merged into a
+     * single run while counting, so that reading the entries afterwards does not merge them again
+     */
+    fn count_distinct_keys(self) -> Result<(usize, SortedEntries), EvalError> {
+        let mut count = 0;
+        let mut last_key: Option<Json> = None;
+        let mut count_key = |key: &Json| {
+            if last_key.as_ref() != Some(key) {
+                count += 1;
+                last_key = Some(key.clone());
+            }
+        };
+        let sorted = match self {
+            SortedEntries::Memory(entries) => {
+                entries.iter().for_each(|(key, _, _)| count_key(key));
+                SortedEntries::Memory(entries)
+            },
+            SortedEntries::Runs(runs, directory) => {
+                let merge = RunMerge::open(&runs).map_err(sort_run_error)?;
+                let merged = SpillRun::write(&directory, merge.inspect(|entry| {
+                    if let Ok((key, _, _)) = entry {
+                        count_key(key);
+                    }
+                })).map_err(|error| EvalError::Io(format!("Could not merge sort runs: {}", error)))?;
+                SortedEntries::Runs(vec![merged], directory)
+            },
+        };
+        Ok((count, sorted))
+    }
+
+    /**
+     * The (key, value) entries in order, or the error of a run that could not be read, after which there are no
+     * more entries. Run files are removed once they have been read
+     */
+    fn into_entries(self) -> Box<dyn Iterator<Item = io::Result<(Json, Vec<u8>)>>> {
+        match self {
+            SortedEntries::Memory(entries) => Box::new(entries.into_iter().map(|(key, _, value)| Ok((key, value)))),
+            SortedEntries::Runs(runs, _) => match RunMerge::open(&runs) {
+                Ok(mut merge) => {
+                    merge.runs = runs;
+                    Box::new(merge.map(|entry| entry.map(|(key, _, value)| (key, value))))
+                },
+                Err(error) => Box::new(std::iter::once(Err(error))),
+            },
+        }
+    }
+}
+
+static SPILL_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/**
+ * File with sorted entries, with the keys encoded with write_json_binary. The file is removed when the run is dropped
+ */
+struct SpillRun {
+    path: PathBuf,
+}
+
+impl SpillRun {
+    fn write<I: IntoIterator<Item = io::Result<SortEntry>>>(directory: &Path, entries: I) -> io::Result<SpillRun> {
+        let run_number = SPILL_RUNS.fetch_add(1, atomic::Ordering::Relaxed);
+        let run = SpillRun { path: directory.join(format!("jc-sort-{}-{}.run", process::id(), run_number)) };
+        let mut writer = BufWriter::new(File::create(&run.path)?);
+        for entry in entries {
+            let (key, position, value) = entry?;
+            write_json_binary(&key, &mut writer)?;
+            writer.write_all(&position.to_le_bytes())?;
+            write_binary_bytes(&value, &mut writer)?;
+        }
+        writer.flush()?;
+        Ok(run)
+    }
+}
+
+impl Drop for SpillRun {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/**
+ * Merges sorted runs, keeping the next entry of each run in a heap
+ */
+struct RunMerge {
+    runs: Vec<SpillRun>, // Set when this is the last read of the runs, so their files are removed after the merge
+    readers: Vec<BufReader<File>>,
+    values: Vec<Option<Vec<u8>>>, // Value of the entry of each run that is in the heap
+    heap: BinaryHeap<Reverse<(Json, u64, usize)>>,
+}
+
+impl RunMerge {
+    fn open(runs: &[SpillRun]) -> io::Result<Self> {
+        let mut merge = RunMerge { runs: vec![], readers: vec![], values: vec![], heap: BinaryHeap::new() };
+        for run in runs {
+            merge.readers.push(BufReader::new(File::open(&run.path)?));
+            merge.values.push(None);
+            merge.read_next(merge.readers.len() - 1)?;
+        }
+        Ok(merge)
+    }
+
+    fn read_next(&mut self, run: usize) -> io::Result<()> {
+        let reader = &mut self.readers[run];
+        let key = match read_json_binary(reader) {
+            Ok(key) => key,
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()), // The run is over
+            Err(error) => return Err(error),
+        };
+        let position = read_u64(reader)?;
+        self.values[run] = Some(read_binary_bytes(reader)?);
+        self.heap.push(Reverse((key, position, run)));
+        Ok(())
+    }
+}
+
+/**
+ * The entries of the runs in order. After an entry that could not be read the merge is over
+ */
+impl Iterator for RunMerge {
+    type Item = io::Result<SortEntry>;
+
+    fn next(&mut self) -> Option<io::Result<SortEntry>> {
+        let Reverse((key, position, run)) = self.heap.pop()?;
+        let value = self.values[run].take().unwrap();
+        if let Err(error) = self.read_next(run) {
+            self.heap.clear();
+            return Some(Err(error));
+        }
+        Some(Ok((key, position, value)))
+    }
+}
+
+/**
+ * Compact binary encoding of json values, used for the runs of sorts that spill to disk.
+ * A tag byte is followed by the contents: f64 numbers, and strings, arrays and objects prefixed with their lengths
+ */
+fn write_json_binary<W: Write>(json: &Json, writer: &mut W) -> io::Result<()> {
+    match json {
+        Json::Null => writer.write_all(&[0]),
+        Json::Boolean(false) => writer.write_all(&[1]),
+        Json::Boolean(true) => writer.write_all(&[2]),
+        Json::Number(num) => {
+            writer.write_all(&[3])?;
+            writer.write_all(&num.to_le_bytes())
+        },
+        Json::String(str) => {
+            writer.write_all(&[4])?;
+            write_binary_string(str, writer)
+        },
+        Json::Array(array) => {
+            writer.write_all(&[5])?;
+            writer.write_all(&(array.len() as u64).to_le_bytes())?;
+            for json_value in array {
+                write_json_binary(json_value, writer)?;
+            }
+            Ok(())
+        },
+        Json::Object(map) => {
+            writer.write_all(&[6])?;
+            writer.write_all(&(map.len() as u64).to_le_bytes())?;
+            for (key, json_value) in map {
+                write_binary_string(key, writer)?;
+                write_json_binary(json_value, writer)?;
+            }
+            Ok(())
+        },
+    }
+}
+
+fn write_binary_string<W: Write>(str: &str, writer: &mut W) -> io::Result<()> {
+    write_binary_bytes(str.as_bytes(), writer)
+}
+
+fn write_binary_bytes<W: Write>(bytes: &[u8], writer: &mut W) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_json_binary<R: Read>(reader: &mut R) -> io::Result<Json> {
+    let mut tag = [0u8; 1];
+    reader.read_exact(&mut tag)?;
+    Ok(match tag[0] {
+        0 => Json::Null,
+        1 => Json::Boolean(false),
+        2 => Json::Boolean(true),
+        3 => Json::Number(f64::from_bits(read_u64(reader)?)),
+        4 => Json::String(read_binary_string(reader)?),
+        5 => {
+            let len = read_u64(reader)?;
+            let mut array = vec![];
+            for _ in 0..len {
+                array.push(read_json_binary(reader)?);
+            }
+            Json::Array(array)
+        },
+        6 => {
+            let len = read_u64(reader)?;
+            let mut map = BTreeMap::new();
+            for _ in 0..len {
+                let key = read_binary_string(reader)?;
+                map.insert(key, read_json_binary(reader)?);
+            }
+            Json::Object(map)
+        },
+        tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown tag {}", tag))),
+    })
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_binary_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let bytes = read_binary_bytes(reader)?;
+    String::from_utf8(bytes).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+fn read_binary_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; read_u64(reader)? as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/**
+ * Sends a value encoded with write_json_binary through the sender channel, following the protocols described above
+ * serialise_json, without deserialising it
+ */
+fn send_json_binary<R: Read>(reader: &mut R, sender: mpsc::Sender<JC>) -> io::Result<()> {
+    let mut tag = [0u8; 1];
+    reader.read_exact(&mut tag)?;
+    match tag[0] {
+        0 => sender.send(JC::Null).unwrap(),
+        1 => sender.send(JC::Boolean(false)).unwrap(),
+        2 => sender.send(JC::Boolean(true)).unwrap(),
+        3 => sender.send(JC::Number(f64::from_bits(read_u64(reader)?))).unwrap(),
+        4 => sender.send(JC::String(read_binary_string(reader)?)).unwrap(),
+        5 => {
+            let len = read_u64(reader)? as usize;
+            sender.send(JC::ArrayStart).unwrap();
+            sender.send(JC::ArrayLen(len)).unwrap();
+            for _ in 0..len {
+                let (value_sender, value_receiver) = mpsc::channel::<JC>();
+                sender.send(JC::Stream(value_receiver)).unwrap();
+                send_json_binary(reader, value_sender)?;
+            }
+            sender.send(JC::ArrayEnd).unwrap();
+        },
+        6 => {
+            let len = read_u64(reader)? as usize;
+            sender.send(JC::ObjectStart).unwrap();
+            sender.send(JC::ArrayLen(len)).unwrap();
+            for _ in 0..len {
+                sender.send(JC::String(read_binary_string(reader)?)).unwrap();
+                let (value_sender, value_receiver) = mpsc::channel::<JC>();
+                sender.send(JC::Stream(value_receiver)).unwrap();
+                send_json_binary(reader, value_sender)?;
+            }
+            sender.send(JC::ObjectEnd).unwrap();
+        },
+        tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown tag {}", tag))),
+    }
+    Ok(())
+}
+
+/**
+ * Visitor that encodes the value it walks with write_json_binary
+ */
+struct BinaryWriter {
+    bytes: Vec<u8>,
+}
+
+impl JcVisitor for BinaryWriter {
+    fn on_scalar(&mut self, value: Json) {
+        write_json_binary(&value, &mut self.bytes).unwrap();
+    }
+
+    fn enter_array(&mut self, len: usize) {
+        self.bytes.push(5);
+        self.bytes.extend_from_slice(&(len as u64).to_le_bytes());
+    }
+
+    fn enter_object(&mut self, len: usize) {
+        self.bytes.push(6);
+        self.bytes.extend_from_slice(&(len as u64).to_le_bytes());
+    }
+
+    fn on_key(&mut self, key: String) {
+        write_binary_string(&key, &mut self.bytes).unwrap();
+    }
+
+    fn on_skipped(&mut self) {
+        panic!("Skipped values cannot be encoded (Binary Writer)");
+    }
+}
+
+#[cfg(test)]
+mod sort_tests {
+    use super::*;
+
+    fn sort_stream(query: &str, options: &EvalOptions, json: &Json) -> Json {
+        let acessor: Acessor = query.parse().unwrap();
+        let (sender, receiver) = mpsc::channel::<JC>();
+        eval_with_options(&acessor, options, jc_stream(json), sender).unwrap();
+        // validate_jc checks that the ArrayLen sent before the values matches them
+        let (validated_sender, validated_receiver) = mpsc::channel::<JC>();
+        validate_jc(receiver, validated_sender).unwrap();
+        deserialise_json(validated_receiver)
+    }
+
+    #[test]
+    fn spilled_sorts_match_the_in_memory_ones() {
+        let mut rng = TestRng(0x9E37_79B9_7F4A_7C15);
+        let json = Json::Array((0..300).map(|i| json!({"k": rng.below(20), "v": i, "rest": [rng.json(2)]})).collect());
+        let directory = std::env::temp_dir().join(format!("jc-sort-test-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let spilling = EvalOptions { memory_budget: 2048, spill_directory: Some(directory.clone()), ..EvalOptions::default() };
+        let runs_before = SPILL_RUNS.load(atomic::Ordering::Relaxed);
+        for query in [r#"sort_by(."k")"#, r#"unique_by(."k")"#, r#"group_by(."k")"#, r#"sort_by(."rest"[0])"#] {
+            let in_memory = sort_stream(query, &EvalOptions::default(), &json);
+            assert_eq!(in_memory, query.parse::<Acessor>().unwrap().apply(&json).unwrap().into_owned());
+            assert_eq!(sort_stream(query, &spilling, &json), in_memory, "{}", query);
+        }
+        assert!(SPILL_RUNS.load(atomic::Ordering::Relaxed) > runs_before + 4);
+        let unique = sort_stream(r#"unique_by(."k")"#, &spilling, &json);
+        assert_eq!(unique.as_array().unwrap().len(), 20);
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 0); // the runs are removed
+        fs::remove_dir(&directory).unwrap();
+    }
+
+    #[test]
+    fn run_files_that_can_not_be_written_are_errors() {
+        let options = EvalOptions { memory_budget: 0, spill_directory: Some(PathBuf::from("/nonexistent/jc-sort")), ..EvalOptions::default() };
+        let (sender, receiver) = mpsc::channel::<JC>();
+        let result = eval_with_options(&"sort_by(.)".parse().unwrap(), &options, jc_stream(&json!([2, 1])), sender);
+        assert!(matches!(result, Err(EvalError::Io(_))));
+        assert!(produced_value(receiver).is_none());
+    }
+
+    #[test]
+    fn run_files_that_can_not_be_read_leave_a_complete_array() {
+        let directory = std::env::temp_dir().join(format!("jc-sort-read-test-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let entry = |key: f64, position: u64| {
+            let mut value = vec![];
+            write_json_binary(&json!({"k": key}), &mut value).unwrap();
+            Ok((Json::Number(key), position, value))
+        };
+        let first = SpillRun::write(&directory, vec![entry(1.0, 0), entry(3.0, 2)]).unwrap();
+        let second = SpillRun::write(&directory, vec![entry(2.0, 1), entry(4.0, 3)]).unwrap();
+        // The last value of the second run is cut short
+        let file = File::options().write(true).open(&second.path).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 2).unwrap();
+
+        let (sender, receiver) = mpsc::channel::<JC>();
+        let sorted = SortedEntries::Runs(vec![first, second], directory.clone());
+        let result = send_sorted(&"sort_by(.)".parse().unwrap(), sorted, 4, &sender);
+        assert!(matches!(result, Err(EvalError::Io(_))), "{:?}", result);
+        assert_eq!(deserialise_json(receiver), json!([{"k": 1}, null, null, null]));
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 0);
+        fs::remove_dir(&directory).unwrap();
+    }
+
+    fn query_error(query: &str, json: &Json) -> EvalError {
+        let (sender, _receiver) = mpsc::channel::<JC>();
+        eval(&query.parse().unwrap(), jc_stream(json), sender).unwrap_err()
+    }
+
+    #[test]
+    fn sorts_are_stable_and_keep_the_array_length() {
+        let json = json!([{"k": 2, "v": "a"}, {"v": "b"}, {"k": 1, "v": "c"}, {"k": 2, "v": "d"}, 5]);
+        let options = EvalOptions::default();
+        assert!(matches!(query_error(r#"sort_by(."k")"#, &json), EvalError::TypeMismatch(_)));
+        let sorted = sort_stream(r#"sort_by(.k?)"#, &options, &json);
+        assert_eq!(sorted, json!([{"v": "b"}, 5, {"k": 1, "v": "c"}, {"k": 2, "v": "a"}, {"k": 2, "v": "d"}]));
+        let grouped = sort_stream(r#"group_by(.k?)"#, &options, &json);
+        assert_eq!(grouped, json!([[{"v": "b"}, 5], [{"k": 1, "v": "c"}], [{"k": 2, "v": "a"}, {"k": 2, "v": "d"}]]));
+        assert_eq!(sort_stream("sort_by(.)", &options, &json!([])), json!([]));
+    }
+}
+
+/**
+ * Evaluates several acessors against the same value, reading the receiver only once. Each result is None when its
+ * acessor reached no value.
+ * When every acessor starts with an ObjectField and the value is an object, each field is only copied to the
+ * acessors that use it. Otherwise the whole value is copied to every acessor. Either way each copy only has the parts
+ * in the projection of the acessor it is copied to
+ */
+fn eval_all(acessors: &[&Acessor], options: &EvalOptions, receiver: mpsc::Receiver<JC>) -> Result<Vec<Option<mpsc::Receiver<JC>>>, EvalError> {
+    let mut results: Vec<Option<mpsc::Receiver<JC>>> = acessors.iter().map(|_| None).collect();
+    let mut result = Ok(());
+    let first_packet = receiver.recv().unwrap();
+    let all_fields = acessors.iter().all(|acessor| matches!(acessor, Acessor::ObjectField(_, _)));
+    match first_packet {
+        JC::ObjectStart if all_fields => {
+            match receiver.recv().unwrap() {
+                JC::ArrayLen(maplen) => {
+                    for _ in 0..maplen {
+                        match receiver.recv().unwrap() {
+                            JC::String(obj_label) => {
+                                match receiver.recv().unwrap() {
+                                    JC::Stream(value_stream) => {
+                                        let targets: Vec<(usize, &Acessor)> = acessors.iter().enumerate()
+                                            .filter_map(|(i, acessor)| match acessor {
+                                                Acessor::ObjectField(label, next_acessor) if *label == obj_label => Some((i, &**next_acessor)),
+                                                _ => None,
+                                            })
+                                            .collect();
+                                        if targets.is_empty() || result.is_err() {
+                                            consume_value(value_stream);
+                                            continue;
+                                        }
+                                        // The copies are buffered, so the ones left after an error can be dropped
+                                        let projections: Vec<Projection> = targets.iter()
+                                            .map(|(_, next_acessor)| next_acessor.projection(Projection::All))
+                                            .collect();
+                                        let copies = tee_projected(value_stream, &projections);
+                                        for ((i, next_acessor), copy) in targets.into_iter().zip(copies) {
+                                            let (result_sender, result_receiver) = mpsc::channel::<JC>();
+                                            result = eval_with_options(next_acessor, options, copy, result_sender);
+                                            if result.is_err() {
+                                                break;
+                                            }
+                                            results[i] = produced_value(result_receiver);
+                                        }
+                                    },
+                                    JC::Skipped => (), // Not needed by any of the acessors
+                                    _ => panic!("Expected Object Stream (Eval Construct)")
+                                }
+                            },
+                            _ => panic!("Expected Object Label (Eval Construct)")
+                        }
+                    }
+                    match receiver.recv().unwrap() {
+                        JC::ObjectEnd => (),
+                        _ => panic!("Expected Object End (Eval Construct)")
+                    }
+                },
+                _ => panic!("Expected ArrayLen (Eval Construct)")
+            }
+        },
+        first_packet => {
+            let projections: Vec<Projection> = acessors.iter().map(|acessor| acessor.projection(Projection::All)).collect();
+            let copies = tee_projected_from(first_packet, receiver, &projections);
+            for (i, copy) in copies.into_iter().enumerate() {
+                let (result_sender, result_receiver) = mpsc::channel::<JC>();
+                eval_with_options(acessors[i], options, copy, result_sender)?;
+                results[i] = produced_value(result_receiver);
+            }
+        }
+    }
+    result.map(|_| results)
+}
+
+/**
+ * Checks if an evaluation that already finished sent a value, returning a receiver with the whole value if it did
+ */
+fn produced_value(receiver: mpsc::Receiver<JC>) -> Option<mpsc::Receiver<JC>> {
+    let first_packet = receiver.try_recv().ok()?;
+    let (sender, value_receiver) = mpsc::channel::<JC>();
+    sender.send(first_packet).unwrap();
+    for packet in receiver.try_iter() {
+        sender.send(packet).unwrap();
+    }
+    Some(value_receiver)
+}
+
+/**
+ * Receiver with the value whose first packet was already received, for an evaluation that is still running in
+ * another thread. Only the top of the value goes through the new channel, the channels of its array or object values
+ * are passed along as they are, so the packets are forwarded by a thread as they arrive instead of being waited for
+ */
+fn resume_value(first_packet: JC, receiver: mpsc::Receiver<JC>) -> mpsc::Receiver<JC> {
+    let (sender, value_receiver) = mpsc::channel::<JC>();
+    sender.send(first_packet).unwrap();
+    thread::spawn(move || {
+        for packet in receiver.iter() {
+            if sender.send(packet).is_err() {
+                break; // the value is no longer read
+            }
+        }
+    });
+    value_receiver
+}
+
+fn null_stream() -> mpsc::Receiver<JC> {
+    let (sender, receiver) = mpsc::channel::<JC>();
+    sender.send(JC::Null).unwrap();
+    receiver
+}
+
+/**
+ * Visitor that sends the value it walks through a JC channel, following the protocols described above serialise_json
+ */
+struct JcWriter {
+    root: Option<mpsc::Sender<JC>>,   // Sender for the walked value, taken when the value starts
+    containers: Vec<mpsc::Sender<JC>>, // Senders of the arrays and objects being sent
+    forward_streams: bool,             // Whether the channels of the values are passed along without reading them
+}
+
+impl JcWriter {
+    fn new(sender: mpsc::Sender<JC>) -> Self {
+        JcWriter { root: Some(sender), containers: vec![], forward_streams: false }
+    }
+
+    /**
+     * Writer that only re-sends the top of the walked value, the channels of its array or object values are sent
+     * as they are
+     */
+    fn forwarding(sender: mpsc::Sender<JC>) -> Self {
+        JcWriter { root: Some(sender), containers: vec![], forward_streams: true }
+    }
+
+    /**
+     * Sender for a value that is starting, which gets its own channel when it is inside an array or object
+     */
+    fn value_sender(&mut self) -> mpsc::Sender<JC> {
+        match self.containers.last() {
+            Some(container_sender) => {
+                let (value_sender, value_receiver) = mpsc::channel::<JC>();
+                container_sender.send(JC::Stream(value_receiver)).unwrap();
+                value_sender
+            },
+            None => self.root.take().expect("Only one value can be sent (Jc Writer)"),
+        }
+    }
+}
+
+impl JcVisitor for JcWriter {
+    fn on_scalar(&mut self, value: Json) {
+        serialise_json(&value, self.value_sender());
+    }
+
+    fn enter_array(&mut self, len: usize) {
+        let value_sender = self.value_sender();
+        value_sender.send(JC::ArrayStart).unwrap();
+        value_sender.send(JC::ArrayLen(len)).unwrap();
+        self.containers.push(value_sender);
+    }
+
+    fn exit_array(&mut self) {
+        self.containers.pop().unwrap().send(JC::ArrayEnd).unwrap();
+    }
+
+    fn enter_object(&mut self, len: usize) {
+        let value_sender = self.value_sender();
+        value_sender.send(JC::ObjectStart).unwrap();
+        value_sender.send(JC::ArrayLen(len)).unwrap();
+        self.containers.push(value_sender);
+    }
+
+    fn on_key(&mut self, key: String) {
+        self.containers.last().unwrap().send(JC::String(key)).unwrap();
+    }
+
+    fn exit_object(&mut self) {
+        self.containers.pop().unwrap().send(JC::ObjectEnd).unwrap();
+    }
+
+    fn on_skipped(&mut self) {
+        self.containers.last().expect("Skipped values are only sent inside arrays and objects (Jc Writer)").send(JC::Skipped).unwrap();
+    }
+
+    fn on_stream(&mut self, value_receiver: mpsc::Receiver<JC>) {
+        match self.containers.last() {
+            Some(container_sender) if self.forward_streams => container_sender.send(JC::Stream(value_receiver)).unwrap(),
+            _ => walk_jc(value_receiver, self),
+        }
+    }
+}
+
+/**
+ * Forwards every event to both visitors
+ */
+impl<A: JcVisitor, B: JcVisitor> JcVisitor for (A, B) {
+    fn on_scalar(&mut self, value: Json) {
+        self.0.on_scalar(value.clone());
+        self.1.on_scalar(value);
+    }
+
+    fn enter_array(&mut self, len: usize) {
+        self.0.enter_array(len);
+        self.1.enter_array(len);
+    }
+
+    fn exit_array(&mut self) {
+        self.0.exit_array();
+        self.1.exit_array();
+    }
+
+    fn enter_object(&mut self, len: usize) {
+        self.0.enter_object(len);
+        self.1.enter_object(len);
+    }
+
+    fn on_key(&mut self, key: String) {
+        self.0.on_key(key.clone());
+        self.1.on_key(key);
+    }
+
+    fn exit_object(&mut self) {
+        self.0.exit_object();
+        self.1.exit_object();
+    }
+
+    fn on_skipped(&mut self) {
+        self.0.on_skipped();
+        self.1.on_skipped();
+    }
+}
+
+/**
+ * Forwards every event to all the visitors
+ */
+impl<V: JcVisitor> JcVisitor for [V] {
+    fn on_scalar(&mut self, value: Json) {
+        for visitor in self.iter_mut() {
+            visitor.on_scalar(value.clone());
+        }
+    }
+
+    fn enter_array(&mut self, len: usize) {
+        for visitor in self.iter_mut() {
+            visitor.enter_array(len);
+        }
+    }
+
+    fn exit_array(&mut self) {
+        for visitor in self.iter_mut() {
+            visitor.exit_array();
+        }
+    }
+
+    fn enter_object(&mut self, len: usize) {
+        for visitor in self.iter_mut() {
+            visitor.enter_object(len);
+        }
+    }
+
+    fn on_key(&mut self, key: String) {
+        for visitor in self.iter_mut() {
+            visitor.on_key(key.clone());
+        }
+    }
+
+    fn exit_object(&mut self) {
+        for visitor in self.iter_mut() {
+            visitor.exit_object();
+        }
+    }
+
+    fn on_skipped(&mut self) {
+        for visitor in self.iter_mut() {
+            visitor.on_skipped();
+        }
+    }
+}
+
+/**
+ * Copies the value streamed through the receiver into a new stream for each projection. Each copy only has the parts
+ * of the value in its projection, with the array and object values that are not needed sent as Skipped, like
+ * serialise_json_with_projection does. The copies are buffered in their channels, so they can be read one after the
+ * other in the same thread
+ */
+fn tee_projected(receiver: mpsc::Receiver<JC>, projections: &[Projection]) -> Vec<mpsc::Receiver<JC>> {
+    let first_packet = receiver.recv().unwrap();
+    tee_projected_from(first_packet, receiver, projections)
+}
+
+fn tee_projected_from(first_packet: JC, receiver: mpsc::Receiver<JC>, projections: &[Projection]) -> Vec<mpsc::Receiver<JC>> {
+    let (mut writers, receivers): (Vec<ProjectedWriter>, Vec<mpsc::Receiver<JC>>) = projections.iter()
+        .map(|projection| {
+            let (sender, receiver) = mpsc::channel::<JC>();
+            (ProjectedWriter::new(sender, projection), receiver)
+        })
+        .unzip();
+    walk_jc_from(first_packet, receiver, &mut writers[..]);
+    receivers
+}
+
+static WHOLE_VALUE: Projection = Projection::All;
+
+/**
+ * Visitor that sends the parts of the walked value in a projection, see serialise_projected
+ */
+struct ProjectedWriter<'p> {
+    writer: JcWriter,
+    root: Option<&'p Projection>, // Projection of the walked value, taken when the value starts
+    frames: Vec<ProjectedFrame<'p>>,
+    skipping: usize, // arrays and objects entered and not exited yet inside a value that is skipped
+}
+
+struct ProjectedFrame<'p> {
+    projection: &'p Projection, // projection of the values, when the array or object is not sent whole
+    whole: bool,
+    index: usize,
+    key: Option<String>,
+}
+
+impl<'p> ProjectedWriter<'p> {
+    fn new(sender: mpsc::Sender<JC>, projection: &'p Projection) -> Self {
+        ProjectedWriter { writer: JcWriter::new(sender), root: Some(projection), frames: vec![], skipping: 0 }
+    }
+
+    /**
+     * Projection of a value that is starting, or None when it is skipped
+     */
+    fn value_projection(&mut self) -> Option<&'p Projection> {
+        let frame = match self.frames.last_mut() {
+            Some(frame) => frame,
+            None => return Some(self.root.take().expect("Only one value can be sent (Projected Writer)")),
+        };
+        let index = frame.index;
+        frame.index += 1;
+        match (frame.whole, frame.projection) {
+            (true, _) => Some(&WHOLE_VALUE),
+            (false, Projection::Entries(entries)) => entries.get(&index),
+            (false, Projection::Each(each)) => Some(&**each),
+            (false, Projection::Fields(fields)) => fields.get(frame.key.as_ref().expect("Expected a key for a object field (Projected Writer)")),
+            _ => None,
+        }
+    }
+
+    fn enter(&mut self, is_object: bool, len: usize) {
+        if self.skipping > 0 {
+            self.skipping += 1;
+            return;
+        }
+        let projection = match self.value_projection() {
+            Some(projection) => projection,
+            None => {
+                self.writer.on_skipped();
+                self.skipping = 1;
+                return;
+            }
+        };
+        // Values of another type than the projection expects are sent whole
+        let whole = !matches!((is_object, projection),
+            (false, Projection::Nothing) | (false, Projection::Entries(_)) | (false, Projection::Each(_))
+                | (true, Projection::Nothing) | (true, Projection::Fields(_)));
+        if is_object { self.writer.enter_object(len) } else { self.writer.enter_array(len) }
+        self.frames.push(ProjectedFrame { projection, whole, index: 0, key: None });
+    }
+
+    fn exit(&mut self, is_object: bool) {
+        if self.skipping > 0 {
+            self.skipping -= 1;
+            return;
+        }
+        self.frames.pop();
+        if is_object { self.writer.exit_object() } else { self.writer.exit_array() }
+    }
+}
+
+impl<'p> JcVisitor for ProjectedWriter<'p> {
+    fn on_scalar(&mut self, value: Json) {
+        if self.skipping > 0 {
+            return;
+        }
+        match self.value_projection() {
+            Some(_) => self.writer.on_scalar(value),
+            None => self.writer.on_skipped(),
+        }
+    }
+
+    fn enter_array(&mut self, len: usize) {
+        self.enter(false, len);
+    }
+
+    fn exit_array(&mut self) {
+        self.exit(false);
+    }
+
+    fn enter_object(&mut self, len: usize) {
+        self.enter(true, len);
+    }
+
+    fn on_key(&mut self, key: String) {
+        if self.skipping > 0 {
+            return;
+        }
+        self.writer.on_key(key.to_string());
+        if let Some(frame) = self.frames.last_mut() {
+            frame.key = Some(key);
+        }
+    }
+
+    fn exit_object(&mut self) {
+        self.exit(true);
+    }
+
+    fn on_skipped(&mut self) {
+        if self.skipping > 0 {
+            return;
+        }
+        self.value_projection();
+        self.writer.on_skipped();
+    }
+}
+
+#[cfg(test)]
+mod tee_tests {
+    use super::*;
+
+    #[test]
+    fn projected_copies_skip_the_values_that_are_not_read() {
+        let json = json!({"a": [1, {"x": 2}], "b": {"c": 1}, "d": [[1], [2]]});
+        let acessor: Acessor = r#"."a"[1]."x" // ."d""#.parse().unwrap();
+        let projections = [acessor.projection(Projection::All), Projection::All];
+        let mut copies = tee_projected(jc_stream(&json), &projections).into_iter();
+        match ValueTop::read(copies.next().unwrap()) {
+            ValueTop::Object(fields) => {
+                let skipped: Vec<(String, bool)> = fields.iter().map(|(key, value)| (key.to_string(), matches!(value, JC::Skipped))).collect();
+                assert_eq!(skipped, vec![("a".to_string(), false), ("b".to_string(), true), ("d".to_string(), false)]);
+                fields.into_iter().for_each(|(_, value)| consume_packet(value));
+            },
+            _ => panic!("Expected an object"),
+        }
+        assert_eq!(deserialise_json(copies.next().unwrap()), json);
+        assert_eq!(acessor.apply(&json).unwrap().into_owned(), json!(2));
+    }
+
+    #[test]
+    fn eval_all_gives_each_acessor_its_projected_copy() {
+        let json = json!({"a": [1, {"x": 2}], "b": {"c": 1}, "d": [[1], [2]]});
+        // The first list only has fields, so each field is copied to the acessors that use it
+        for queries in [&[r#"."a"[1]."x""#, r#"."a"[0]"#, r#"."d"[1]"#, r#"."e""#][..], &[r#"."a"[1]."x""#, r#"."d" | length"#, "keys", r#"."b"."c""#]] {
+            let acessors: Vec<Acessor> = queries.iter().map(|query| query.parse().unwrap()).collect();
+            let acessor_refs: Vec<&Acessor> = acessors.iter().collect();
+            let results = eval_all(&acessor_refs, &EvalOptions::default(), jc_stream(&json)).unwrap();
+            for (acessor, result) in acessors.iter().zip(results) {
+                assert_eq!(result.map(deserialise_json), acessor.apply(&json).ok().map(Cow::into_owned), "{}", acessor);
+            }
+        }
+    }
+}
+
+/**
+ * Reasons why applying an acessor does not produce a value
+ */
+#[derive(Debug, Clone, PartialEq)]
+enum EvalError {
+    TypeMismatch(String),    // The acessor cannot be applied to the value, e.g. ObjectField on an array
+    NoValue,                 // The acessor did not reach any value, e.g. a missing field or an index out of bounds
+    InvalidArgument(String), // A function or operator cannot use the value, e.g. a division by zero or an invalid regex
+    UnknownFunction(String), // The FunctionRegistry has no function with the name of a Call
+    UnboundVariable(String), // No value is bound to the variable of a Var
+    Io(String),              // A file could not be written or read, e.g. a run of a sort that went over the memory budget
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::TypeMismatch(message) => write!(f, "{}", message),
+            EvalError::NoValue => write!(f, "the acessor did not reach any value"),
+            EvalError::InvalidArgument(message) => write!(f, "{}", message),
+            EvalError::UnknownFunction(name) => write!(f, "unknown function {}", name),
+            EvalError::UnboundVariable(name) => write!(f, "${} is not bound", name),
+            EvalError::Io(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Acessor {
+    /**
+     * In memory version of eval, for values that are already deserialised and small enough that starting the
+     * serialiser, eval and deserialiser threads costs more than the lookup.
+     * Gives the same results as deserialise_json over the output of eval: when eval sends nothing (the
+     * deserialiser would be left waiting) the result is NoValue, and when eval fails it is the same error.
+     * The result is borrowed from the json, unless a Map acessor had to build a new array
+     */
+    fn apply<'a>(&self, json: &'a Json) -> Result<Cow<'a, Json>, EvalError> {
+        self.apply_with(json, &EvalOptions::default())
+    }
+
+    /**
+     * Same as apply, using the functions and variables of the options
+     */
+    fn apply_with<'a>(&self, json: &'a Json, options: &EvalOptions) -> Result<Cow<'a, Json>, EvalError> {
+        match self {
+            Acessor::ObjectField(label, next_acessor) => match json {
+                Json::Object(map) => next_acessor.apply_with(map.get(label).ok_or(EvalError::NoValue)?, options),
+                _ => Err(EvalError::TypeMismatch(format!("Cannot apply ObjectField Acessor to {}", json_type_name(json)))),
+            },
+            Acessor::ArrayEntry(index, next_acessor) => match json {
+                Json::Array(array) => next_acessor.apply_with(array.get(*index).ok_or(EvalError::NoValue)?, options),
+                _ => Err(EvalError::TypeMismatch(format!("Cannot apply Index accessor to {}", json_type_name(json)))),
+            },
+            Acessor::Map(next_acessor) => match json {
+                Json::Array(array) => {
+                    let values = array.iter()
+                        .map(|value| or_null(next_acessor.apply_with(value, options)))
+                        .collect::<Result<Vec<Json>, EvalError>>()?;
+                    Ok(Cow::Owned(Json::Array(values)))
+                },
+                _ => Err(EvalError::TypeMismatch(format!("Cannot apply Map to {}", json_type_name(json)))),
+            },
+            Acessor::End => Ok(Cow::Borrowed(json)),
+            Acessor::Construct(fields) => {
+                let mut map = BTreeMap::new();
+                for (key, field_acessor) in fields {
+                    map.insert(key.to_string(), or_null(field_acessor.apply_with(json, options))?);
+                }
+                Ok(Cow::Owned(Json::Object(map)))
+            },
+            Acessor::ConstructArray(values) => {
+                let array = values.iter()
+                    .map(|value_acessor| or_null(value_acessor.apply_with(json, options)))
+                    .collect::<Result<Vec<Json>, EvalError>>()?;
+                Ok(Cow::Owned(Json::Array(array)))
+            },
+            Acessor::Pipe(first, second) => match first.apply_with(json, options)? {
+                Cow::Borrowed(first_result) => second.apply_with(first_result, options),
+                Cow::Owned(first_result) => Ok(Cow::Owned(second.apply_with(&first_result, options)?.into_owned())),
+            },
+            Acessor::SortBy(key_acessor) | Acessor::UniqueBy(key_acessor) | Acessor::GroupBy(key_acessor) => {
+                let array = match json {
+                    Json::Array(array) => array,
+                    _ => return Err(EvalError::TypeMismatch(format!("Cannot apply {} to {}", self, json_type_name(json)))),
+                };
+                let mut entries = array.iter()
+                    .map(|value| Ok((or_null(key_acessor.apply_with(value, options))?, value)))
+                    .collect::<Result<Vec<(Json, &Json)>, EvalError>>()?;
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b)); // stable, so equal keys keep their order
+                let mut groups: Vec<(Json, Vec<Json>)> = vec![];
+                for (key, value) in entries {
+                    match groups.last_mut() {
+                        Some((group_key, group)) if *group_key == key => group.push(value.clone()),
+                        _ => groups.push((key, vec![value.clone()])),
+                    }
+                }
+                Ok(Cow::Owned(Json::Array(match self {
+                    Acessor::SortBy(_) => groups.into_iter().flat_map(|(_, group)| group).collect(),
+                    Acessor::UniqueBy(_) => groups.into_iter().map(|(_, group)| group.into_iter().next().unwrap()).collect(),
+                    _ => groups.into_iter().map(|(_, group)| Json::Array(group)).collect(),
+                })))
+            },
+            Acessor::Optional(optional_acessor) => match optional_acessor.apply_with(json, options) {
+                Err(EvalError::TypeMismatch(_)) | Err(EvalError::InvalidArgument(_)) => Err(EvalError::NoValue),
+                result => result,
+            },
+            Acessor::Alternative(first, second) => match first.apply_with(json, options) {
+                Ok(ref value) if value.is_null() => second.apply_with(json, options),
+                Err(EvalError::NoValue) => second.apply_with(json, options),
+                result => result,
+            },
+            Acessor::If(condition, then_acessor, else_acessor) => match *condition.apply_with(json, options)? {
+                Json::Null | Json::Boolean(false) => else_acessor.apply_with(json, options),
+                _ => then_acessor.apply_with(json, options),
+            },
+            Acessor::Literal(value) => Ok(Cow::Owned(value.clone())),
+            Acessor::Call(name, arguments) => {
+                let function = options.functions.get(name)?;
+                let mut argument_values = Vec::with_capacity(arguments.len());
+                for argument in arguments {
+                    argument_values.push(argument.apply_with(json, options)?.into_owned());
+                }
+                function(json, &argument_values).map(Cow::Owned)
+            },
+            Acessor::Binary(operator, left, right) => {
+                let left_value = left.apply_with(json, options)?;
+                match operator {
+                    // The second acessor is not applied when the first value decides the result
+                    BinaryOperator::And if !left_value.is_truthy() => Ok(Cow::Owned(Json::Boolean(false))),
+                    BinaryOperator::Or if left_value.is_truthy() => Ok(Cow::Owned(Json::Boolean(true))),
+                    _ => operator.apply(&left_value, &*right.apply_with(json, options)?).map(Cow::Owned),
+                }
+            },
+            Acessor::Var(name) => options.bindings.get(name).cloned().map(Cow::Owned).ok_or_else(|| EvalError::UnboundVariable(name.to_string())),
+            Acessor::Bind(source, name, body) => {
+                let value = source.apply_with(json, options)?.into_owned();
+                body.apply_with(json, &options.with_binding(name, value))
+            },
+            _ => self.apply_aggregate(json).map(Cow::Owned),
+        }
+    }
+
+    fn apply_aggregate(&self, json: &Json) -> Result<Json, EvalError> {
+        let values: Vec<&Json> = match (json, self) {
+            (Json::Array(array), _) => array.iter().collect(),
+            (Json::Object(map), _) => map.values().collect(),
+            (Json::String(str), Acessor::Length) => return Ok(Json::Number(str.chars().count() as f64)),
+            (Json::Null, Acessor::Length) => return Ok(Json::Number(0.0)),
+            _ => return Err(EvalError::TypeMismatch(format!("Cannot apply {} to {}", self, json_type_name(json)))),
+        };
+        Ok(match self {
+            Acessor::Length => Json::Number(values.len() as f64),
+            Acessor::Keys => match json {
+                Json::Object(map) => Json::Array(map.keys().map(|key| Json::String(key.to_string())).collect()),
+                _ => Json::Array((0..values.len()).map(|i| Json::Number(i as f64)).collect()),
+            },
+            Acessor::Values => Json::Array(values.into_iter().cloned().collect()),
+            Acessor::Sum => {
+                let mut sum = 0.0;
+                for value in values {
+                    match value {
+                        Json::Number(num) => sum += num,
+                        Json::Null => (),
+                        _ => return Err(EvalError::TypeMismatch("Sum can only add numbers".to_string())),
+                    }
+                }
+                Json::Number(sum)
+            },
+            Acessor::Min => values.into_iter().min().cloned().unwrap_or(Json::Null),
+            Acessor::Max => values.into_iter().max().cloned().unwrap_or(Json::Null),
+            Acessor::Count => Json::Number(values.iter().filter(|value| !value.is_null()).count() as f64),
+            Acessor::Any => Json::Boolean(values.iter().any(|value| value.is_truthy())),
+            Acessor::All => Json::Boolean(values.iter().all(|value| value.is_truthy())),
+            _ => return Err(EvalError::TypeMismatch(format!("{} is not an aggregate acessor", self))),
+        })
+    }
+}
+
+/**
+ * Results of Construct and Map acessors are null when the acessor reached no value
+ */
+fn or_null(result: Result<Cow<'_, Json>, EvalError>) -> Result<Json, EvalError> {
+    match result {
+        Ok(value) => Ok(value.into_owned()),
+        Err(EvalError::NoValue) => Ok(Json::Null),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod apply_tests {
+    use super::*;
+
+    pub(super) fn random_acessor(rng: &mut TestRng, depth: u32) -> Acessor {
+        let next = |rng: &mut TestRng| Box::new(random_acessor(rng, depth - 1));
+        if depth == 0 {
+            return Acessor::End;
+        }
+        match rng.below(17) {
+            0 => Acessor::End,
+            1 => Acessor::ObjectField(format!("k{}", rng.below(4)), next(rng)),
+            2 => Acessor::ArrayEntry(rng.below(3) as usize, next(rng)),
+            3 => Acessor::Map(next(rng)),
+            4 => Acessor::Construct((0..rng.below(3)).map(|i| (format!("f{}", i), *next(rng))).collect()),
+            5 => Acessor::ConstructArray((0..rng.below(3)).map(|_| *next(rng)).collect()),
+            6 => Acessor::Pipe(next(rng), next(rng)),
+            7 => [Acessor::Length, Acessor::Keys, Acessor::Values, Acessor::Sum, Acessor::Min, Acessor::Max, Acessor::Count, Acessor::Any, Acessor::All]
+                .into_iter().nth(rng.below(9) as usize).unwrap(),
+            8 => [Acessor::SortBy, Acessor::UniqueBy, Acessor::GroupBy][rng.below(3) as usize](next(rng)),
+            9 => Acessor::Optional(next(rng)),
+            10 => Acessor::Alternative(next(rng), next(rng)),
+            11 => Acessor::If(next(rng), next(rng), next(rng)),
+            12 => Acessor::Literal(rng.json(1)),
+            13 => {
+                let (name, arguments) = match rng.below(6) {
+                    0 => ("to_string", vec![]),
+                    1 => ("ascii_upcase", vec![]),
+                    2 => ("split", vec![Acessor::Literal(json!("s"))]),
+                    3 => ("test", vec![Acessor::Literal(json!("s[0-2]"))]),
+                    4 => ("substring", vec![Acessor::Literal(json!(1)), *next(rng)]),
+                    _ => ("not", vec![]),
+                };
+                Acessor::Call(name.to_string(), arguments)
+            },
+            14 => {
+                let operators = [BinaryOperator::Add, BinaryOperator::Subtract, BinaryOperator::Multiply, BinaryOperator::Divide, BinaryOperator::Modulo,
+                                 BinaryOperator::Equal, BinaryOperator::NotEqual, BinaryOperator::Less, BinaryOperator::GreaterEqual, BinaryOperator::And, BinaryOperator::Or];
+                Acessor::Binary(operators[rng.below(operators.len() as u64) as usize], next(rng), next(rng))
+            },
+            15 => Acessor::Var(["x", "y"][rng.below(2) as usize].to_string()),
+            _ => Acessor::Bind(next(rng), ["x", "y"][rng.below(2) as usize].to_string(), next(rng)),
+        }
+    }
+
+    /**
+     * What deserialise_json gives for the output of eval, or NoValue when eval sent nothing
+     */
+    fn eval_json(acessor: &Acessor, options: &EvalOptions, json: &Json) -> Result<Json, EvalError> {
+        let (sender, receiver) = mpsc::channel();
+        eval_with_options(acessor, options, jc_stream(json), sender)?;
+        match receiver.try_recv() {
+            Ok(first_packet) => {
+                let (value_sender, value_receiver) = mpsc::channel();
+                value_sender.send(first_packet).unwrap();
+                receiver.try_iter().for_each(|packet| value_sender.send(packet).unwrap());
+                Ok(deserialise_json(value_receiver))
+            },
+            Err(_) => Err(EvalError::NoValue),
+        }
+    }
+
+    #[test]
+    fn apply_agrees_with_eval() {
+        let mut rng = TestRng(0x9E37_79B9_7F4A_7C15);
+        let mut options = EvalOptions::default();
+        options.bindings.insert(String::from("x"), json!([1, "s1"]));
+        let (mut values, mut no_values, mut errors) = (0, 0, 0);
+        for _ in 0..3000 {
+            let (json, acessor) = (rng.json(4), random_acessor(&mut rng, 4));
+            options.memory_budget = if rng.below(2) == 0 { 64 } else { 1 << 20 }; // spill sorts now and then
+            let applied = acessor.apply_with(&json, &options).map(Cow::into_owned);
+            let evaluated = eval_json(&acessor, &options, &json);
+            match (&applied, &evaluated) {
+                (Ok(a), Ok(e)) => {
+                    assert_eq!(a, e, "{} on {}", acessor, json);
+                    values += 1;
+                },
+                (Err(EvalError::NoValue), Err(EvalError::NoValue)) => no_values += 1,
+                (Err(a), Err(e)) if std::mem::discriminant(a) == std::mem::discriminant(e) => errors += 1,
+                _ => panic!("{} on {}: apply gives {:?} and eval {:?}", acessor, json, applied, evaluated),
+            }
+        }
+        // Every kind of result is covered
+        assert!(values > 500 && no_values > 50 && errors > 50, "{} values, {} without value, {} errors", values, no_values, errors);
+    }
+
+    #[test]
+    fn map_results_keep_the_length_of_the_array() {
+        let json = json!([{"a": 1}, {"b": 2}, {"a": 3}]);
+        let acessor: Acessor = r#"[]."a""#.parse().unwrap();
+        assert_eq!(acessor.apply(&json).unwrap().into_owned(), json!([1, null, 3]));
+        assert_eq!(eval_json(&acessor, &EvalOptions::default(), &json), Ok(json!([1, null, 3])));
+        let mismatch: Acessor = r#"[]."a"[0]"#.parse().unwrap();
+        assert!(matches!(mismatch.apply(&json), Err(EvalError::TypeMismatch(_))));
+        assert!(matches!(eval_json(&mismatch, &EvalOptions::default(), &json), Err(EvalError::TypeMismatch(_))));
+    }
+}
+
+
+impl Acessor {
+    /**
+     * Whether the acessor only follows object fields, array entries and maps until the End
+     */
+    fn is_path(&self) -> bool {
+        match self {
+            Acessor::ObjectField(_, next_acessor) | Acessor::ArrayEntry(_, next_acessor) | Acessor::Map(next_acessor) => next_acessor.is_path(),
+            Acessor::End => true,
+            _ => false,
+        }
+    }
+
+    /**
+     * Whether the acessor is displayed with a | outside of any brackets, like a Pipe or a path followed by a Construct
+     */
+    fn is_written_as_pipe(&self) -> bool {
+        match self {
+            Acessor::Pipe(_, _) | Acessor::Bind(_, _, _) => true,
+            Acessor::ObjectField(_, next_acessor) | Acessor::ArrayEntry(_, next_acessor) => match **next_acessor {
+                Acessor::End => false,
+                Acessor::ObjectField(_, _) | Acessor::ArrayEntry(_, _) => next_acessor.is_written_as_pipe(),
+                Acessor::Map(ref map_acessor) => !map_acessor.is_path(),
+                _ => true,
+            },
+            _ => false,
+        }
+    }
+
+    /**
+     * Whether the acessor is written with a Bind at the end, outside of any brackets
+     */
+    fn ends_with_bind(&self) -> bool {
+        match self {
+            Acessor::Bind(_, _, _) => true,
+            Acessor::Pipe(_, next_acessor) | Acessor::ObjectField(_, next_acessor) | Acessor::ArrayEntry(_, next_acessor) => next_acessor.ends_with_bind(),
+            _ => false,
+        }
+    }
+
+    /**
+     * How tightly the acessor binds when written as an operand: 0 for pipes, 1 for //, the precedence of the
+     * operator for Binary and TERM_PRECEDENCE for the rest
+     */
+    fn precedence(&self) -> u8 {
+        match self {
+            _ if self.is_written_as_pipe() => 0,
+            Acessor::Alternative(_, _) => 1,
+            Acessor::Binary(operator, _, _) => operator.precedence(),
+            _ => TERM_PRECEDENCE,
+        }
+    }
+
+    /**
+     * Appends other at the End of this acessor, so it is applied to every value this acessor reaches.
+     * Note that after a Map this applies other to each array value, unlike a Pipeline stage which would receive
+     * the whole array built by the Map
+     */
+    fn then(self, other: Acessor) -> Acessor {
+        if let Acessor::End = other {
+            return self;
+        }
+        match self {
+            Acessor::ObjectField(label, next_acessor) => Acessor::ObjectField(label, Box::new(next_acessor.then(other))),
+            Acessor::ArrayEntry(index, next_acessor) => Acessor::ArrayEntry(index, Box::new(next_acessor.then(other))),
+            Acessor::Map(next_acessor) => Acessor::Map(Box::new(next_acessor.then(other))),
+            Acessor::End => other,
+            Acessor::Pipe(first, second) => Acessor::Pipe(first, Box::new(second.then(other))),
+            // The others have no End to append to, so other is applied to their result
+            _ => Acessor::Pipe(Box::new(self), Box::new(other)),
+        }
+    }
+}
+
+/**
+ * Error from parsing the text of a query, with the position (in characters) where it was found
+ */
+#[derive(Debug, Clone, PartialEq)]
+struct ParseError {
+    position: usize,
+    message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+/**
+ * Parses queries written the way acessors are displayed, e.g. ."socialProfiles"[]."name" or
+ * { name: ."name", city: ."address"."city" }
+ * Fields can also be written without quotes (.address.city), { name } is short for { name: ."name" },
+ * a | b applies b to the result of a, and map(a) applies a to each value of an array.
+ * Literals are written like json, and a // b, a? and if a then b else c end are Alternative, Optional and If.
+ * Other names are function calls, e.g. ascii_downcase or substring(0; 3), and ."age" + 1 or .a == .b and .c are Binary.
+ * Variables are written $name, and ."name" as $name | b binds name while applying b
+ */
+impl FromStr for Acessor {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<Acessor, ParseError> {
+        Acessor::parse(text)
+    }
+}
+
+impl Acessor {
+    fn parse(text: &str) -> Result<Acessor, ParseError> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut parser = QueryParser { chars: &chars, position: 0 };
+        let acessor = parser.parse_pipe()?;
+        parser.skip_whitespace();
+        if parser.position != chars.len() {
+            return Err(parser.error("unexpected character"));
+        }
+        Ok(acessor)
+    }
+}
+
+struct QueryParser<'a> {
+    chars: &'a [char],
+    position: usize,
+}
+
+impl<'a> QueryParser<'a> {
+    fn error(&self, message: &str) -> ParseError {
+        ParseError { position: self.position, message: message.to_string() }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.position).is_some_and(|c| c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.position).cloned()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        if self.peek() == Some(expected) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", expected)))
+        }
+    }
+
+    fn parse_pipe(&mut self) -> Result<Acessor, ParseError> {
+        let mut acessor = self.parse_alternative()?;
+        while self.peek() == Some('|') {
+            self.position += 1;
+            acessor = Acessor::Pipe(Box::new(acessor), Box::new(self.parse_alternative()?));
+        }
+        Ok(acessor)
+    }
+
+    fn parse_alternative(&mut self) -> Result<Acessor, ParseError> {
+        let mut acessor = self.parse_binary(2)?;
+        while self.peek() == Some('/') && self.chars.get(self.position + 1) == Some(&'/') {
+            self.position += 2;
+            acessor = Acessor::Alternative(Box::new(acessor), Box::new(self.parse_binary(2)?));
+        }
+        Ok(acessor)
+    }
+
+    /**
+     * Parses the operators with the given precedence or higher, see BinaryOperator::precedence
+     */
+    fn parse_binary(&mut self, precedence: u8) -> Result<Acessor, ParseError> {
+        if precedence == TERM_PRECEDENCE {
+            return self.parse_postfix();
+        }
+        let mut acessor = self.parse_binary(precedence + 1)?;
+        while let Some(operator) = self.peek_operator(precedence) {
+            self.position += operator.symbol().len();
+            acessor = Acessor::Binary(operator, Box::new(acessor), Box::new(self.parse_binary(precedence + 1)?));
+            // Comparisons can not be chained
+            if precedence == 4 {
+                break;
+            }
+        }
+        Ok(acessor)
+    }
+
+    fn peek_operator(&mut self, precedence: u8) -> Option<BinaryOperator> {
+        let operators: &[BinaryOperator] = match precedence {
+            2 => &[BinaryOperator::Or],
+            3 => &[BinaryOperator::And],
+            // <= and >= before < and > so the longest operator is found
+            4 => &[BinaryOperator::Equal, BinaryOperator::NotEqual, BinaryOperator::LessEqual, BinaryOperator::GreaterEqual,
+                BinaryOperator::Less, BinaryOperator::Greater],
+            5 => &[BinaryOperator::Add, BinaryOperator::Subtract],
+            _ => &[BinaryOperator::Multiply, BinaryOperator::Divide, BinaryOperator::Modulo],
+        };
+        self.peek();
+        for &operator in operators {
+            let symbol = operator.symbol();
+            let end = self.position + symbol.len();
+            let found = match operator {
+                BinaryOperator::And | BinaryOperator::Or => self.peek_keyword(symbol),
+                // A second / is the // of Alternative
+                BinaryOperator::Divide => self.chars.get(self.position) == Some(&'/') && self.chars.get(end) != Some(&'/'),
+                _ => end <= self.chars.len() && self.chars[self.position..end].iter().cloned().eq(symbol.chars()),
+            };
+            if found {
+                return Some(operator);
+            }
+        }
+        None
+    }
+
+    /**
+     * Parses a term followed by any number of ?. The steps right after a ? are part of the optional path,
+     * so ."address"?."city" reaches nothing when the address is not an object or is a string
+     */
+    fn parse_postfix(&mut self) -> Result<Acessor, ParseError> {
+        let mut acessor = self.parse_term()?;
+        while self.peek() == Some('?') {
+            self.position += 1;
+            let steps = self.parse_steps(vec![])?;
+            acessor = Acessor::Optional(Box::new(acessor.then(steps)));
+        }
+        // a as $x | b binds x for the whole of b
+        if self.peek_keyword("as") {
+            self.position += 2;
+            self.expect('$')?;
+            let name = self.parse_variable_name()?;
+            self.expect('|')?;
+            acessor = Acessor::Bind(Box::new(acessor), name, Box::new(self.parse_pipe()?));
+        }
+        Ok(acessor)
+    }
+
+    fn parse_term(&mut self) -> Result<Acessor, ParseError> {
+        match self.peek() {
+            Some('{') => {
+                self.position += 1;
+                self.parse_construct()
+            },
+            Some('[') => {
+                self.position += 1;
+                match self.parse_bracket_step()? {
+                    Some(step) => self.parse_steps(vec![step]),
+                    None => self.parse_construct_array(),
+                }
+            },
+            Some('.') => {
+                self.position += 1;
+                // A dot on its own is the End acessor
+                match self.parse_dot_step()? {
+                    Some(step) => self.parse_steps(vec![step]),
+                    None => Ok(Acessor::End),
+                }
+            },
+            Some('(') => {
+                self.position += 1;
+                let acessor = self.parse_pipe()?;
+                self.expect(')')?;
+                Ok(acessor)
+            },
+            Some('"') => Ok(Acessor::Literal(Json::String(self.parse_string()?))),
+            Some('$') => {
+                self.position += 1;
+                Ok(Acessor::Var(self.parse_variable_name()?))
+            },
+            Some(c) if c.is_ascii_digit() || c == '-' => Ok(Acessor::Literal(Json::Number(self.parse_number()?))),
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.position;
+                let name = self.parse_identifier();
+                match name.as_str() {
+                    "null" => return Ok(Acessor::Literal(Json::Null)),
+                    "true" => return Ok(Acessor::Literal(Json::Boolean(true))),
+                    "false" => return Ok(Acessor::Literal(Json::Boolean(false))),
+                    "if" => return self.parse_if(),
+                    "then" | "else" | "end" | "and" | "or" | "as" => return Err(ParseError { position: start, message: format!("unexpected {}", name) }),
+                    _ => (),
+                }
+                let takes_acessor = matches!(name.as_str(), "map" | "sort_by" | "unique_by" | "group_by");
+                if self.peek() == Some('(') {
+                    if aggregate_acessor(&name).is_some() {
+                        return Err(ParseError { position: start, message: format!("{} takes no arguments", name) });
+                    }
+                    // Arguments are separated with ; since , separates the values of a ConstructArray
+                    self.position += 1;
+                    let mut arguments = vec![self.parse_pipe()?];
+                    while self.peek() == Some(';') {
+                        self.position += 1;
+                        arguments.push(self.parse_pipe()?);
+                    }
+                    self.expect(')')?;
+                    let wrap: fn(Box<Acessor>) -> Acessor = match name.as_str() {
+                        "map" => Acessor::Map,
+                        "sort_by" => Acessor::SortBy,
+                        "unique_by" => Acessor::UniqueBy,
+                        "group_by" => Acessor::GroupBy,
+                        _ => return Ok(Acessor::Call(name, arguments)),
+                    };
+                    if arguments.len() != 1 {
+                        return Err(ParseError { position: start, message: format!("{} takes one argument", name) });
+                    }
+                    return Ok(wrap(Box::new(arguments.pop().unwrap())));
+                }
+                if takes_acessor {
+                    return Err(ParseError { position: start, message: format!("{} takes one argument", name) });
+                }
+                // Any other name is a Call, checked against the FunctionRegistry by Acessor::compile
+                Ok(aggregate_acessor(&name).unwrap_or_else(|| Acessor::Call(name, vec![])))
+            },
+            _ => Err(self.error("expected '.', '[', '{', '(', a literal or a function name")),
+        }
+    }
+
+    /**
+     * Parses the steps of a path that follow the ones already read. Steps must follow each other without whitespace
+     */
+    fn parse_steps(&mut self, mut steps: Vec<QueryStep>) -> Result<Acessor, ParseError> {
+        loop {
+            match self.chars.get(self.position) {
+                Some('[') => {
+                    self.position += 1;
+                    match self.parse_bracket_step()? {
+                        Some(step) => steps.push(step),
+                        None => return Err(self.error("expected an index or ']'")),
+                    }
+                },
+                Some('.') => {
+                    self.position += 1;
+                    match self.parse_dot_step()? {
+                        Some(step) => steps.push(step),
+                        None => return Err(self.error("expected a field name")),
+                    }
+                },
+                _ => break,
+            }
+        }
+        Ok(steps.into_iter().rev().fold(Acessor::End, |next_acessor, step| match step {
+            QueryStep::Field(label) => Acessor::ObjectField(label, Box::new(next_acessor)),
+            QueryStep::Index(index) => Acessor::ArrayEntry(index, Box::new(next_acessor)),
+            QueryStep::Map => Acessor::Map(Box::new(next_acessor)),
+        }))
+    }
+
+    /**
+     * Parses if condition then a else b end, after the if. Without else the value is passed along unchanged
+     */
+    fn parse_if(&mut self) -> Result<Acessor, ParseError> {
+        let condition = self.parse_pipe()?;
+        self.expect_keyword("then")?;
+        let then_acessor = self.parse_pipe()?;
+        let else_acessor = if self.peek_keyword("else") {
+            self.parse_identifier();
+            self.parse_pipe()?
+        } else {
+            Acessor::End
+        };
+        self.expect_keyword("end")?;
+        Ok(Acessor::If(Box::new(condition), Box::new(then_acessor), Box::new(else_acessor)))
+    }
+
+    fn peek_keyword(&mut self, keyword: &str) -> bool {
+        self.peek();
+        let end = self.position + keyword.len();
+        end <= self.chars.len()
+            && self.chars[self.position..end].iter().cloned().eq(keyword.chars())
+            && !self.chars.get(end).is_some_and(|&c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        if self.peek_keyword(keyword) {
+            self.position += keyword.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {}", keyword)))
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<f64, ParseError> {
+        let start = self.position;
+        let digits = |parser: &mut QueryParser| {
+            while parser.chars.get(parser.position).is_some_and(|c| c.is_ascii_digit()) {
+                parser.position += 1;
+            }
+        };
+        if self.chars.get(self.position) == Some(&'-') {
+            self.position += 1;
+        }
+        digits(self);
+        if self.chars.get(self.position) == Some(&'.') && self.chars.get(self.position + 1).is_some_and(|c| c.is_ascii_digit()) {
+            self.position += 1;
+            digits(self);
+        }
+        if let Some('e') | Some('E') = self.chars.get(self.position) {
+            self.position += 1;
+            if let Some('+') | Some('-') = self.chars.get(self.position) {
+                self.position += 1;
+            }
+            digits(self);
+        }
+        let text: String = self.chars[start..self.position].iter().collect();
+        text.parse::<f64>().map_err(|_| ParseError { position: start, message: "invalid number".to_string() })
+    }
+
+    /**
+     * Parses the step after a dot: a field name, or a bracket as in .[0] and .[]
+     */
+    fn parse_dot_step(&mut self) -> Result<Option<QueryStep>, ParseError> {
+        match self.chars.get(self.position) {
+            Some('"') => Ok(Some(QueryStep::Field(self.parse_string()?))),
+            Some('[') => {
+                self.position += 1;
+                match self.parse_bracket_step()? {
+                    Some(step) => Ok(Some(step)),
+                    None => Err(self.error("expected an index or ']'")),
+                }
+            },
+            Some(&c) if c.is_ascii_alphabetic() || c == '_' => Ok(Some(QueryStep::Field(self.parse_identifier()))),
+            _ => Ok(None),
+        }
+    }
+
+    /**
+     * Parses [index] and [] after the opening bracket. Anything else is the start of a ConstructArray, and gives None.
+     * The index or ] must come right after the bracket, so [ ] and [ 0 ] are arrays built with ConstructArray
+     */
+    fn parse_bracket_step(&mut self) -> Result<Option<QueryStep>, ParseError> {
+        if self.chars.get(self.position) == Some(&']') {
+            self.position += 1;
+            return Ok(Some(QueryStep::Map));
+        }
+        match self.chars.get(self.position).cloned() {
+            Some(c) if c.is_ascii_digit() => {
+                let start = self.position;
+                while self.chars.get(self.position).is_some_and(|c| c.is_ascii_digit()) {
+                    self.position += 1;
+                }
+                let digits: String = self.chars[start..self.position].iter().collect();
+                let index = digits.parse::<usize>().map_err(|_| ParseError { position: start, message: "index is too large".to_string() })?;
+                self.expect(']')?;
+                Ok(Some(QueryStep::Index(index)))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    fn parse_construct(&mut self) -> Result<Acessor, ParseError> {
+        let mut fields = vec![];
+        if self.peek() == Some('}') {
+            self.position += 1;
+            return Ok(Acessor::Construct(fields));
+        }
+        loop {
+            let key = match self.peek() {
+                Some('"') => self.parse_string()?,
+                Some(c) if c.is_ascii_alphabetic() || c == '_' => self.parse_identifier(),
+                _ => return Err(self.error("expected a key")),
+            };
+            if self.peek() == Some(':') {
+                self.position += 1;
+                fields.push((key, self.parse_pipe()?));
+            } else {
+                let field_acessor = Acessor::ObjectField(key.to_string(), Box::new(Acessor::End));
+                fields.push((key, field_acessor));
+            }
+            match self.peek() {
+                Some(',') => self.position += 1,
+                Some('}') => {
+                    self.position += 1;
+                    return Ok(Acessor::Construct(fields));
+                },
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn parse_construct_array(&mut self) -> Result<Acessor, ParseError> {
+        let mut values = vec![];
+        if self.peek() == Some(']') {
+            self.position += 1;
+            return Ok(Acessor::ConstructArray(values));
+        }
+        loop {
+            values.push(self.parse_pipe()?);
+            match self.peek() {
+                Some(',') => self.position += 1,
+                Some(']') => {
+                    self.position += 1;
+                    return Ok(Acessor::ConstructArray(values));
+                },
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    /**
+     * Parses the name of a variable after the $, which must follow it without whitespace
+     */
+    fn parse_variable_name(&mut self) -> Result<String, ParseError> {
+        match self.chars.get(self.position) {
+            Some(&c) if c.is_ascii_alphabetic() || c == '_' => Ok(self.parse_identifier()),
+            _ => Err(self.error("expected a variable name")),
+        }
+    }
+
+    fn parse_identifier(&mut self) -> String {
+        let start = self.position;
+        while self.chars.get(self.position).is_some_and(|&c| c.is_ascii_alphanumeric() || c == '_') {
+            self.position += 1;
+        }
+        self.chars[start..self.position].iter().collect()
+    }
+
+    /**
+     * Parses a json string literal, with the same escapes write_json_string produces
+     */
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            let c = match self.chars.get(self.position) {
+                Some(&c) => c,
+                None => return Err(self.error("unterminated string")),
+            };
+            self.position += 1;
+            match c {
+                '"' => return Ok(string),
+                '\\' => {
+                    let escape = self.chars.get(self.position).cloned().ok_or_else(|| self.error("unterminated string"))?;
+                    self.position += 1;
+                    match escape {
+                        '"' | '\\' | '/' => string.push(escape),
+                        'n' => string.push('\n'),
+                        'r' => string.push('\r'),
+                        't' => string.push('\t'),
+                        'b' => string.push('\u{8}'),
+                        'f' => string.push('\u{c}'),
+                        'u' => {
+                            let hex: String = self.chars.iter().skip(self.position).take(4).collect();
+                            let code = u32::from_str_radix(&hex, 16).ok()
+                                .filter(|_| hex.len() == 4)
+                                .ok_or_else(|| self.error("invalid unicode escape"))?;
+                            self.position += 4;
+                            string.push(std::char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))?);
+                        },
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                },
+                c => string.push(c),
+            }
+        }
+    }
+}
+
+fn aggregate_acessor(name: &str) -> Option<Acessor> {
+    match name {
+        "length" => Some(Acessor::Length),
+        "keys" => Some(Acessor::Keys),
+        "values" => Some(Acessor::Values),
+        "sum" => Some(Acessor::Sum),
+        "min" => Some(Acessor::Min),
+        "max" => Some(Acessor::Max),
+        "count" => Some(Acessor::Count),
+        "any" => Some(Acessor::Any),
+        "all" => Some(Acessor::All),
+        _ => None,
+    }
+}
+
+enum QueryStep {
+    Field(String),
+    Index(usize),
+    Map,
+}
+
+#[cfg(test)]
+mod query_text_tests {
+    use super::*;
+
+    fn round_trip(text: &str) -> String {
+        text.parse::<Acessor>().unwrap_or_else(|error| panic!("{} does not parse: {}", text, error)).to_string()
+    }
+
+    #[test]
+    fn pipes_apply_the_second_acessor_to_the_result_of_the_first() {
+        let json = json!({"a": [{"b": 1}, {"b": 2}], "c": 3});
+        let piped: Acessor = r#"."a" | map(."b") | length"#.parse().unwrap();
+        assert_eq!(piped.apply(&json).unwrap().into_owned(), json!(2));
+        let construct: Acessor = r#"{ "x": ."c" } | ."x""#.parse().unwrap();
+        assert_eq!(construct.apply(&json).unwrap().into_owned(), json!(3));
+        assert_eq!(round_trip(r#"."a" | length"#), round_trip(&round_trip(r#"."a" | length"#)));
+    }
+
+    #[test]
+    fn display_parses_back_to_the_same_acessor() {
+        let mut rng = TestRng(0x2545_F491_4F6C_DD1D);
+        for _ in 0..2000 {
+            let acessor = apply_tests::random_acessor(&mut rng, 4);
+            let text = acessor.to_string();
+            let parsed: Acessor = text.parse().unwrap_or_else(|error| panic!("{} does not parse: {}", text, error));
+            assert_eq!(parsed.to_string(), text);
+            let mut options = EvalOptions::default();
+            options.bindings.insert("x".to_string(), json!([1, "s1"]));
+            options.bindings.insert("y".to_string(), json!({"k0": 2}));
+            for _ in 0..3 {
+                let json = rng.json(3);
+                assert_eq!(parsed.apply_with(&json, &options), acessor.apply_with(&json, &options), "{}", text);
+            }
+        }
+    }
+}
+
+
+/**
+ * Acessors evaluated one after the other, each stage reading the JC stream sent by the previous one.
+ * Built with the | operator: `first | second | third`
+ */
+struct Pipeline {
+    stages: Vec<Acessor>,
+    options: EvalOptions,
+}
+
+impl Pipeline {
+    fn new(first: Acessor) -> Self {
+        Pipeline { stages: vec![first], options: EvalOptions::default() }
+    }
+
+    fn with_options(mut self, options: EvalOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /**
+     * Evaluates every stage in its own thread, returning the handles of the threads.
+     * The stages run concurrently, so values start reaching the sender before the first stage finishes.
+     * When a stage reaches no value, or fails before sending anything, the stages after it send nothing. When it fails
+     * partway through a value, what it sent is completed with null in place of what failed (see eval), so the stages
+     * after it still finish, and the error is in the handle of the stage that failed
+     */
+    fn spawn(self, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Vec<thread::JoinHandle<Result<(), EvalError>>> {
+        let options = Arc::new(self.options);
+        let mut handles = Vec::with_capacity(self.stages.len());
+        let mut stage_receiver = receiver;
+        let mut stages = self.stages.into_iter().peekable();
+        while let Some(stage) = stages.next() {
+            let options = Arc::clone(&options);
+            // A stage starts as soon as the first packet of the previous result arrives, and reaches no value
+            // when the previous stage finished without sending anything
+            let eval_stage = move |stage_input: mpsc::Receiver<JC>, stage_sender| match stage_input.recv() {
+                Ok(first_packet) => eval_with_options(&stage, &options, resume_value(first_packet, stage_input), stage_sender),
+                Err(_) => Ok(()),
+            };
+            if stages.peek().is_none() {
+                // The last stage sends to the pipeline's sender
+                handles.push(thread::spawn(move || eval_stage(stage_receiver, sender)));
+                break;
+            }
+            let (stage_sender, next_receiver) = mpsc::channel::<JC>();
+            let stage_input = std::mem::replace(&mut stage_receiver, next_receiver);
+            handles.push(thread::spawn(move || eval_stage(stage_input, stage_sender)));
+        }
+        handles
+    }
+
+    /**
+     * Evaluates the stages one after the other in the current thread. Each stage is fully evaluated before the
+     * next one starts, with its output buffered in the channel between them
+     */
+    fn eval(&self, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<(), EvalError> {
+        let mut stage_receiver = receiver;
+        for stage in &self.stages[..self.stages.len() - 1] {
+            let (stage_sender, next_receiver) = mpsc::channel::<JC>();
+            eval_with_options(stage, &self.options, stage_receiver, stage_sender)?;
+            stage_receiver = match produced_value(next_receiver) {
+                Some(stage_result) => stage_result,
+                None => return Ok(()),
+            };
+        }
+        eval_with_options(self.stages.last().unwrap(), &self.options, stage_receiver, sender)
+    }
+
+    /**
+     * In memory version of eval, see Acessor::apply. The result of each stage is only copied when a later stage
+     * reaches part of a value built by an earlier one
+     */
+    fn apply<'a>(&self, json: &'a Json) -> Result<Cow<'a, Json>, EvalError> {
+        let mut value = Cow::Borrowed(json);
+        for stage in &self.stages {
+            value = match value {
+                Cow::Borrowed(json) => stage.apply_with(json, &self.options)?,
+                Cow::Owned(json) => Cow::Owned(stage.apply_with(&json, &self.options)?.into_owned()),
+            };
+        }
+        Ok(value)
+    }
+}
+
+impl BitOr for Acessor {
+    type Output = Pipeline;
+
+    fn bitor(self, next_stage: Acessor) -> Pipeline {
+        Pipeline::new(self) | next_stage
+    }
+}
+
+impl BitOr<Acessor> for Pipeline {
+    type Output = Pipeline;
+
+    fn bitor(mut self, next_stage: Acessor) -> Pipeline {
+        self.stages.push(next_stage);
+        self
+    }
+}
+
+#[cfg(test)]
+mod pipeline_tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn pipeline(queries: &[&str]) -> Pipeline {
+        let mut stages = queries.iter().map(|query| query.parse::<Acessor>().unwrap());
+        let first = Pipeline::new(stages.next().unwrap());
+        stages.fold(first, |pipeline, stage| pipeline | stage)
+    }
+
+    fn spawn_json(pipeline: Pipeline, json: &Json) -> (Vec<Result<(), EvalError>>, Option<Json>) {
+        let (sender, receiver) = mpsc::channel::<JC>();
+        let handles = pipeline.spawn(jc_stream(json), sender);
+        let results = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        (results, produced_value(receiver).map(deserialise_json))
+    }
+
+    #[test]
+    fn spawn_eval_and_apply_agree() {
+        let json = json!({"profiles": [{"name": "Twitter", "link": "a"}, {"name": "Facebook"}]});
+        for queries in [&[".profiles", "map(.name)", "[1]"][..], &[".profiles", "length"], &[".", ".profiles[0]", "keys"]] {
+            let expected = pipeline(queries).apply(&json).unwrap().into_owned();
+            let (results, spawned) = spawn_json(pipeline(queries), &json);
+            assert!(results.iter().all(Result::is_ok));
+            assert_eq!(spawned, Some(expected.clone()));
+            let (sender, receiver) = mpsc::channel::<JC>();
+            pipeline(queries).eval(jc_stream(&json), sender).unwrap();
+            assert_eq!(deserialise_json(receiver), expected);
+        }
+        assert!(matches!(pipeline(&[".profiles[0]"]).apply(&json).unwrap(), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn stages_after_a_missing_value_or_an_error_send_nothing() {
+        let json = json!({"a": [1, 2]});
+        let (results, spawned) = spawn_json(pipeline(&[".a", "[5]", "length"]), &json);
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(spawned, None);
+        let (results, spawned) = spawn_json(pipeline(&[".a", ".b", "length"]), &json);
+        assert!(matches!(results[1], Err(EvalError::TypeMismatch(_))));
+        assert!(results[2].is_ok());
+        assert_eq!(spawned, None);
+    }
+
+    #[test]
+    fn stages_after_an_error_partway_through_a_value_finish() {
+        let json = json!([{"a": 1}, 5, {"a": 2}]);
+        let (results, spawned) = spawn_json(pipeline(&[r#"map(."a")"#, "length"]), &json);
+        assert!(matches!(results[0], Err(EvalError::TypeMismatch(_))));
+        assert!(results[1].is_ok());
+        assert_eq!(spawned, Some(json!(3)));
+        let (results, spawned) = spawn_json(pipeline(&[r#"map(."a")"#, "."]), &json);
+        assert!(matches!(results[0], Err(EvalError::TypeMismatch(_))));
+        assert_eq!(spawned, Some(json!([1, null, null])));
+    }
+
+    #[test]
+    fn stages_run_concurrently() {
+        // The end of the input is held back, so the output can only arrive while the first stage is still running
+        let (input_sender, input_receiver) = mpsc::channel::<JC>();
+        input_sender.send(JC::ObjectStart).unwrap();
+        input_sender.send(JC::ArrayLen(1)).unwrap();
+        input_sender.send(JC::String("a".to_string())).unwrap();
+        input_sender.send(JC::Stream(jc_stream(&json!([1, 2])))).unwrap();
+        let (sender, receiver) = mpsc::channel::<JC>();
+        let handles = pipeline(&[".a", "."]).spawn(input_receiver, sender);
+        assert!(matches!(receiver.recv_timeout(Duration::from_secs(10)), Ok(JC::ArrayStart)));
+        assert!(!handles[0].is_finished());
+        input_sender.send(JC::ObjectEnd).unwrap();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+    }
+}
+
+
+/**
+ * The types of json values an acessor can reach, one bit for each type
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Shape(u8);
+
+impl Shape {
+    const NONE: Shape = Shape(0);
+    const NULL: Shape = Shape(1);
+    const BOOLEAN: Shape = Shape(2);
+    const NUMBER: Shape = Shape(4);
+    const STRING: Shape = Shape(8);
+    const ARRAY: Shape = Shape(16);
+    const OBJECT: Shape = Shape(32);
+    const ANY: Shape = Shape(63);
+
+    fn of(json: &Json) -> Shape {
+        match json {
+            Json::Null => Shape::NULL,
+            Json::Boolean(_) => Shape::BOOLEAN,
+            Json::Number(_) => Shape::NUMBER,
+            Json::String(_) => Shape::STRING,
+            Json::Array(_) => Shape::ARRAY,
+            Json::Object(_) => Shape::OBJECT,
+        }
+    }
+
+    fn union(self, other: Shape) -> Shape {
+        Shape(self.0 | other.0)
+    }
+
+    fn intersection(self, other: Shape) -> Shape {
+        Shape(self.0 & other.0)
+    }
+
+    fn is_none(self) -> bool {
+        self.0 == 0
+    }
+}
+
+/**
+ * The types in the shape, e.g. "number or string"
+ */
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Shape::NONE => return write!(f, "nothing"),
+            Shape::ANY => return write!(f, "any value"),
+            _ => (),
+        }
+        let names = ["null", "boolean", "number", "string", "array", "object"];
+        let types: Vec<&str> = names.iter().enumerate().filter(|&(bit, _)| self.0 & (1 << bit) != 0).map(|(_, name)| *name).collect();
+        write!(f, "{}", types.join(" or "))
+    }
+}
+
+/**
+ * Reasons why a query can not be compiled
+ */
+#[derive(Debug, Clone, PartialEq)]
+enum CompileError {
+    Parse(ParseError),       // The text of the query is not a valid acessor
+    TypeMismatch(String),    // A step can not be applied to any of the values the steps before it reach
+    UnknownFunction(String), // The FunctionRegistry has no function with the name of a Call
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Parse(error) => write!(f, "{}", error),
+            CompileError::TypeMismatch(message) => write!(f, "{}", message),
+            CompileError::UnknownFunction(name) => write!(f, "unknown function {}", name),
+        }
+    }
+}
+
+impl From<ParseError> for CompileError {
+    fn from(error: ParseError) -> Self {
+        CompileError::Parse(error)
+    }
+}
+
+/**
+ * An acessor that was checked and simplified by Acessor::compile, with the functions it was compiled for.
+ * It is shared behind an Arc, so clones are cheap and can be sent to other threads
+ */
+#[derive(Debug, Clone)]
+struct CompiledQuery {
+    acessor: Arc<Acessor>,
+    shape: Shape,                // The types of the values the query can reach
+    projection: Arc<Projection>, // The parts of the value the query reads
+    functions: FunctionRegistry, // The registry the query was compiled with, used to evaluate it
+}
+
+impl CompiledQuery {
+    fn parse(text: &str) -> Result<CompiledQuery, CompileError> {
+        Acessor::parse(text)?.compile()
+    }
+
+    fn shape(&self) -> Shape {
+        self.shape
+    }
+
+    fn eval(&self, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<(), EvalError> {
+        self.eval_with(&Bindings::new(), receiver, sender)
+    }
+
+    /**
+     * Evaluates the query with values for its variables, see eval_with
+     */
+    fn eval_with(&self, bindings: &Bindings, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<(), EvalError> {
+        eval_with_options(&self.acessor, &self.options(bindings), receiver, sender)
+    }
+
+    fn apply<'a>(&self, json: &'a Json) -> Result<Cow<'a, Json>, EvalError> {
+        self.apply_with(&Bindings::new(), json)
+    }
+
+    fn apply_with<'a>(&self, bindings: &Bindings, json: &'a Json) -> Result<Cow<'a, Json>, EvalError> {
+        self.acessor.apply_with(json, &self.options(bindings))
+    }
+
+    fn options(&self, bindings: &Bindings) -> EvalOptions {
+        EvalOptions { functions: self.functions.clone(), bindings: bindings.clone(), ..EvalOptions::default() }
+    }
+}
+
+impl fmt::Display for CompiledQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.acessor)
+    }
+}
+
+impl Acessor {
+    /**
+     * Checks that every step can be applied to some of the values reached by the steps before it, e.g. length | map(.)
+     * is rejected since length only reaches numbers. Steps inside an Optional are not checked, and neither are the
+     * results of functions, since the FunctionRegistry can replace the builtins.
+     * Parts that do not depend on the value, like 1 + 2 or { a: 1 }, are replaced by their result
+     */
+    fn compile(self) -> Result<CompiledQuery, CompileError> {
+        self.compile_with(&FunctionRegistry::default())
+    }
+
+    /**
+     * Same as compile, for queries evaluated with the functions of the registry. Calls of functions that are not in
+     * the registry are rejected
+     */
+    fn compile_with(self, functions: &FunctionRegistry) -> Result<CompiledQuery, CompileError> {
+        self.check_functions(functions)?;
+        let acessor = self.fold_constants();
+        let shape = acessor.output_shape(Shape::ANY, true)?;
+        let projection = acessor.projection(Projection::All);
+        Ok(CompiledQuery { acessor: Arc::new(acessor), shape, projection: Arc::new(projection), functions: functions.clone() })
+    }
+
+    fn check_functions(&self, functions: &FunctionRegistry) -> Result<(), CompileError> {
+        match self {
+            Acessor::Call(name, arguments) => {
+                if functions.get(name).is_err() {
+                    return Err(CompileError::UnknownFunction(name.to_string()));
+                }
+                arguments.iter().try_for_each(|argument| argument.check_functions(functions))
+            },
+            Acessor::ObjectField(_, next_acessor) | Acessor::ArrayEntry(_, next_acessor) | Acessor::Map(next_acessor)
+                | Acessor::SortBy(next_acessor) | Acessor::UniqueBy(next_acessor) | Acessor::GroupBy(next_acessor)
+                | Acessor::Optional(next_acessor) => next_acessor.check_functions(functions),
+            Acessor::Construct(fields) => fields.iter().try_for_each(|(_, field_acessor)| field_acessor.check_functions(functions)),
+            Acessor::ConstructArray(values) => values.iter().try_for_each(|value_acessor| value_acessor.check_functions(functions)),
+            Acessor::Pipe(first, second) | Acessor::Alternative(first, second) | Acessor::Binary(_, first, second)
+                | Acessor::Bind(first, _, second) => {
+                first.check_functions(functions)?;
+                second.check_functions(functions)
+            },
+            Acessor::If(condition, then_acessor, else_acessor) => {
+                condition.check_functions(functions)?;
+                then_acessor.check_functions(functions)?;
+                else_acessor.check_functions(functions)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Replaces the parts of the acessor that give the same result for every value with a Literal
+     */
+    fn fold_constants(self) -> Acessor {
+        let fold = |acessor: Box<Acessor>| Box::new(acessor.fold_constants());
+        match self {
+            Acessor::ObjectField(label, next_acessor) => Acessor::ObjectField(label, fold(next_acessor)),
+            Acessor::ArrayEntry(index, next_acessor) => Acessor::ArrayEntry(index, fold(next_acessor)),
+            Acessor::Map(next_acessor) => Acessor::Map(fold(next_acessor)),
+            Acessor::Construct(fields) => {
+                let fields: Vec<(String, Acessor)> = fields.into_iter().map(|(key, field_acessor)| (key, field_acessor.fold_constants())).collect();
+                if fields.iter().all(|(_, field_acessor)| field_acessor.is_literal()) {
+                    return Acessor::Literal(Json::Object(fields.into_iter().map(|(key, field_acessor)| (key, field_acessor.into_literal())).collect()));
+                }
+                Acessor::Construct(fields)
+            },
+            Acessor::ConstructArray(values) => {
+                let values: Vec<Acessor> = values.into_iter().map(Acessor::fold_constants).collect();
+                if values.iter().all(Acessor::is_literal) {
+                    return Acessor::Literal(Json::Array(values.into_iter().map(Acessor::into_literal).collect()));
+                }
+                Acessor::ConstructArray(values)
+            },
+            Acessor::Pipe(first, second) => match (first.fold_constants(), second.fold_constants()) {
+                (Acessor::End, second) => second,
+                (first, Acessor::End) => first,
+                (first, second) => Acessor::Pipe(Box::new(first), Box::new(second)),
+            },
+            Acessor::SortBy(key_acessor) => Acessor::SortBy(fold(key_acessor)),
+            Acessor::UniqueBy(key_acessor) => Acessor::UniqueBy(fold(key_acessor)),
+            Acessor::GroupBy(key_acessor) => Acessor::GroupBy(fold(key_acessor)),
+            Acessor::Optional(optional_acessor) => match optional_acessor.fold_constants() {
+                Acessor::Literal(value) => Acessor::Literal(value),
+                optional_acessor => Acessor::Optional(Box::new(optional_acessor)),
+            },
+            Acessor::Alternative(first, second) => match first.fold_constants() {
+                Acessor::Literal(Json::Null) => second.fold_constants(),
+                Acessor::Literal(value) => Acessor::Literal(value),
+                first => Acessor::Alternative(Box::new(first), fold(second)),
+            },
+            Acessor::If(condition, then_acessor, else_acessor) => match condition.fold_constants() {
+                Acessor::Literal(ref value) if value.is_truthy() => then_acessor.fold_constants(),
+                Acessor::Literal(_) => else_acessor.fold_constants(),
+                condition => Acessor::If(Box::new(condition), fold(then_acessor), fold(else_acessor)),
+            },
+            Acessor::Call(name, arguments) => Acessor::Call(name, arguments.into_iter().map(Acessor::fold_constants).collect()),
+            Acessor::Binary(operator, left, right) => match (operator, left.fold_constants(), right.fold_constants()) {
+                (BinaryOperator::And, Acessor::Literal(ref value), _) if !value.is_truthy() => Acessor::Literal(Json::Boolean(false)),
+                (BinaryOperator::Or, Acessor::Literal(ref value), _) if value.is_truthy() => Acessor::Literal(Json::Boolean(true)),
+                (operator, Acessor::Literal(left), Acessor::Literal(right)) => match operator.apply(&left, &right) {
+                    Ok(value) => Acessor::Literal(value),
+                    // Errors are left for eval to report
+                    Err(_) => Acessor::Binary(operator, Box::new(Acessor::Literal(left)), Box::new(Acessor::Literal(right))),
+                },
+                (operator, left, right) => Acessor::Binary(operator, Box::new(left), Box::new(right)),
+            },
+            Acessor::Bind(source, name, body) => Acessor::Bind(fold(source), name, fold(body)),
+            acessor => acessor,
+        }
+    }
+
+    fn is_literal(&self) -> bool {
+        matches!(self, Acessor::Literal(_))
+    }
+
+    fn into_literal(self) -> Json {
+        match self {
+            Acessor::Literal(value) => value,
+            _ => panic!("Expected a Literal acessor"),
+        }
+    }
+
+    /**
+     * The types of the values the acessor can reach when applied to values of the input shape.
+     * When checked, a step that can not be applied to any of them is a TypeMismatch
+     */
+    fn output_shape(&self, input: Shape, checked: bool) -> Result<Shape, CompileError> {
+        if input.is_none() {
+            // No value reaches the acessor, so there is nothing to check
+            return Ok(Shape::NONE);
+        }
+        // The types of the input the acessor can be applied to, for the ones that need a value of some type
+        let expected = match self {
+            Acessor::ObjectField(_, _) => Shape::OBJECT,
+            Acessor::ArrayEntry(_, _) | Acessor::Map(_) | Acessor::SortBy(_) | Acessor::UniqueBy(_) | Acessor::GroupBy(_) => Shape::ARRAY,
+            Acessor::Length => Shape::ARRAY.union(Shape::OBJECT).union(Shape::STRING).union(Shape::NULL),
+            Acessor::Keys | Acessor::Values | Acessor::Sum | Acessor::Min | Acessor::Max
+                | Acessor::Count | Acessor::Any | Acessor::All => Shape::ARRAY.union(Shape::OBJECT),
+            _ => Shape::ANY,
+        };
+        if input.intersection(expected).is_none() {
+            if !checked {
+                return Ok(Shape::NONE);
+            }
+            let step = match self {
+                Acessor::ObjectField(label, _) => format!(".{}", Json::String(label.to_string())),
+                Acessor::ArrayEntry(index, _) => format!("[{}]", index),
+                Acessor::Map(_) => "map".to_string(),
+                _ => self.to_string(),
+            };
+            return Err(CompileError::TypeMismatch(format!("{} cannot be applied to {}", step, input)));
+        }
+        Ok(match self {
+            Acessor::ObjectField(_, next_acessor) | Acessor::ArrayEntry(_, next_acessor) => next_acessor.output_shape(Shape::ANY, checked)?,
+            Acessor::Map(next_acessor) => {
+                next_acessor.output_shape(Shape::ANY, checked)?;
+                Shape::ARRAY
+            },
+            Acessor::End => input,
+            Acessor::Construct(fields) => {
+                for (_, field_acessor) in fields {
+                    field_acessor.output_shape(input, checked)?;
+                }
+                Shape::OBJECT
+            },
+            Acessor::ConstructArray(values) => {
+                for value_acessor in values {
+                    value_acessor.output_shape(input, checked)?;
+                }
+                Shape::ARRAY
+            },
+            Acessor::Pipe(first, second) => second.output_shape(first.output_shape(input, checked)?, checked)?,
+            Acessor::Length | Acessor::Sum | Acessor::Count => Shape::NUMBER,
+            Acessor::Keys | Acessor::Values => Shape::ARRAY,
+            Acessor::Min | Acessor::Max => Shape::ANY,
+            Acessor::Any | Acessor::All => Shape::BOOLEAN,
+            Acessor::SortBy(key_acessor) | Acessor::UniqueBy(key_acessor) | Acessor::GroupBy(key_acessor) => {
+                key_acessor.output_shape(Shape::ANY, checked)?;
+                Shape::ARRAY
+            },
+            Acessor::Optional(optional_acessor) => optional_acessor.output_shape(input, false)?,
+            Acessor::Alternative(first, second) => first.output_shape(input, checked)?.union(second.output_shape(input, checked)?),
+            Acessor::If(condition, then_acessor, else_acessor) => {
+                condition.output_shape(input, checked)?;
+                then_acessor.output_shape(input, checked)?.union(else_acessor.output_shape(input, checked)?)
+            },
+            Acessor::Literal(value) => Shape::of(value),
+            Acessor::Call(_, arguments) => {
+                for argument in arguments {
+                    argument.output_shape(input, checked)?;
+                }
+                Shape::ANY
+            },
+            Acessor::Binary(operator, left, right) => {
+                let operands = left.output_shape(input, checked)?.union(right.output_shape(input, checked)?);
+                match operator {
+                    // null + value is the value, and the other types are kept by concatenating and merging
+                    BinaryOperator::Add => operands,
+                    BinaryOperator::Subtract => operands.intersection(Shape::NUMBER.union(Shape::ARRAY)),
+                    BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Modulo => Shape::NUMBER,
+                    _ => Shape::BOOLEAN,
+                }
+            },
+            Acessor::Var(_) => Shape::ANY,
+            Acessor::Bind(source, _, body) => {
+                source.output_shape(input, checked)?;
+                body.output_shape(input, checked)?
+            },
+        })
+    }
+}
+
+/**
+ * The parts of a value an acessor reads, used to skip the rest when serialising
+ */
+#[derive(Debug, Clone, PartialEq)]
+enum Projection {
+    All,                                  // The whole value
+    Nothing,                              // Only its type, and the keys and length when it is an object or array
+    Fields(BTreeMap<String, Projection>), // Some fields of an object
+    Entries(BTreeMap<usize, Projection>), // Some entries of an array
+    Each(Box<Projection>),                // The same part of every entry of an array
+}
+
+impl Projection {
+    /**
+     * The parts read by either projection
+     */
+    fn union(self, other: Projection) -> Projection {
+        match (self, other) {
+            (Projection::All, _) | (_, Projection::All) => Projection::All,
+            (Projection::Nothing, projection) | (projection, Projection::Nothing) => projection,
+            (Projection::Fields(fields), Projection::Fields(other_fields)) => Projection::Fields(merge_projections(fields, other_fields)),
+            (Projection::Entries(entries), Projection::Entries(other_entries)) => Projection::Entries(merge_projections(entries, other_entries)),
+            (Projection::Each(each), Projection::Each(other_each)) => Projection::Each(Box::new(each.union(*other_each))),
+            (Projection::Each(each), Projection::Entries(entries)) | (Projection::Entries(entries), Projection::Each(each)) => {
+                Projection::Each(Box::new(entries.into_iter().fold(*each, |each, (_, projection)| each.union(projection))))
+            },
+            // Fields of objects and entries of arrays, the value is only one of them so all of it is kept
+            _ => Projection::All,
+        }
+    }
+}
+
+fn merge_projections<K: Ord>(mut projections: BTreeMap<K, Projection>, other: BTreeMap<K, Projection>) -> BTreeMap<K, Projection> {
+    for (key, projection) in other {
+        let merged = match projections.remove(&key) {
+            Some(existing) => existing.union(projection),
+            None => projection,
+        };
+        projections.insert(key, merged);
+    }
+    projections
+}
+
+impl Acessor {
+    /**
+     * The parts of the value the acessor reads, when next is the part read of each value it reaches
+     */
+    fn projection(&self, next: Projection) -> Projection {
+        match self {
+            Acessor::End => next,
+            Acessor::ObjectField(label, next_acessor) => {
+                let mut fields = BTreeMap::new();
+                fields.insert(label.to_string(), next_acessor.projection(next));
+                Projection::Fields(fields)
+            },
+            Acessor::ArrayEntry(index, next_acessor) => {
+                let mut entries = BTreeMap::new();
+                entries.insert(*index, next_acessor.projection(next));
+                Projection::Entries(entries)
+            },
+            // The results make a new array, so what is read from it is not a part of the array values
+            Acessor::Map(next_acessor) => Projection::Each(Box::new(next_acessor.projection(Projection::All))),
+            // The second acessor reads parts of the values the first one reaches, when those are parts of the value
+            Acessor::Pipe(first, second) if first.reaches_parts() => first.projection(second.projection(next)),
+            Acessor::Pipe(first, _) => first.projection(Projection::All),
+            Acessor::Construct(fields) => fields.iter()
+                .fold(Projection::Nothing, |projection, (_, field_acessor)| projection.union(field_acessor.projection(Projection::All))),
+            Acessor::ConstructArray(values) => values.iter()
+                .fold(Projection::Nothing, |projection, value_acessor| projection.union(value_acessor.projection(Projection::All))),
+            Acessor::Optional(optional_acessor) => optional_acessor.projection(next),
+            Acessor::Alternative(first, second) => first.projection(next.clone()).union(second.projection(next)),
+            Acessor::If(condition, then_acessor, else_acessor) => condition.projection(Projection::All)
+                .union(then_acessor.projection(next.clone()))
+                .union(else_acessor.projection(next)),
+            Acessor::Literal(_) | Acessor::Var(_) => Projection::Nothing,
+            Acessor::Binary(_, left, right) => left.projection(Projection::All).union(right.projection(Projection::All)),
+            Acessor::Bind(source, _, body) => source.projection(Projection::All).union(body.projection(next)),
+            // Functions, aggregates and sorts read the whole value
+            _ => Projection::All,
+        }
+    }
+
+    /**
+     * Whether the values the acessor reaches are parts of the value it is applied to, rather than new values
+     */
+    fn reaches_parts(&self) -> bool {
+        match self {
+            Acessor::End => true,
+            Acessor::ObjectField(_, next_acessor) | Acessor::ArrayEntry(_, next_acessor) | Acessor::Optional(next_acessor) => next_acessor.reaches_parts(),
+            Acessor::Pipe(first, second) => first.reaches_parts() && second.reaches_parts(),
+            _ => false,
+        }
+    }
+}
+
+/**
+ * Same as serialise_json, but only the parts of the value the query reads are sent. An array or object value that is
+ * not needed is sent as Skipped instead of its Stream or simple value, so it is neither serialised nor given a channel.
+ * Values of another type than the query expects are sent whole, so eval fails the same way as with serialise_json
+ */
+fn serialise_json_with_projection(val: &Json, query: &CompiledQuery, sender: mpsc::Sender<JC>) {
+    serialise_projected(val, &query.projection, sender);
+}
+
+fn serialise_projected(val: &Json, projection: &Projection, sender: mpsc::Sender<JC>) {
+    match (val, projection) {
+        (Json::Array(json_array), Projection::Nothing) | (Json::Array(json_array), Projection::Entries(_)) | (Json::Array(json_array), Projection::Each(_)) => {
+            sender.send(JC::ArrayStart).unwrap();
+            sender.send(JC::ArrayLen(json_array.len())).unwrap();
+            for (index, json_value) in json_array.iter().enumerate() {
+                let value_projection = match projection {
+                    Projection::Entries(entries) => entries.get(&index),
+                    Projection::Each(each) => Some(&**each),
+                    _ => None,
+                };
+                send_projected_value(json_value, value_projection, &sender);
+            }
+            sender.send(JC::ArrayEnd).unwrap();
+        },
+        (Json::Object(json_object), Projection::Nothing) | (Json::Object(json_object), Projection::Fields(_)) => {
+            sender.send(JC::ObjectStart).unwrap();
+            sender.send(JC::ArrayLen(json_object.len())).unwrap();
+            for (key, json_value) in json_object {
+                sender.send(JC::String(key.to_string())).unwrap();
+                let value_projection = match projection {
+                    Projection::Fields(fields) => fields.get(key),
+                    _ => None,
+                };
+                send_projected_value(json_value, value_projection, &sender);
+            }
+            sender.send(JC::ObjectEnd).unwrap();
+        },
+        _ => serialise_json(val, sender),
+    }
+}
+
+/**
+ * Sends the value of an array or object in its own channel, or Skipped when it has no projection, whatever its type
+ */
+fn send_projected_value(json_value: &Json, projection: Option<&Projection>, sender: &mpsc::Sender<JC>) {
+    match projection {
+        Some(projection) => {
+            let (value_sender, value_receiver) = mpsc::channel::<JC>();
+            sender.send(JC::Stream(value_receiver)).unwrap();
+            serialise_projected(json_value, projection, value_sender);
+        },
+        None => sender.send(JC::Skipped).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod projection_tests {
+    use super::*;
+
+    /**
+     * Evaluates the query on the value serialised with the given function, keeping the error, if any
+     */
+    fn eval_serialised(query: &CompiledQuery, serialise: impl FnOnce(mpsc::Sender<JC>)) -> Result<Option<Json>, EvalError> {
+        let (sender, receiver) = mpsc::channel::<JC>();
+        serialise(sender);
+        let (result_sender, result_receiver) = mpsc::channel::<JC>();
+        query.eval(receiver, result_sender)?;
+        Ok(produced_value(result_receiver).map(deserialise_json))
+    }
+
+    #[test]
+    fn projected_and_full_serialisation_give_the_same_results() {
+        let json = json!({
+            "name": "Jason Ray",
+            "age": 31,
+            "admin": false,
+            "address": { "city": "New York", "postalCode": 64780, "geo": { "lat": 40.7, "lng": -74.0 } },
+            "languages": ["Java", "Node.js", "Javascript", "JSON"],
+            "socialProfiles": [
+                { "name": "Twitter", "link": "https://twitter.com", "followers": 120 },
+                { "name": "Facebook", "link": "https://www.facebook.com", "followers": 80 }
+            ],
+            "scores": [[1, 2], [3, 4, 5], []]
+        });
+        let queries = [
+            r#"."name""#, r#"."age""#, r#"."address"."city""#, r#"."address"."geo"."lat""#, r#"."languages"[2]"#,
+            r#"."languages"[9]"#, r#"."socialProfiles" | map(."name")"#, r#"."socialProfiles"[1]."link""#,
+            r#"."scores" | map(length)"#, r#"."scores"[1][2]"#, r#"."missing""#, r#"."missing"?"#,
+            r#"{ "city": ."address"."city", "first": ."languages"[0] }"#, r#"[ ."age", ."admin" ]"#,
+            r#"."age" + 1 >= 18 and ."admin" == false"#, r#"."nickname" // ."name""#,
+            r#"if ."admin" then ."address" else ."languages" end"#, r#"."languages" | length"#, r#"."address" | keys"#,
+            r#"."socialProfiles" | sort_by(."followers") | map(."name")"#, r#"."socialProfiles" | map(."followers") | sum"#,
+            r#"."name" | ascii_downcase"#, r#"."age" as $age | ."socialProfiles" | map([ ."name", $age ])"#,
+            r#"."address" | ."geo""#, ".", r#"."name"[0]"#,
+        ];
+        for text in queries {
+            let query = CompiledQuery::parse(text).unwrap();
+            let full = eval_serialised(&query, |sender| serialise_json(&json, sender));
+            let projected = eval_serialised(&query, |sender| serialise_json_with_projection(&json, &query, sender));
+            assert_eq!(projected, full, "{}", text);
+        }
+    }
+
+    #[test]
+    fn projected_and_full_serialisation_agree_on_random_queries() {
+        // A Skipped value that reaches an acessor which needs it panics, or gives a different result
+        let mut rng = TestRng(0x2545_F491_4F6C_DD1D);
+        let mut compared = 0;
+        for _ in 0..3000 {
+            let json = rng.json(3);
+            let query = match super::apply_tests::random_acessor(&mut rng, 4).compile() {
+                Ok(query) => query,
+                Err(_) => continue, // Steps that can never be applied are rejected before eval
+            };
+            let full = eval_serialised(&query, |sender| serialise_json(&json, sender));
+            let projected = eval_serialised(&query, |sender| serialise_json_with_projection(&json, &query, sender));
+            assert_eq!(projected, full, "{} on {}", query, json);
+            compared += 1;
+        }
+        assert!(compared > 1000);
+    }
+
+    #[test]
+    fn values_that_are_not_read_are_skipped() {
+        let json = json!({"name": "Jason Ray", "age": 31, "languages": ["Java", "JSON"]});
+        let query = CompiledQuery::parse(r#"."languages"[1]"#).unwrap();
+        let (sender, receiver) = mpsc::channel::<JC>();
+        serialise_json_with_projection(&json, &query, sender);
+        // Simple values are skipped as well as arrays and objects
+        let top = ValueTop::read(receiver);
+        let fields = match top {
+            ValueTop::Object(fields) => fields,
+            _ => panic!("Expected an object (Projection Tests)"),
+        };
+        let skipped: Vec<(&str, bool)> = fields.iter().map(|(key, value)| (key.as_str(), matches!(value, JC::Skipped))).collect();
+        assert_eq!(skipped, vec![("age", true), ("languages", false), ("name", true)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Skipped values cannot be deserialised (Json Builder)")]
+    fn skipped_values_are_not_deserialised() {
+        let query = CompiledQuery::parse(r#"."age""#).unwrap();
+        let (sender, receiver) = mpsc::channel::<JC>();
+        serialise_json_with_projection(&json!({"name": "Jason Ray", "age": 31}), &query, sender);
+        deserialise_json(receiver);
+    }
+}
+
+/**
+ * Compiled queries by their text, keeping the ones used most recently when there are more than the capacity
+ */
+struct QueryCache {
+    capacity: usize,
+    queries: HashMap<String, (CompiledQuery, u64)>, // Each query with the time it was last used
+    recent: BTreeMap<u64, String>,                   // The text of the queries by the time they were last used
+    time: u64,
+}
+
+impl QueryCache {
+    fn new(capacity: usize) -> Self {
+        QueryCache { capacity, queries: HashMap::new(), recent: BTreeMap::new(), time: 0 }
+    }
+
+    /**
+     * Returns the compiled query for the text, compiling it when it is not in the cache.
+     * Queries that fail to compile are not cached
+     */
+    fn get(&mut self, text: &str) -> Result<CompiledQuery, CompileError> {
+        self.time += 1;
+        if let Some((query, last_used)) = self.queries.get_mut(text) {
+            let text = self.recent.remove(last_used).unwrap();
+            self.recent.insert(self.time, text);
+            *last_used = self.time;
+            return Ok(query.clone());
+        }
+        let query = CompiledQuery::parse(text)?;
+        if self.capacity == 0 {
+            return Ok(query);
+        }
+        if self.queries.len() == self.capacity {
+            let oldest = *self.recent.keys().next().unwrap();
+            let oldest_text = self.recent.remove(&oldest).unwrap();
+            self.queries.remove(&oldest_text);
+        }
+        self.queries.insert(text.to_string(), (query.clone(), self.time));
+        self.recent.insert(self.time, text.to_string());
+        Ok(query)
+    }
+
+    fn len(&self) -> usize {
+        self.queries.len()
+    }
+}
+
+#[cfg(test)]
+mod compile_tests {
+    use super::*;
+
+    /**
+     * The text of the acessor after folding its constants
+     */
+    fn folded(text: &str) -> String {
+        text.parse::<Acessor>().unwrap().fold_constants().to_string()
+    }
+
+    #[test]
+    fn constants_are_folded() {
+        assert_eq!(folded("1 + 2"), "3");
+        assert_eq!(folded(r#"{ "a": 1 + 2, "b": [ "x", 2 > 1 ] }"#), folded(r#"{ "a": 3, "b": [ "x", true ] }"#));
+        assert!(matches!(r#"{ "a": 1 + 2 }"#.parse::<Acessor>().unwrap().fold_constants(), Acessor::Literal(_)));
+        assert_eq!(folded(r#"if 1 == 2 then ."a" else ."b" end"#), folded(r#"."b""#));
+        assert_eq!(folded(r#"null // ."a""#), folded(r#"."a""#));
+        assert_eq!(folded(r#"false and ."a""#), "false");
+        // Only the parts that do not depend on the value are folded
+        assert_eq!(folded(r#"."age" + (1 + 2) * 2"#), folded(r#"."age" + 6"#));
+        // Errors are left for eval to report
+        let division = "1 / 0".parse::<Acessor>().unwrap().fold_constants();
+        assert!(matches!(division, Acessor::Binary(BinaryOperator::Divide, _, _)));
+        assert!(matches!(division.apply(&Json::Null), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn steps_that_reach_no_value_of_their_type_are_rejected() {
+        for text in ["length | map(.)", r#"keys | ."a""#, r#"{ "a": 1 } | [0]"#, r#"."a" | length | sort_by(.)"#, "[ 1 ] | any | keys"] {
+            match CompiledQuery::parse(text) {
+                Err(CompileError::TypeMismatch(_)) => (),
+                other => panic!("Expected {} to be rejected, got {:?}", text, other),
+            }
+        }
+        assert_eq!(CompiledQuery::parse("length | map(.)").unwrap_err(), CompileError::TypeMismatch(String::from("map cannot be applied to number")));
+        // Steps inside an Optional are not checked
+        assert!(CompiledQuery::parse("(length | map(.))?").is_ok());
+        assert_eq!(CompiledQuery::parse(r#"."a" | length"#).unwrap().shape(), Shape::NUMBER);
+        assert_eq!(CompiledQuery::parse(r#"."a" | keys"#).unwrap().shape(), Shape::ARRAY);
+    }
+
+    #[test]
+    fn queries_are_evaluated_with_the_functions_they_were_compiled_with() {
+        let mut functions = FunctionRegistry::default();
+        functions.register("shout", |input, _| Ok(Json::String(format!("{}!", input.as_str().unwrap_or_default()))));
+        let compiled = r#"."name" | shout"#.parse::<Acessor>().unwrap().compile_with(&functions).unwrap();
+        let json = json!({"name": "Jason", "age": 31});
+        assert_eq!(compiled.apply(&json).unwrap().into_owned(), json!("Jason!"));
+        let (sender, receiver) = mpsc::channel::<JC>();
+        compiled.eval(jc_stream(&json), sender).unwrap();
+        assert_eq!(deserialise_json(receiver), json!("Jason!"));
+
+        let compiled = r#"."age" >= $min_age"#.parse::<Acessor>().unwrap().compile_with(&functions).unwrap();
+        let bindings = Bindings::new().with("min_age", json!(40));
+        assert_eq!(compiled.apply_with(&bindings, &json).unwrap().into_owned(), json!(false));
+        let (sender, receiver) = mpsc::channel::<JC>();
+        compiled.eval_with(&bindings, jc_stream(&json), sender).unwrap();
+        assert_eq!(deserialise_json(receiver), json!(false));
+        assert_eq!(compiled.apply(&json), Err(EvalError::UnboundVariable(String::from("min_age"))));
+    }
+
+    #[test]
+    fn least_recently_used_queries_are_evicted() {
+        let mut cache = QueryCache::new(2);
+        let first = cache.get(r#"."a""#).unwrap();
+        cache.get(r#"."b""#).unwrap();
+        // Using ."a" again makes ."b" the least recently used, so it is the one evicted for ."c"
+        assert!(Arc::ptr_eq(&cache.get(r#"."a""#).unwrap().acessor, &first.acessor));
+        cache.get(r#"."c""#).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.queries.contains_key(r#"."a""#));
+        assert!(!cache.queries.contains_key(r#"."b""#));
+        assert!(cache.queries.contains_key(r#"."c""#));
+        assert_eq!(cache.recent.len(), 2);
+        // Queries that fail to compile are not cached
+        assert!(cache.get("length | map(.)").is_err());
+        assert!(cache.queries.contains_key(r#"."a""#));
+        let mut no_cache = QueryCache::new(0);
+        no_cache.get(r#"."a""#).unwrap();
+        assert_eq!(no_cache.len(), 0);
+    }
+}
+
+
+/**
+ * Operations that can be applied to the values targeted by an acessor when evaluating in write mode
+ * Set and Apply replace the targeted value, Delete removes it from its array or object and
+ * Rename changes the key of the targeted object field
+ */
+enum Update {
+    Set(Json),
+    Delete,
+    Rename(String),
+    Apply(Box<dyn Fn(Json) -> Json + Send>),
+}
+
+
+/**
+ * Write mode of eval. Instead of sending only the values reached by the acessor, the whole json value is
+ * sent through the sender channel following the usual protocols, with only the targeted values changed.
+ * Delete and Rename change the array or object that holds the targeted value, so they are handled by the
+ * ObjectField and ArrayEntry acessors that are followed by End.
+ * Values that are not targeted are passed along without being read, the same way the End acessor does in eval.
+ * Set and Apply on a missing object field add the field, on a missing array index nothing is changed.
+ * Acessors that are not paths, and Delete or Rename on something other than a field or entry, are rejected with an
+ * InvalidArgument before anything is sent. A path that does not fit the value is a TypeMismatch, and a value Skipped by
+ * a projection can not be updated. In both cases the whole value is still sent, with the values that could not be
+ * updated, and the ones after them, left unchanged
+ */
+fn eval_update(acessor: &Acessor, update: &Update, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<(), EvalError> {
+    if let Err(error) = check_update(acessor, update) {
+        consume_value(receiver);
+        return Err(error);
+    }
+    update_value(acessor, update, receiver, sender)
+}
+
+/**
+ * Checks that the update can be applied at the end of the acessor, whatever the value is
+ */
+fn check_update(acessor: &Acessor, update: &Update) -> Result<(), EvalError> {
+    match (acessor, update) {
+        (Acessor::ObjectField(_, next_acessor), _) => match (&**next_acessor, update) {
+            (Acessor::End, _) => Ok(()),
+            _ => check_update(next_acessor, update),
+        },
+        (Acessor::ArrayEntry(_, next_acessor), _) | (Acessor::Map(next_acessor), _) => match (&**next_acessor, update) {
+            (Acessor::End, Update::Rename(_)) => Err(EvalError::InvalidArgument(String::from("Rename can only be applied to object fields"))),
+            (Acessor::End, _) => Ok(()),
+            _ => check_update(next_acessor, update),
+        },
+        (Acessor::End, Update::Set(_)) | (Acessor::End, Update::Apply(_)) => Ok(()),
+        (Acessor::End, _) => Err(EvalError::InvalidArgument(String::from("Delete and Rename must target an object field or an array entry"))),
+        _ => Err(EvalError::InvalidArgument(format!("Only paths can be updated, not {}", acessor))),
+    }
+}
+
+fn update_value(acessor: &Acessor, update: &Update, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Result<(), EvalError> {
+    match acessor {
+        Acessor::ObjectField(label, next_acessor) => {
+            // The fields are collected before sending anything, since deleting, renaming or adding a field
+            // changes the size of the object. Only the stream endpoints are kept, the values are not read
+            let mut fields = match ValueTop::read(receiver) {
+                ValueTop::Object(fields) => fields,
+                other => {
+                    other.send(&sender);
+                    return Err(EvalError::TypeMismatch(String::from("Cannot apply ObjectField Acessor to this Json Value")));
+                }
+            };
+
+            let mut target = fields.iter().position(|(key, _)| key == label);
+            if let Acessor::End = **next_acessor {
+                match update {
+                    Update::Delete => {
+                        if let Some(i) = target {
+                            let (_, value_packet) = fields.remove(i);
+                            consume_packet(value_packet);
+                        }
+                        target = None;
+                    },
+                    Update::Rename(new_label) => {
+                        if target.is_some() && new_label != label {
+                            // A field that already has the new label is replaced by the renamed one
+                            if let Some(j) = fields.iter().position(|(key, _)| key == new_label) {
+                                let (_, value_packet) = fields.remove(j);
+                                consume_packet(value_packet);
+                            }
+                            let i = fields.iter().position(|(key, _)| key == label).unwrap();
+                            fields[i].0 = new_label.to_string();
+                        }
+                        target = None;
+                    },
+                    Update::Set(_) | Update::Apply(_) => {
+                        if target.is_none() {
+                            // Missing fields are added as null and then updated like any other value
+                            let (null_sender, null_receiver) = mpsc::channel::<JC>();
+                            null_sender.send(JC::Null).unwrap();
+                            fields.push((label.to_string(), JC::Stream(null_receiver)));
+                            target = Some(fields.len() - 1);
+                        }
+                    }
+                }
+            }
+
+            sender.send(JC::ObjectStart).unwrap();
+            sender.send(JC::ArrayLen(fields.len())).unwrap();
+            let mut fields = fields.into_iter().enumerate();
+            for (i, (key, value_packet)) in fields.by_ref() {
+                sender.send(JC::String(key)).unwrap();
+                if Some(i) == target {
+                    // recursively update the targeted value through a new channel
+                    if let Err(error) = update_entry(next_acessor, update, value_packet, &sender) {
+                        for (_, (key, value_packet)) in fields {
+                            sender.send(JC::String(key)).unwrap();
+                            sender.send(value_packet).unwrap();
+                        }
+                        sender.send(JC::ObjectEnd).unwrap();
+                        return Err(error);
+                    }
+                } else {
+                    sender.send(value_packet).unwrap(); // pass the value along untouched
+                }
+            }
+            sender.send(JC::ObjectEnd).unwrap();
+        },
+        Acessor::ArrayEntry(index, next_acessor) => {
+            let mut values = match ValueTop::read(receiver) {
+                ValueTop::Array(values) => values,
+                other => {
+                    other.send(&sender);
+                    return Err(EvalError::TypeMismatch(String::from("Cannot apply Index accessor to this Json Value")));
+                }
+            };
+
+            let mut target = if *index < values.len() { Some(*index) } else { None };
+            // Rename was rejected by check_update, so only Delete changes the array
+            if let (Acessor::End, Update::Delete) = (&**next_acessor, update) {
+                if let Some(i) = target {
+                    consume_packet(values.remove(i));
+                }
+                target = None;
+            }
+
+            sender.send(JC::ArrayStart).unwrap();
+            sender.send(JC::ArrayLen(values.len())).unwrap();
+            let mut values = values.into_iter().enumerate();
+            for (i, value_packet) in values.by_ref() {
+                if Some(i) == target {
+                    if let Err(error) = update_entry(next_acessor, update, value_packet, &sender) {
+                        values.for_each(|(_, value_packet)| sender.send(value_packet).unwrap());
+                        sender.send(JC::ArrayEnd).unwrap();
+                        return Err(error);
+                    }
+                } else {
+                    sender.send(value_packet).unwrap();
+                }
+            }
+            sender.send(JC::ArrayEnd).unwrap();
+        },
+        Acessor::Map(next_acessor) => {
+            // Fans the update out to every value of the array
+            let values = match ValueTop::read(receiver) {
+                ValueTop::Array(values) => values,
+                other => {
+                    other.send(&sender);
+                    return Err(EvalError::TypeMismatch(String::from("Cannot apply Map to this Json Value")));
+                }
+            };
+            let delete_all = matches!((&**next_acessor, update), (Acessor::End, Update::Delete));
+            sender.send(JC::ArrayStart).unwrap();
+            sender.send(JC::ArrayLen(if delete_all { 0 } else { values.len() })).unwrap();
+            let mut result = Ok(());
+            for value_packet in values {
+                if delete_all {
+                    consume_packet(value_packet);
+                } else if result.is_err() {
+                    sender.send(value_packet).unwrap(); // pass the rest along untouched
+                } else {
+                    result = update_entry(next_acessor, update, value_packet, &sender);
+                }
+            }
+            sender.send(JC::ArrayEnd).unwrap();
+            return result;
+        },
+        Acessor::End => {
+            // The targeted value is reached, so it is replaced by the result of the update
+            match update {
+                Update::Set(new_value) => {
+                    consume_value(receiver);
+                    serialise_json(new_value, sender);
+                },
+                Update::Apply(function) => {
+                    let old_value = deserialise_json(receiver);
+                    serialise_json(&function(old_value), sender);
+                },
+                _ => unreachable!("Delete and Rename were rejected by check_update (Eval Update End)")
+            }
+        },
+        _ => unreachable!("Only paths pass check_update (Eval Update)")
+    }
+    Ok(())
+}
+
+/**
+ * Updates a value of an array or object, sending the result through a new channel of the container.
+ * A Skipped value is passed along as it is
+ */
+fn update_entry(acessor: &Acessor, update: &Update, value_packet: JC, sender: &mpsc::Sender<JC>) -> Result<(), EvalError> {
+    match value_packet {
+        JC::Stream(value_stream) => {
+            let (value_sender, value_receiver) = mpsc::channel::<JC>();
+            sender.send(JC::Stream(value_receiver)).unwrap();
+            update_value(acessor, update, value_stream, value_sender)
+        },
+        skipped => {
+            sender.send(skipped).unwrap();
+            Err(EvalError::InvalidArgument(String::from("Values skipped by a projection cannot be updated")))
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod update_tests {
+    use super::*;
+
+    fn update(query: &str, update: Update, json: &Json) -> Result<Json, EvalError> {
+        let acessor: Acessor = query.parse().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        eval_update(&acessor, &update, jc_stream(json), sender)?;
+        Ok(deserialise_json(receiver))
+    }
+
+    fn profiles() -> Json {
+        json!({"name": "Jason", "profiles": [{"name": "Twitter", "link": "a"}, {"name": "Facebook", "link": "b"}]})
+    }
+
+    #[test]
+    fn set_changes_only_the_targeted_values() {
+        let updated = update(r#"."profiles"[]."link""#, Update::Set(json!("<redacted>")), &profiles()).unwrap();
+        assert_eq!(updated, json!({"name": "Jason", "profiles": [{"name": "Twitter", "link": "<redacted>"}, {"name": "Facebook", "link": "<redacted>"}]}));
+        let added = update(r#"."age""#, Update::Set(json!(31)), &profiles()).unwrap();
+        assert_eq!(added["age"], json!(31));
+        assert_eq!(update("[5]", Update::Set(json!(1)), &json!([0])).unwrap(), json!([0]));
+    }
+
+    #[test]
+    fn delete_rename_and_apply() {
+        assert_eq!(update(r#"."profiles""#, Update::Delete, &profiles()).unwrap(), json!({"name": "Jason"}));
+        assert_eq!(update(r#"."profiles"[0]"#, Update::Delete, &profiles()).unwrap(), json!({"name": "Jason", "profiles": [{"name": "Facebook", "link": "b"}]}));
+        assert_eq!(update("[]", Update::Delete, &json!([1, 2])).unwrap(), json!([]));
+        let renamed = update(r#"."profiles"[1]."link""#, Update::Rename(String::from("url")), &profiles()).unwrap();
+        assert_eq!(renamed, json!({"name": "Jason", "profiles": [{"name": "Twitter", "link": "a"}, {"name": "Facebook", "url": "b"}]}));
+        let upper = Update::Apply(Box::new(|name: Json| Json::String(name.as_str().unwrap_or_default().to_uppercase())));
+        assert_eq!(update(r#"."name""#, upper, &profiles()).unwrap(), json!({"name": "JASON", "profiles": [{"name": "Twitter", "link": "a"}, {"name": "Facebook", "link": "b"}]}));
+    }
+
+    #[test]
+    fn invalid_updates_are_errors() {
+        assert!(matches!(update(r#"."profiles"[0]"#, Update::Rename(String::from("x")), &profiles()), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(update(r#"."profiles"[]"#, Update::Rename(String::from("x")), &profiles()), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(update(".", Update::Delete, &profiles()), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(update(r#"{ a: ."name" }"#, Update::Delete, &profiles()), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(update(r#"."name"[0]"#, Update::Set(Json::Null), &profiles()), Err(EvalError::TypeMismatch(_))));
+        assert!(matches!(update(r#"."profiles"[]."name"."x""#, Update::Delete, &profiles()), Err(EvalError::TypeMismatch(_))));
+    }
+
+    #[test]
+    fn values_that_do_not_fit_the_path_are_sent_unchanged() {
+        let json = json!({"profiles": [{"link": "a"}, 5, {"link": "c"}], "name": "Jason"});
+        for (query, expected) in [
+            (r#"."profiles"[]."link""#, json!({"profiles": [{"link": "x"}, 5, {"link": "c"}], "name": "Jason"})),
+            (r#"."profiles"[1]."link""#, json.clone()),
+            (r#"."name"."first""#, json.clone()),
+            (r#"[0]"#, json.clone()),
+        ] {
+            let acessor: Acessor = query.parse().unwrap();
+            let (sender, receiver) = mpsc::channel();
+            let result = eval_update(&acessor, &Update::Set(json!("x")), jc_stream(&json), sender);
+            assert!(matches!(result, Err(EvalError::TypeMismatch(_))), "{}", query);
+            assert_eq!(deserialise_json(receiver), expected, "{}", query);
+        }
+    }
+}
+
+
+/**
+ * Json Patch (RFC 6902)
+ * Paths are Json Pointers (RFC 6901), e.g. "/socialProfiles/0/name", where "~1" stands for "/" and "~0" for "~"
+ */
+#[derive(Debug, Clone, PartialEq)]
+enum PatchOperation {
+    Add { path: String, value: Json },
+    Remove { path: String },
+    Replace { path: String, value: Json },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    // Compares with the equality of Json values, so -0.0 passes a test for 0.0 and NaN passes a test for NaN,
+    // where RFC 6902 leaves numbers to the json equality of their values
+    Test { path: String, value: Json },
+}
+
+type Patch = Vec<PatchOperation>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum PatchError {
+    InvalidPointer(String),   // The pointer does not follow RFC 6901
+    PathNotFound(String),     // The pointer does not reference an existing value
+    InvalidOperation(String), // The patch document is malformed or the operation cannot be applied
+    TestFailed(String),       // A test operation found a different value
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::InvalidPointer(pointer) => write!(f, "invalid json pointer \"{}\"", pointer),
+            PatchError::PathNotFound(pointer) => write!(f, "path \"{}\" does not exist", pointer),
+            PatchError::InvalidOperation(message) => write!(f, "invalid patch operation: {}", message),
+            PatchError::TestFailed(pointer) => write!(f, "test failed at path \"{}\"", pointer),
+        }
+    }
+}
+
+
+/**
+ * Splits a json pointer into its unescaped reference tokens
+ */
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, PatchError> {
+    if pointer.is_empty() {
+        return Ok(vec![]); // The empty pointer references the whole document
+    }
+    if !pointer.starts_with('/') {
+        return Err(PatchError::InvalidPointer(pointer.to_string()));
+    }
+    let mut tokens = vec![];
+    for raw_token in pointer[1..].split('/') {
+        // Escapes are decoded in a single pass so that "~01" becomes "~1" and not "/"
+        let mut token = String::new();
+        let mut chars = raw_token.chars();
+        while let Some(c) = chars.next() {
+            if c == '~' {
+                match chars.next() {
+                    Some('0') => token.push('~'),
+                    Some('1') => token.push('/'),
+                    _ => return Err(PatchError::InvalidPointer(pointer.to_string())),
+                }
+            } else {
+                token.push(c);
+            }
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn escape_pointer_token(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+/**
+ * Parses an array index token, which must not have leading zeros
+ */
+fn parse_array_index(token: &str, pointer: &str) -> Result<usize, PatchError> {
+    if token.is_empty() || !token.chars().all(|c| c.is_ascii_digit()) || (token.len() > 1 && token.starts_with('0')) {
+        return Err(PatchError::InvalidPointer(pointer.to_string()));
+    }
+    token.parse::<usize>().map_err(|_| PatchError::InvalidPointer(pointer.to_string()))
+}
+
+fn pointer_get<'a>(json: &'a Json, tokens: &[String], pointer: &str) -> Result<&'a Json, PatchError> {
+    let mut current = json;
+    for token in tokens {
+        current = match current {
+            Json::Object(map) => map.get(token),
+            Json::Array(array) => array.get(parse_array_index(token, pointer)?),
+            _ => None,
+        }.ok_or_else(|| PatchError::PathNotFound(pointer.to_string()))?;
+    }
+    Ok(current)
+}
+
+fn pointer_get_mut<'a>(json: &'a mut Json, tokens: &[String], pointer: &str) -> Result<&'a mut Json, PatchError> {
+    let mut current = json;
+    for token in tokens {
+        current = match current {
+            Json::Object(map) => map.get_mut(token),
+            Json::Array(array) => array.get_mut(parse_array_index(token, pointer)?),
+            _ => None,
+        }.ok_or_else(|| PatchError::PathNotFound(pointer.to_string()))?;
+    }
+    Ok(current)
+}
+
+fn patch_add(json: &mut Json, pointer: &str, value: Json) -> Result<(), PatchError> {
+    let tokens = parse_pointer(pointer)?;
+    let (last, parent_tokens) = match tokens.split_last() {
+        Some(split) => split,
+        None => {
+            *json = value; // Adding to the root replaces the whole document
+            return Ok(());
+        }
+    };
+    match pointer_get_mut(json, parent_tokens, pointer)? {
+        Json::Object(map) => {
+            map.insert(last.to_string(), value);
+            Ok(())
+        },
+        Json::Array(array) => {
+            if last == "-" {
+                array.push(value); // "-" references the position after the last value
+                return Ok(());
+            }
+            let index = parse_array_index(last, pointer)?;
+            if index > array.len() {
+                return Err(PatchError::PathNotFound(pointer.to_string()));
+            }
+            array.insert(index, value);
+            Ok(())
+        },
+        _ => Err(PatchError::PathNotFound(pointer.to_string())),
+    }
+}
+
+fn patch_remove(json: &mut Json, pointer: &str) -> Result<Json, PatchError> {
+    let tokens = parse_pointer(pointer)?;
+    let (last, parent_tokens) = match tokens.split_last() {
+        Some(split) => split,
+        None => return Err(PatchError::InvalidOperation("cannot remove the whole document".to_string())),
+    };
+    match pointer_get_mut(json, parent_tokens, pointer)? {
+        Json::Object(map) => map.remove(last).ok_or_else(|| PatchError::PathNotFound(pointer.to_string())),
+        Json::Array(array) => {
+            let index = parse_array_index(last, pointer)?;
+            if index >= array.len() {
+                return Err(PatchError::PathNotFound(pointer.to_string()));
+            }
+            Ok(array.remove(index))
+        },
+        _ => Err(PatchError::PathNotFound(pointer.to_string())),
+    }
+}
+
+fn apply_operation(json: &mut Json, operation: &PatchOperation) -> Result<(), PatchError> {
+    match operation {
+        PatchOperation::Add { path, value } => patch_add(json, path, value.clone()),
+        PatchOperation::Remove { path } => patch_remove(json, path).map(|_| ()),
+        PatchOperation::Replace { path, value } => {
+            let target = pointer_get_mut(json, &parse_pointer(path)?, path)?;
+            *target = value.clone();
+            Ok(())
+        },
+        PatchOperation::Move { from, path } => {
+            // A value cannot be moved into one of its own children
+            if path != from && path.starts_with(from.as_str()) && path[from.len()..].starts_with('/') {
+                return Err(PatchError::InvalidOperation(format!("cannot move \"{}\" into \"{}\"", from, path)));
+            }
+            let value = patch_remove(json, from)?;
+            patch_add(json, path, value)
+        },
+        PatchOperation::Copy { from, path } => {
+            let value = pointer_get(json, &parse_pointer(from)?, from)?.clone();
+            patch_add(json, path, value)
+        },
+        PatchOperation::Test { path, value } => {
+            if pointer_get(json, &parse_pointer(path)?, path)? == value {
+                Ok(())
+            } else {
+                Err(PatchError::TestFailed(path.to_string()))
+            }
+        },
+    }
+}
+
+/**
+ * Applies every operation of the patch in order. The patch is applied atomically, if any operation fails
+ * the json is left unchanged and the error of the failing operation is returned
+ */
+fn apply_patch(json: &mut Json, patch: &Patch) -> Result<(), PatchError> {
+    let mut patched = json.clone();
+    for operation in patch {
+        apply_operation(&mut patched, operation)?;
+    }
+    *json = patched;
+    Ok(())
+}
+
+
+/**
+ * Reads a patch from its json representation, an array of operation objects such as
+ * [{ "op": "replace", "path": "/age", "value": 32 }]
+ */
+fn patch_from_json(json: &Json) -> Result<Patch, PatchError> {
+    let operations = match json {
+        Json::Array(operations) => operations,
+        _ => return Err(PatchError::InvalidOperation("a patch must be an array of operations".to_string())),
+    };
+    let mut patch = vec![];
+    for operation in operations {
+        let fields = match operation {
+            Json::Object(fields) => fields,
+            _ => return Err(PatchError::InvalidOperation("an operation must be an object".to_string())),
+        };
+        let string_field = |name: &str| match fields.get(name) {
+            Some(Json::String(s)) => Ok(s.to_string()),
+            _ => Err(PatchError::InvalidOperation(format!("missing string member \"{}\"", name))),
+        };
+        let value_field = || fields.get("value").cloned()
+            .ok_or_else(|| PatchError::InvalidOperation("missing member \"value\"".to_string()));
+        let path = string_field("path")?;
+        patch.push(match string_field("op")?.as_str() {
+            "add" => PatchOperation::Add { path, value: value_field()? },
+            "remove" => PatchOperation::Remove { path },
+            "replace" => PatchOperation::Replace { path, value: value_field()? },
+            "move" => PatchOperation::Move { from: string_field("from")?, path },
+            "copy" => PatchOperation::Copy { from: string_field("from")?, path },
+            "test" => PatchOperation::Test { path, value: value_field()? },
+            op => return Err(PatchError::InvalidOperation(format!("unknown operation \"{}\"", op))),
+        });
+    }
+    Ok(patch)
+}
+
+fn patch_to_json(patch: &Patch) -> Json {
+    Json::Array(patch.iter().map(|operation| {
+        let string = |s: &str| Json::String(s.to_string());
+        let fields = match operation {
+            PatchOperation::Add { path, value } => vec![("op", string("add")), ("path", string(path)), ("value", value.clone())],
+            PatchOperation::Remove { path } => vec![("op", string("remove")), ("path", string(path))],
+            PatchOperation::Replace { path, value } => vec![("op", string("replace")), ("path", string(path)), ("value", value.clone())],
+            PatchOperation::Move { from, path } => vec![("op", string("move")), ("from", string(from)), ("path", string(path))],
+            PatchOperation::Copy { from, path } => vec![("op", string("copy")), ("from", string(from)), ("path", string(path))],
+            PatchOperation::Test { path, value } => vec![("op", string("test")), ("path", string(path)), ("value", value.clone())],
+        };
+        Json::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }).collect())
+}
+
+
+/**
+ * Generates a patch that turns the source json into the target json
+ * Objects are compared field by field and arrays with an edit distance over their values, so the patch has the
+ * fewest operations that remove, add or change values in place, and only the values that actually changed end up in it.
+ * Values are compared with the equality of Json, so a change from 0.0 to -0.0 is not in the patch
+ */
+fn diff(source: &Json, target: &Json) -> Patch {
+    let mut patch = vec![];
+    diff_values(source, target, "", &mut patch);
+    patch
+}
+
+fn diff_values(source: &Json, target: &Json, pointer: &str, patch: &mut Patch) {
+    if source == target {
+        return;
+    }
+    match (source, target) {
+        (Json::Object(source_map), Json::Object(target_map)) => {
+            for (key, source_value) in source_map {
+                let field_pointer = format!("{}/{}", pointer, escape_pointer_token(key));
+                match target_map.get(key) {
+                    Some(target_value) => diff_values(source_value, target_value, &field_pointer, patch),
+                    None => patch.push(PatchOperation::Remove { path: field_pointer }),
+                }
+            }
+            for (key, target_value) in target_map {
+                if !source_map.contains_key(key) {
+                    let field_pointer = format!("{}/{}", pointer, escape_pointer_token(key));
+                    patch.push(PatchOperation::Add { path: field_pointer, value: target_value.clone() });
+                }
+            }
+        },
+        (Json::Array(source_array), Json::Array(target_array)) => diff_arrays(source_array, target_array, pointer, patch),
+        _ => patch.push(PatchOperation::Replace { path: pointer.to_string(), value: target.clone() }),
+    }
+}
+
+/**
+ * Most cells of the edit distance table of diff_arrays, 2 million cells of two numbers each, so 32 MB on 64 bit
+ * targets. Larger arrays are diffed by position instead
+ */
+const DIFF_TABLE_LIMIT: usize = 1 << 21;
+
+/**
+ * Diffs two arrays with the smallest number of operations, using an edit distance table where a value is removed,
+ * added, or diffed in place. The table has a cell for each pair of source and target values, so when the arrays are
+ * too large for DIFF_TABLE_LIMIT the values are paired by position instead, which can give a longer patch
+ */
+fn diff_arrays(source: &[Json], target: &[Json], pointer: &str, patch: &mut Patch) {
+    // The values shared at the start and end of both arrays are left alone
+    let prefix = source.iter().zip(target).take_while(|(s, t)| s == t).count();
+    let suffix = source[prefix..].iter().rev().zip(target[prefix..].iter().rev()).take_while(|(s, t)| s == t).count();
+    let (source, target) = (&source[prefix..source.len() - suffix], &target[prefix..target.len() - suffix]);
+
+    let width = target.len() + 1;
+    if (source.len() + 1).saturating_mul(width) > DIFF_TABLE_LIMIT {
+        // The shared positions are diffed in place, then the extra source values are removed or the extra target
+        // values added after them
+        let shared = source.len().min(target.len());
+        for (k, (s, t)) in source.iter().zip(target).enumerate() {
+            diff_values(s, t, &format!("{}/{}", pointer, prefix + k), patch);
+        }
+        for _ in shared..source.len() {
+            patch.push(PatchOperation::Remove { path: format!("{}/{}", pointer, prefix + shared) });
+        }
+        for (k, value) in target[shared..].iter().enumerate() {
+            patch.push(PatchOperation::Add { path: format!("{}/{}", pointer, prefix + shared + k), value: value.clone() });
+        }
+        return;
+    }
+
+    // The cell of (i, j) holds the smallest number of operations that turns source[i..] into target[j..], and the
+    // number of operations of diffing source[i] and target[j] in place, which the walk below reads again
+    let mut table = vec![(0usize, 0usize); (source.len() + 1) * width];
+    for i in (0..=source.len()).rev() {
+        for j in (0..=target.len()).rev() {
+            table[i * width + j] = match (i < source.len(), j < target.len()) {
+                (false, false) => (0, 0),
+                (true, false) => (table[(i + 1) * width + j].0 + 1, 0),
+                (false, true) => (table[i * width + j + 1].0 + 1, 0),
+                (true, true) => {
+                    let mut operations = vec![];
+                    diff_values(&source[i], &target[j], "", &mut operations);
+                    let cost = (table[(i + 1) * width + j].0 + 1)
+                        .min(table[i * width + j + 1].0 + 1)
+                        .min(table[(i + 1) * width + j + 1].0 + operations.len());
+                    (cost, operations.len())
+                },
+            };
+        }
+    }
+
+    // Follow the cheapest choices from the start, keeping track of the index in the array as it is after
+    // applying the operations so far
+    let (mut i, mut j) = (0, 0);
+    let mut position = prefix;
+    while i < source.len() || j < target.len() {
+        let (cost, in_place_cost) = table[i * width + j];
+        if i < source.len() && j < target.len() && cost == table[(i + 1) * width + j + 1].0 + in_place_cost {
+            diff_values(&source[i], &target[j], &format!("{}/{}", pointer, position), patch);
+            i += 1;
+            j += 1;
+            position += 1;
+        } else if i < source.len() && cost == table[(i + 1) * width + j].0 + 1 {
+            patch.push(PatchOperation::Remove { path: format!("{}/{}", pointer, position) });
+            i += 1;
+        } else {
+            patch.push(PatchOperation::Add { path: format!("{}/{}", pointer, position), value: target[j].clone() });
+            j += 1;
+            position += 1;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod patch_tests {
+    use super::*;
+
+    // Appendix A of RFC 6902, as (document, patch, result) where a None result means the patch fails
+    fn rfc_examples() -> Vec<(Json, Json, Option<Json>)> {
+        vec![
+            (json!({"foo": "bar"}), json!([{"op": "add", "path": "/baz", "value": "qux"}]), Some(json!({"baz": "qux", "foo": "bar"}))),
+            (json!({"foo": ["bar", "baz"]}), json!([{"op": "add", "path": "/foo/1", "value": "qux"}]), Some(json!({"foo": ["bar", "qux", "baz"]}))),
+            (json!({"baz": "qux", "foo": "bar"}), json!([{"op": "remove", "path": "/baz"}]), Some(json!({"foo": "bar"}))),
+            (json!({"foo": ["bar", "qux", "baz"]}), json!([{"op": "remove", "path": "/foo/1"}]), Some(json!({"foo": ["bar", "baz"]}))),
+            (json!({"baz": "qux", "foo": "bar"}), json!([{"op": "replace", "path": "/baz", "value": "boo"}]), Some(json!({"baz": "boo", "foo": "bar"}))),
+            (
+                json!({"foo": {"bar": "baz", "waldo": "fred"}, "qux": {"corge": "grault"}}),
+                json!([{"op": "move", "from": "/foo/waldo", "path": "/qux/thud"}]),
+                Some(json!({"foo": {"bar": "baz"}, "qux": {"corge": "grault", "thud": "fred"}})),
+            ),
+            (json!({"foo": ["all", "grass", "cows", "eat"]}), json!([{"op": "move", "from": "/foo/1", "path": "/foo/3"}]), Some(json!({"foo": ["all", "cows", "eat", "grass"]}))),
+            (
+                json!({"baz": "qux", "foo": ["a", 2, "c"]}),
+                json!([{"op": "test", "path": "/baz", "value": "qux"}, {"op": "test", "path": "/foo/1", "value": 2}]),
+                Some(json!({"baz": "qux", "foo": ["a", 2, "c"]})),
+            ),
+            (json!({"baz": "qux"}), json!([{"op": "test", "path": "/baz", "value": "bar"}]), None),
+            (json!({"foo": "bar"}), json!([{"op": "add", "path": "/child", "value": {"grandchild": {}}}]), Some(json!({"foo": "bar", "child": {"grandchild": {}}}))),
+            (json!({"foo": "bar"}), json!([{"op": "add", "path": "/baz", "value": "qux", "xyz": 123}]), Some(json!({"foo": "bar", "baz": "qux"}))),
+            (json!({"foo": "bar"}), json!([{"op": "add", "path": "/baz/bat", "value": "qux"}]), None),
+            // A.13 has a duplicate "op" member, which a Json object can not hold
+            (json!({"/": 9, "~1": 10}), json!([{"op": "test", "path": "/~01", "value": 10}]), Some(json!({"/": 9, "~1": 10}))),
+            (json!({"/": 9, "~1": 10}), json!([{"op": "test", "path": "/~01", "value": "10"}]), None),
+            (json!({"foo": ["bar"]}), json!([{"op": "add", "path": "/foo/-", "value": ["abc", "def"]}]), Some(json!({"foo": ["bar", ["abc", "def"]]}))),
+        ]
+    }
+
+    #[test]
+    fn rfc_6902_appendix_examples() {
+        for (document, patch_json, result) in rfc_examples() {
+            let patch = patch_from_json(&patch_json).unwrap();
+            assert_eq!(patch_from_json(&patch_to_json(&patch)).unwrap(), patch);
+            let mut patched = document.clone();
+            match result {
+                Some(expected) => {
+                    apply_patch(&mut patched, &patch).unwrap();
+                    assert_eq!(patched, expected, "{}", patch_json);
+                },
+                None => {
+                    assert!(apply_patch(&mut patched, &patch).is_err(), "{}", patch_json);
+                    assert_eq!(patched, document); // failed patches leave the document unchanged
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn diff_of_arrays_is_minimal() {
+        assert_eq!(diff(&json!([1, 2, 3]), &json!([1, 2, 3])), vec![]);
+        assert_eq!(diff(&json!([1, 2, 3, 4]), &json!([1, 3, 4])), vec![PatchOperation::Remove { path: String::from("/1") }]);
+        assert_eq!(diff(&json!([1, 3]), &json!([0, 1, 2, 3])), vec![
+            PatchOperation::Add { path: String::from("/0"), value: json!(0) },
+            PatchOperation::Add { path: String::from("/2"), value: json!(2) },
+        ]);
+        // A greedy walk pairs the 9 with the 1 and removes and adds the rest, an edit distance replaces one value
+        assert_eq!(diff(&json!([9, 1, 2]), &json!([1, 2, 8])).len(), 2);
+        assert_eq!(diff(&json!([{"a": 1, "b": 2}, 5]), &json!([{"a": 1, "b": 3}, 5])), vec![
+            PatchOperation::Replace { path: String::from("/0/b"), value: json!(3) },
+        ]);
+    }
+
+    #[test]
+    fn applying_the_diff_gives_the_target() {
+        let mut rng = TestRng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..2000 {
+            let (source, target) = (rng.json(4), rng.json(4));
+            let patch = diff(&source, &target);
+            let mut patched = source.clone();
+            apply_patch(&mut patched, &patch).unwrap_or_else(|error| panic!("{} on {} -> {}", error, source, target));
+            assert_eq!(patched, target, "{:?}", patch);
+        }
+    }
+
+    #[test]
+    fn large_arrays_are_diffed_by_position() {
+        // 3000 by 3000 values is over DIFF_TABLE_LIMIT, so the values are paired by position
+        let source: Vec<Json> = (0..3000).map(|n| json!({"id": n})).collect();
+        let mut target = source.clone();
+        target[1500] = json!({"id": -1});
+        target.push(json!(3000));
+        target.remove(10);
+        let patch = diff(&Json::Array(source.clone()), &Json::Array(target.clone()));
+        let mut patched = Json::Array(source.clone());
+        apply_patch(&mut patched, &patch).unwrap();
+        assert_eq!(patched, Json::Array(target));
+        let shorter = Json::Array(source[..2000].to_vec());
+        let patch = diff(&Json::Array(source), &shorter);
+        assert_eq!(patch.len(), 1000);
+        assert!(patch.iter().all(|operation| *operation == PatchOperation::Remove { path: String::from("/2000") }));
+    }
+}
+
+
+impl Json {
+    /**
+     * Json Merge Patch (RFC 7396)
+     * Objects in the patch are merged recursively into this value, null fields of the patch delete the
+     * field and any other value replaces this one
+     */
+    fn merge_patch(&mut self, patch: &Json) {
+        match patch {
+            Json::Object(patch_map) => {
+                if let Json::Object(_) = self {} else {
+                    *self = Json::Object(BTreeMap::new()); // merging an object into a non object starts from {}
+                }
+                if let Json::Object(map) = self {
+                    for (key, patch_value) in patch_map {
+                        match patch_value {
+                            Json::Null => {
+                                map.remove(key);
+                            },
+                            _ => map.entry(key.to_string()).or_insert(Json::Null).merge_patch(patch_value),
+                        }
+                    }
+                }
+            },
+            _ => *self = patch.clone(),
+        }
+    }
+}
+
+
+/**
+ * Streaming version of Json::merge_patch. The base json is received through the receiver channel and the
+ * merged json is sent through the sender channel, following the usual protocols.
+ * Only the parts of the base that are touched by the patch are read, every other value is passed along
+ * through its original stream, so the base is never fully deserialised
+ */
+fn merge_patch_stream(patch: &Json, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) {
+    let patch_map = match patch {
+        Json::Object(patch_map) => patch_map,
+        _ => {
+            // Anything that is not an object replaces the base
+            consume_value(receiver);
+            serialise_json(patch, sender);
+            return;
+        }
+    };
+
+    // Only the stream endpoints of the base fields are kept, since the size of the merged object
+    // must be known before sending it
+    let mut fields: BTreeMap<String, Option<JC>> = match ValueTop::read(receiver) {
+        ValueTop::Object(base_fields) => base_fields.into_iter().map(|(key, value_packet)| (key, Some(value_packet))).collect(),
+        other => {
+            // The base is not an object, so the patch is merged into an empty object
+            other.consume();
+            let mut merged = Json::Null;
+            merged.merge_patch(patch);
+            serialise_json(&merged, sender);
+            return;
+        }
+    };
+
+    for (key, patch_value) in patch_map {
+        match patch_value {
+            Json::Null => {
+                if let Some(Some(value_packet)) = fields.remove(key) {
+                    consume_packet(value_packet);
+                }
+            },
+            _ => {
+                if !fields.contains_key(key) {
+                    fields.insert(key.to_string(), None); // field that only exists in the patch
+                }
+            }
+        }
+    }
+
+    sender.send(JC::ObjectStart).unwrap();
+    sender.send(JC::ArrayLen(fields.len())).unwrap();
+    for (key, base_value) in fields {
+        let patch_value = patch_map.get(&key);
+        sender.send(JC::String(key)).unwrap();
+        match (patch_value, base_value) {
+            (None, Some(value_packet)) => sender.send(value_packet).unwrap(), // untouched field
+            (Some(patch_value), base_value) => {
+                let (value_sender, value_receiver) = mpsc::channel::<JC>();
+                sender.send(JC::Stream(value_receiver)).unwrap();
+                match base_value {
+                    Some(JC::Stream(value_stream)) => merge_patch_stream(patch_value, value_stream, value_sender),
+                    _ => {
+                        // Fields that are missing or were skipped are merged like a null base
+                        let mut merged = Json::Null;
+                        merged.merge_patch(patch_value);
+                        serialise_json(&merged, value_sender);
+                    }
+                }
+            },
+            (None, None) => unreachable!(),
+        }
+    }
+    sender.send(JC::ObjectEnd).unwrap();
+}
+
+#[cfg(test)]
+mod merge_patch_tests {
+    use super::*;
+
+    #[test]
+    fn rfc_7396_appendix_examples() {
+        let examples = vec![
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "b"}), json!({"b": "c"}), json!({"a": "b", "b": "c"})),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (json!({"a": "b", "b": "c"}), json!({"a": null}), json!({"b": "c"})),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (json!({"a": {"b": "c"}}), json!({"a": {"b": "d", "c": null}}), json!({"a": {"b": "d"}})),
+            (json!({"a": [{"b": "c"}]}), json!({"a": [1]}), json!({"a": [1]})),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (json!({"e": null}), json!({"a": 1}), json!({"e": null, "a": 1})),
+            (json!([1, 2]), json!({"a": "b", "c": null}), json!({"a": "b"})),
+            (json!({}), json!({"a": {"bb": {"ccc": null}}}), json!({"a": {"bb": {}}})),
+        ];
+        for (base, patch, expected) in examples {
+            let mut merged = base.clone();
+            merged.merge_patch(&patch);
+            assert_eq!(merged, expected, "{} merged with {}", base, patch);
+
+            let (sender, receiver) = mpsc::channel();
+            merge_patch_stream(&patch, jc_stream(&base), sender);
+            assert_eq!(deserialise_json(receiver), expected, "{} merged with {} as a stream", base, patch);
+        }
+    }
+}
+
+
+/**
+ * A step in the path to a value inside a json, used to build the acessor that reaches that value
+ */
+#[derive(Debug, Clone)]
+enum PathStep {
+    Field(String),
+    Index(usize),
+}
+
+fn path_acessor(path: &[PathStep]) -> Acessor {
+    path.iter().rev().fold(Acessor::End, |next_acessor, step| match step {
+        PathStep::Field(label) => Acessor::ObjectField(label.to_string(), Box::new(next_acessor)),
+        PathStep::Index(index) => Acessor::ArrayEntry(*index, Box::new(next_acessor)),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiffKind {
+    Added,
+    Removed,
+    Changed,
+    TypeChanged, // The value changed to a different kind of json value, e.g. a string to a number
+}
+
+/**
+ * A single difference between two json values. The path is the acessor that reaches the value,
+ * old_value is missing for added values and new_value is missing for removed ones
+ */
+#[derive(Debug)]
+struct Difference {
+    path: Acessor,
+    kind: DiffKind,
+    old_value: Option<Json>,
+    new_value: Option<Json>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.old_value, &self.new_value) {
+            (Some(old_value), Some(new_value)) => {
+                let kind = if self.kind == DiffKind::TypeChanged { "type changed" } else { "changed" };
+                write!(f, "{} {}: {} -> {}", kind, self.path, old_value, new_value)
+            },
+            (None, Some(new_value)) => write!(f, "added {}: {}", self.path, new_value),
+            (Some(old_value), None) => write!(f, "removed {}: {}", self.path, old_value),
+            (None, None) => write!(f, "{:?} {}", self.kind, self.path),
+        }
+    }
+}
+
+struct DiffOptions {
+    ignore_array_order: bool, // Arrays are compared as multisets of values
+    numeric_tolerance: f64,   // Numbers whose absolute difference is at most this are considered equal
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions { ignore_array_order: false, numeric_tolerance: 0.0 }
+    }
+}
+
+
+/**
+ * Lists every difference between two json values, compared structurally
+ */
+fn json_diff(a: &Json, b: &Json) -> Vec<Difference> {
+    json_diff_with(a, b, &DiffOptions::default())
+}
+
+/**
+ * Same as json_diff, but arrays can be compared without taking order into account and numbers can be
+ * compared with some tolerance.
+ * When ignoring order, the values of both arrays are first paired with equivalent values of the other array, pairing
+ * as many values as possible.
+ * The values that are left are then compared in order, and the paths of the differences found use the
+ * indexes of the first array, except for added values which use the indexes of the second one
+ */
+fn json_diff_with(a: &Json, b: &Json, options: &DiffOptions) -> Vec<Difference> {
+    let mut differences = vec![];
+    diff_json_values(a, b, options, &mut vec![], &mut differences);
+    differences
+}
+
+fn same_json_type(a: &Json, b: &Json) -> bool {
+    matches!((a, b),
+        (Json::Number(_), Json::Number(_))
+        | (Json::String(_), Json::String(_))
+        | (Json::Boolean(_), Json::Boolean(_))
+        | (Json::Array(_), Json::Array(_))
+        | (Json::Object(_), Json::Object(_))
+        | (Json::Null, Json::Null))
+}
+
+/**
+ * Equality that follows the diff options
+ */
+fn json_equivalent(a: &Json, b: &Json, options: &DiffOptions) -> bool {
+    match (a, b) {
+        // Json equality first, so NaN is equivalent to NaN as it is equal to it, and the tolerance for the rest
+        (Json::Number(x), Json::Number(y)) => a == b || (x - y).abs() <= options.numeric_tolerance,
+        (Json::Array(xs), Json::Array(ys)) => {
+            if xs.len() != ys.len() {
+                return false;
+            }
+            if !options.ignore_array_order {
+                return xs.iter().zip(ys).all(|(x, y)| json_equivalent(x, y, options));
+            }
+            match_equivalent(xs, ys, options).iter().all(Option::is_some)
+        },
+        (Json::Object(xs), Json::Object(ys)) => {
+            xs.len() == ys.len() && xs.iter().all(|(key, x)| ys.get(key).is_some_and(|y| json_equivalent(x, y, options)))
+        },
+        _ => a == b,
+    }
+}
+
+/**
+ * Pairs the values of xs with equivalent values of ys, each value being used at most once, so that as many values
+ * as possible are paired. A value that is equivalent to several others (e.g. with a numeric tolerance) is not
+ * just paired with the first one, which could leave another value without a pair it needed.
+ * Returns the index in ys paired with each value of xs
+ */
+fn match_equivalent(xs: &[Json], ys: &[Json], options: &DiffOptions) -> Vec<Option<usize>> {
+    let candidates: Vec<Vec<usize>> = xs.iter()
+        .map(|x| (0..ys.len()).filter(|&j| json_equivalent(x, &ys[j], options)).collect())
+        .collect();
+    let mut pair_of_y: Vec<Option<usize>> = vec![None; ys.len()];
+    for i in 0..xs.len() {
+        let mut visited = vec![false; ys.len()];
+        augment_matching(i, &candidates, &mut visited, &mut pair_of_y);
+    }
+    let mut pair_of_x = vec![None; xs.len()];
+    for (j, pair) in pair_of_y.iter().enumerate() {
+        if let Some(i) = pair {
+            pair_of_x[*i] = Some(j);
+        }
+    }
+    pair_of_x
+}
+
+/**
+ * Looks for a free value of ys for xs[i], moving the values already paired to other candidates when needed
+ */
+fn augment_matching(i: usize, candidates: &[Vec<usize>], visited: &mut [bool], pair_of_y: &mut [Option<usize>]) -> bool {
+    for &j in &candidates[i] {
+        if visited[j] {
+            continue;
+        }
+        visited[j] = true;
+        if pair_of_y[j].is_none_or(|other| augment_matching(other, candidates, visited, pair_of_y)) {
+            pair_of_y[j] = Some(i);
+            return true;
+        }
+    }
+    false
+}
+
+fn diff_json_values(a: &Json, b: &Json, options: &DiffOptions, path: &mut Vec<PathStep>, differences: &mut Vec<Difference>) {
+    if json_equivalent(a, b, options) {
+        return;
+    }
+    match (a, b) {
+        (Json::Object(a_map), Json::Object(b_map)) => {
+            for (key, a_value) in a_map {
+                path.push(PathStep::Field(key.to_string()));
+                match b_map.get(key) {
+                    Some(b_value) => diff_json_values(a_value, b_value, options, path, differences),
+                    None => differences.push(Difference {
+                        path: path_acessor(path), kind: DiffKind::Removed, old_value: Some(a_value.clone()), new_value: None,
+                    }),
+                }
+                path.pop();
+            }
+            for (key, b_value) in b_map {
+                if !a_map.contains_key(key) {
+                    path.push(PathStep::Field(key.to_string()));
+                    differences.push(Difference {
+                        path: path_acessor(path), kind: DiffKind::Added, old_value: None, new_value: Some(b_value.clone()),
+                    });
+                    path.pop();
+                }
+            }
+        },
+        (Json::Array(a_array), Json::Array(b_array)) => {
+            // Indexes of the values that are compared in order
+            let (a_left, b_left): (Vec<usize>, Vec<usize>) = if options.ignore_array_order {
+                let pairs = match_equivalent(a_array, b_array, options);
+                let mut b_matched = vec![false; b_array.len()];
+                pairs.iter().flatten().for_each(|&j| b_matched[j] = true);
+                let a_left = (0..a_array.len()).filter(|&i| pairs[i].is_none()).collect();
+                (a_left, (0..b_array.len()).filter(|&j| !b_matched[j]).collect())
+            } else {
+                ((0..a_array.len()).collect(), (0..b_array.len()).collect())
+            };
+            for (&i, &j) in a_left.iter().zip(&b_left) {
+                path.push(PathStep::Index(i));
+                diff_json_values(&a_array[i], &b_array[j], options, path, differences);
+                path.pop();
+            }
+            for &i in a_left.iter().skip(b_left.len()) {
+                path.push(PathStep::Index(i));
+                differences.push(Difference {
+                    path: path_acessor(path), kind: DiffKind::Removed, old_value: Some(a_array[i].clone()), new_value: None,
+                });
+                path.pop();
+            }
+            for &j in b_left.iter().skip(a_left.len()) {
+                path.push(PathStep::Index(j));
+                differences.push(Difference {
+                    path: path_acessor(path), kind: DiffKind::Added, old_value: None, new_value: Some(b_array[j].clone()),
+                });
+                path.pop();
+            }
+        },
+        _ => {
+            let kind = if same_json_type(a, b) { DiffKind::Changed } else { DiffKind::TypeChanged };
+            differences.push(Difference {
+                path: path_acessor(path), kind, old_value: Some(a.clone()), new_value: Some(b.clone()),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod json_diff_tests {
+    use super::*;
+
+    fn paths(differences: &[Difference]) -> Vec<String> {
+        differences.iter().map(|difference| difference.to_string()).collect()
+    }
+
+    #[test]
+    fn differences_have_readable_paths() {
+        let a = json!({"name": "Jason", "age": 31, "languages": ["Java", "Rust"]});
+        let b = json!({"name": "Jason", "age": "31", "languages": ["Java"], "city": "New York"});
+        assert_eq!(paths(&json_diff(&a, &b)), vec![
+            r#"type changed ."age": 31 -> "31""#,
+            r#"removed ."languages"[1]: "Rust""#,
+            r#"added ."city": "New York""#,
+        ]);
+        assert!(json_diff(&a, &a).is_empty());
+    }
+
+    #[test]
+    fn ignoring_order_pairs_as_many_values_as_possible() {
+        let options = DiffOptions { ignore_array_order: true, numeric_tolerance: 0.5 };
+        // Pairing 1.0 with the first value it is close to leaves 1.6 without a pair
+        assert!(json_diff_with(&json!([1.0, 1.6]), &json!([1.5, 0.8]), &options).is_empty());
+        assert!(json_diff_with(&json!([[1.0, 1.6], 3]), &json!([3, [1.5, 0.8]]), &options).is_empty());
+        assert_eq!(paths(&json_diff_with(&json!([1.0, 1.6, 7]), &json!([1.5, 0.8, 9]), &options)), vec!["changed [2]: 7 -> 9"]);
+        assert_eq!(json_diff_with(&json!([1, 2]), &json!([2, 1]), &DiffOptions::default()).len(), 2);
+    }
+
+    #[test]
+    fn numbers_equal_as_json_are_not_changed() {
+        let nan = json!({"x": f64::NAN, "values": [f64::NAN, 1.0]});
+        assert_eq!(nan, nan.clone());
+        assert!(json_diff(&nan, &nan.clone()).is_empty());
+        let options = DiffOptions { ignore_array_order: true, numeric_tolerance: 0.1 };
+        assert!(json_diff_with(&json!([1.0, f64::NAN]), &json!([f64::NAN, 1.05]), &options).is_empty());
+        assert_eq!(paths(&json_diff(&json!(f64::NAN), &json!(1))), vec!["changed .: null -> 1"]);
+    }
+}
+
+
+/**
+ * Small regular expression engine, used for the pattern keywords of json schemas.
+ * Supports literals, ., character classes ([a-z], [^0-9], \d, \w, \s and their negations), anchors (^ and $),
+ * groups (capturing, named with (?<name>...) and non capturing with (?:...)), alternation (|) and
+ * the quantifiers *, +, ?, {n}, {n,} and {n,m}, optionally lazy with a trailing ?
+ * Like json schema patterns, a regex matches if it matches anywhere in the text.
+ * Patterns are compiled to a program that runs on a Pike VM, which follows every possible match at once instead of
+ * backtracking, so matching takes time proportional to the size of the text times the size of the program
+ * and patterns like ^(a+)+$ can not take exponential time. Patterns whose program is too large, e.g. because of
+ * nested counted repetitions, are rejected, and matching stops with an error after REGEX_STEP_LIMIT steps
+ */
+#[derive(Debug, Clone)]
+struct Regex {
+    program: Vec<RegexInstruction>,
+    group_names: Vec<Option<String>>, // one entry for each capturing group, in order
+}
+
+const REGEX_PROGRAM_LIMIT: usize = 10_000;
+const REGEX_STEP_LIMIT: usize = 1 << 26;
+
+/**
+ * Instructions of the Pike VM. Char, Any and Class consume a character, the others are followed without consuming
+ * anything. Split continues at both targets, the first one having priority, which is how greedy and lazy
+ * quantifiers and the order of alternatives are kept
+ */
+#[derive(Debug, Clone)]
+enum RegexInstruction {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    Save(usize), // saves the position in a capture slot, two slots per group with the whole match as group 0
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+#[derive(Debug, Clone)]
+enum RegexNode {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool), // items of the class and whether it is negated
+    Start,
+    End,
+    Group(Box<RegexNode>, Option<usize>), // capturing groups carry their index
+    Concat(Vec<RegexNode>),
+    Alternate(Vec<RegexNode>),
+    Repeat(Box<RegexNode>, usize, Option<usize>, bool), // node, min, max and whether it is greedy
+}
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Range(char, char),
+    Digit(bool), // \d, or \D when true
+    Word(bool),  // \w, or \W when true
+    Space(bool), // \s, or \S when true
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            ClassItem::Range(low, high) => *low <= c && c <= *high,
+            ClassItem::Digit(negated) => c.is_ascii_digit() != *negated,
+            ClassItem::Word(negated) => (c.is_ascii_alphanumeric() || c == '_') != *negated,
+            ClassItem::Space(negated) => c.is_whitespace() != *negated,
+        }
+    }
+}
+
+struct RegexParser<'a> {
+    chars: &'a [char],
+    position: usize,
+    group_names: Vec<Option<String>>,
+}
+
+impl<'a> RegexParser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).cloned()
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = self.peek().ok_or_else(|| "unexpected end of pattern".to_string())?;
+        self.position += 1;
+        Ok(c)
+    }
+
+    fn parse_alternation(&mut self) -> Result<RegexNode, String> {
+        let mut branches = vec![self.parse_concat()?];
+        while self.peek() == Some('|') {
+            self.position += 1;
+            branches.push(self.parse_concat()?);
+        }
+        Ok(if branches.len() == 1 { branches.pop().unwrap() } else { RegexNode::Alternate(branches) })
+    }
+
+    fn parse_concat(&mut self) -> Result<RegexNode, String> {
+        let mut nodes = vec![];
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.parse_atom()?;
+            nodes.push(self.parse_quantifier(atom)?);
+        }
+        Ok(RegexNode::Concat(nodes))
+    }
+
+    fn parse_atom(&mut self) -> Result<RegexNode, String> {
+        match self.next()? {
+            '.' => Ok(RegexNode::Any),
+            '^' => Ok(RegexNode::Start),
+            '$' => Ok(RegexNode::End),
+            '(' => {
+                let mut index = Some(self.group_names.len());
+                if self.peek() == Some('?') {
+                    self.position += 1;
+                    match self.next()? {
+                        ':' => index = None,
+                        '<' => {
+                            let mut name = String::new();
+                            loop {
+                                match self.next()? {
+                                    '>' => break,
+                                    c => name.push(c),
+                                }
+                            }
+                            self.group_names.push(Some(name));
+                        },
+                        c => return Err(format!("unsupported group (?{}", c)),
+                    }
+                } else {
+                    self.group_names.push(None);
+                }
+                let node = self.parse_alternation()?;
+                match self.next()? {
+                    ')' => Ok(RegexNode::Group(Box::new(node), index)),
+                    _ => Err("expected )".to_string()),
+                }
+            },
+            '[' => self.parse_class(),
+            '\\' => self.parse_escape(),
+            c @ '*' | c @ '+' | c @ '?' | c @ '{' => Err(format!("nothing to repeat before {}", c)),
+            c => Ok(RegexNode::Char(c)),
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<RegexNode, String> {
+        Ok(match self.next()? {
+            'd' => RegexNode::Class(vec![ClassItem::Digit(false)], false),
+            'D' => RegexNode::Class(vec![ClassItem::Digit(true)], false),
+            'w' => RegexNode::Class(vec![ClassItem::Word(false)], false),
+            'W' => RegexNode::Class(vec![ClassItem::Word(true)], false),
+            's' => RegexNode::Class(vec![ClassItem::Space(false)], false),
+            'S' => RegexNode::Class(vec![ClassItem::Space(true)], false),
+            c => RegexNode::Char(escaped_char(c)),
+        })
+    }
+
+    fn parse_class(&mut self) -> Result<RegexNode, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.position += 1;
+        }
+        let mut items = vec![];
+        loop {
+            let c = self.next()?;
+            let low = match c {
+                ']' if !items.is_empty() => break,
+                '\\' => match self.next()? {
+                    'd' => { items.push(ClassItem::Digit(false)); continue },
+                    'D' => { items.push(ClassItem::Digit(true)); continue },
+                    'w' => { items.push(ClassItem::Word(false)); continue },
+                    'W' => { items.push(ClassItem::Word(true)); continue },
+                    's' => { items.push(ClassItem::Space(false)); continue },
+                    'S' => { items.push(ClassItem::Space(true)); continue },
+                    c => escaped_char(c),
+                },
+                c => c,
+            };
+            // A - between two characters is a range, anywhere else it is a literal -
+            if self.peek() == Some('-') && self.chars.get(self.position + 1).is_some_and(|&c| c != ']') {
+                self.position += 1;
+                let high = match self.next()? {
+                    '\\' => escaped_char(self.next()?),
+                    c => c,
+                };
+                if high < low {
+                    return Err(format!("invalid class range {}-{}", low, high));
+                }
+                items.push(ClassItem::Range(low, high));
+            } else {
+                items.push(ClassItem::Range(low, low));
+            }
+        }
+        Ok(RegexNode::Class(items, negated))
+    }
+
+    fn parse_quantifier(&mut self, atom: RegexNode) -> Result<RegexNode, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                // Braces that are not a valid quantifier are treated as literals
+                let start = self.position;
+                match self.parse_braces() {
+                    Some(bounds) => {
+                        self.position -= 1; // the closing brace is skipped below
+                        bounds
+                    },
+                    None => {
+                        self.position = start;
+                        return Ok(atom);
+                    }
+                }
+            },
+            _ => return Ok(atom),
+        };
+        self.position += 1;
+        let greedy = self.peek() != Some('?');
+        if !greedy {
+            self.position += 1;
+        }
+        if let Some(max) = max {
+            if max < min {
+                return Err(format!("invalid quantifier {{{},{}}}", min, max));
+            }
+        }
+        Ok(RegexNode::Repeat(Box::new(atom), min, max, greedy))
+    }
+
+    fn parse_braces(&mut self) -> Option<(usize, Option<usize>)> {
+        self.position += 1; // skip {
+        let read_number = |parser: &mut Self| {
+            let start = parser.position;
+            while parser.peek().is_some_and(|c| c.is_ascii_digit()) {
+                parser.position += 1;
+            }
+            parser.chars[start..parser.position].iter().collect::<String>().parse::<usize>().ok()
+        };
+        let min = read_number(self)?;
+        let max = if self.peek() == Some(',') {
+            self.position += 1;
+            if self.peek() == Some('}') { None } else { Some(read_number(self)?) }
+        } else {
+            Some(min)
+        };
+        if self.peek() != Some('}') {
+            return None;
+        }
+        self.position += 1;
+        Some((min, max))
+    }
+}
+
+fn escaped_char(c: char) -> char {
+    match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        'f' => '\u{c}',
+        'v' => '\u{b}',
+        '0' => '\0',
+        c => c,
+    }
+}
+
+impl Regex {
+    fn new(pattern: &str) -> Result<Regex, String> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut parser = RegexParser { chars: &chars, position: 0, group_names: vec![] };
+        let node = parser.parse_alternation()?;
+        if parser.position != chars.len() {
+            return Err("unmatched )".to_string());
+        }
+        let mut program = vec![RegexInstruction::Save(0)];
+        compile_regex(&node, &mut program)?;
+        program.push(RegexInstruction::Save(1));
+        program.push(RegexInstruction::Match);
+        Ok(Regex { program, group_names: parser.group_names })
+    }
+
+    fn is_match(&self, text: &str) -> Result<bool, String> {
+        Ok(self.captures(text)?.is_some())
+    }
+
+    /**
+     * Returns the text of the whole match followed by the text of each capturing group,
+     * groups that did not take part in the match are None.
+     * The match is the one a backtracking engine would find: the leftmost, then following the priorities of
+     * alternatives and quantifiers
+     */
+    fn captures(&self, text: &str) -> Result<Option<Vec<Option<String>>>, String> {
+        let chars: Vec<char> = text.chars().collect();
+        let slot_count = 2 * (self.group_names.len() + 1);
+        let mut threads = RegexThreads::new(self.program.len());
+        let mut next_threads = RegexThreads::new(self.program.len());
+        let mut matched: Option<Vec<Option<usize>>> = None;
+        let mut steps = 0;
+        for position in 0..=chars.len() {
+            // A thread starting at this position has the lowest priority, and none is needed once something matched
+            if matched.is_none() {
+                self.add_thread(&mut threads, 0, position, &chars, vec![None; slot_count], &mut steps)?;
+            }
+            if threads.list.is_empty() {
+                break;
+            }
+            next_threads.clear();
+            for (pc, slots) in threads.list.drain(..) {
+                let consumes = match &self.program[pc] {
+                    RegexInstruction::Char(c) => chars.get(position) == Some(c),
+                    RegexInstruction::Any => chars.get(position).is_some_and(|&c| c != '\n'),
+                    RegexInstruction::Class(items, negated) => {
+                        chars.get(position).is_some_and(|&c| items.iter().any(|item| item.matches(c)) != *negated)
+                    },
+                    RegexInstruction::Match => {
+                        // Threads after this one have lower priority, so they are dropped
+                        matched = Some(slots);
+                        break;
+                    },
+                    _ => unreachable!("Only instructions that consume characters are kept in the thread list (Regex)"),
+                };
+                if consumes {
+                    self.add_thread(&mut next_threads, pc + 1, position + 1, &chars, slots, &mut steps)?;
+                }
+            }
+            std::mem::swap(&mut threads, &mut next_threads);
+        }
+        Ok(matched.map(|slots| {
+            slots.chunks(2).map(|slot| match (slot[0], slot[1]) {
+                (Some(from), Some(to)) => Some(chars[from..to].iter().collect::<String>()),
+                _ => None,
+            }).collect()
+        }))
+    }
+
+    /**
+     * Adds the thread at pc to the list, following the instructions that do not consume characters.
+     * Uses an explicit stack so that long texts and large programs can not overflow the call stack
+     */
+    fn add_thread(&self, threads: &mut RegexThreads, pc: usize, position: usize, chars: &[char], slots: Vec<Option<usize>>,
+                  steps: &mut usize) -> Result<(), String> {
+        let mut stack = vec![(pc, slots)];
+        while let Some((pc, mut slots)) = stack.pop() {
+            *steps += 1;
+            if *steps > REGEX_STEP_LIMIT {
+                return Err("the text is too long for this pattern".to_string());
+            }
+            if threads.visited[pc] == threads.generation {
+                continue; // a thread with higher priority already reached this instruction at this position
+            }
+            threads.visited[pc] = threads.generation;
+            match self.program[pc] {
+                RegexInstruction::Jump(target) => stack.push((target, slots)),
+                RegexInstruction::Split(first, second) => {
+                    stack.push((second, slots.clone()));
+                    stack.push((first, slots));
+                },
+                RegexInstruction::Save(slot) => {
+                    slots[slot] = Some(position);
+                    stack.push((pc + 1, slots));
+                },
+                RegexInstruction::Start => if position == 0 { stack.push((pc + 1, slots)) },
+                RegexInstruction::End => if position == chars.len() { stack.push((pc + 1, slots)) },
+                _ => threads.list.push((pc, slots)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Threads of the Pike VM at one position of the text, in priority order
+ */
+struct RegexThreads {
+    list: Vec<(usize, Vec<Option<usize>>)>, // instruction and capture slots of each thread
+    visited: Vec<usize>,                    // generation in which each instruction was last reached
+    generation: usize,
+}
+
+impl RegexThreads {
+    fn new(program_len: usize) -> Self {
+        RegexThreads { list: vec![], visited: vec![0; program_len], generation: 1 }
+    }
+
+    fn clear(&mut self) {
+        self.list.clear();
+        self.generation += 1;
+    }
+}
+
+/**
+ * Appends the instructions of the node to the program
+ */
+fn compile_regex(node: &RegexNode, program: &mut Vec<RegexInstruction>) -> Result<(), String> {
+    if program.len() > REGEX_PROGRAM_LIMIT {
+        return Err("the pattern is too large".to_string());
+    }
+    match node {
+        RegexNode::Char(c) => program.push(RegexInstruction::Char(*c)),
+        RegexNode::Any => program.push(RegexInstruction::Any),
+        RegexNode::Class(items, negated) => program.push(RegexInstruction::Class(items.clone(), *negated)),
+        RegexNode::Start => program.push(RegexInstruction::Start),
+        RegexNode::End => program.push(RegexInstruction::End),
+        RegexNode::Group(inner, Some(index)) => {
+            program.push(RegexInstruction::Save(2 * index + 2));
+            compile_regex(inner, program)?;
+            program.push(RegexInstruction::Save(2 * index + 3));
+        },
+        RegexNode::Group(inner, None) => compile_regex(inner, program)?,
+        RegexNode::Concat(nodes) => {
+            for node in nodes {
+                compile_regex(node, program)?;
+            }
+        },
+        RegexNode::Alternate(branches) => {
+            // Each branch but the last is preceded by a Split to the next one and followed by a Jump to the end
+            let mut jumps = vec![];
+            for (i, branch) in branches.iter().enumerate() {
+                if i + 1 < branches.len() {
+                    let split = program.len();
+                    program.push(RegexInstruction::Split(split + 1, 0));
+                    compile_regex(branch, program)?;
+                    jumps.push(program.len());
+                    program.push(RegexInstruction::Jump(0));
+                    let next_branch = program.len();
+                    program[split] = RegexInstruction::Split(split + 1, next_branch);
+                } else {
+                    compile_regex(branch, program)?;
+                }
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = RegexInstruction::Jump(end);
+            }
+        },
+        RegexNode::Repeat(inner, min, max, greedy) => {
+            let split = |body: usize, exit: usize| {
+                if *greedy { RegexInstruction::Split(body, exit) } else { RegexInstruction::Split(exit, body) }
+            };
+            for _ in 0..*min {
+                compile_regex(inner, program)?;
+            }
+            match max {
+                None => {
+                    // The VM never follows the same instruction twice at one position, so empty loops end
+                    let loop_start = program.len();
+                    program.push(RegexInstruction::Jump(0));
+                    compile_regex(inner, program)?;
+                    program.push(RegexInstruction::Jump(loop_start));
+                    let exit = program.len();
+                    program[loop_start] = split(loop_start + 1, exit);
+                },
+                Some(max) => {
+                    // Each optional repetition can skip all the ones after it
+                    let mut splits = vec![];
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(RegexInstruction::Jump(0));
+                        compile_regex(inner, program)?;
+                    }
+                    let exit = program.len();
+                    for at in splits {
+                        program[at] = split(at + 1, exit);
+                    }
+                },
+            }
+        },
+    }
+    if program.len() > REGEX_PROGRAM_LIMIT {
+        return Err("the pattern is too large".to_string());
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod regex_tests {
+    use super::*;
+
+    fn captures(pattern: &str, text: &str) -> Option<Vec<Option<String>>> {
+        Regex::new(pattern).unwrap().captures(text).unwrap()
+    }
+
+    fn texts(groups: &[Option<&str>]) -> Option<Vec<Option<String>>> {
+        Some(groups.iter().map(|group| group.map(String::from)).collect())
+    }
+
+    #[test]
+    fn matches_like_a_backtracking_engine() {
+        assert_eq!(captures("b+", "abbbc"), texts(&[Some("bbb")]));
+        assert_eq!(captures("b+?", "abbbc"), texts(&[Some("b")]));
+        assert_eq!(captures("(a|ab)(c|bcd)", "abcd"), texts(&[Some("abcd"), Some("a"), Some("bcd")]));
+        assert_eq!(captures("(ab|a)(c|bcd)", "abcd"), texts(&[Some("abc"), Some("ab"), Some("c")]));
+        assert_eq!(captures("(?<year>\\d{4})-(?<month>\\d{2})", "on 2024-05-17"), texts(&[Some("2024-05"), Some("2024"), Some("05")]));
+        assert_eq!(captures("(x)?y", "y"), texts(&[Some("y"), None]));
+        assert_eq!(captures("(a*)*b", "aab"), texts(&[Some("aab"), Some("aa")]));
+        assert_eq!(captures("^[^0-9]{2,3}$", "ab1"), None);
+        assert_eq!(captures("a{2,}?", "aaaa"), texts(&[Some("aa")]));
+        assert_eq!(captures("^$", ""), texts(&[Some("")]));
+        assert_eq!(captures("x{2}", "xxx"), texts(&[Some("xx")]));
+    }
+
+    #[test]
+    fn long_texts_and_nested_quantifiers_are_linear() {
+        let long = "a".repeat(60_000);
+        assert_eq!(Regex::new("^a*$").unwrap().is_match(&long), Ok(true));
+        let almost = format!("{}b", "a".repeat(28));
+        assert_eq!(Regex::new("^(a+)+$").unwrap().is_match(&almost), Ok(false));
+        assert_eq!(Regex::new("^(a|a)*$").unwrap().is_match(&almost), Ok(false));
+    }
+
+    #[test]
+    fn invalid_and_too_large_patterns_are_rejected() {
+        assert!(Regex::new("(a").is_err());
+        assert!(Regex::new("a)").is_err());
+        assert!(Regex::new("*a").is_err());
+        assert!(Regex::new("[z-a]").is_err());
+        assert!(Regex::new("a{1000}{1000}").is_err());
+        assert!(Regex::new("a{10}").is_ok());
+    }
+}
+
+
+/**
+ * A value that does not follow the json schema. The path is the acessor that reaches the value
+ * and keyword is the schema keyword that was not satisfied
+ */
+#[derive(Debug)]
+struct SchemaViolation {
+    path: Acessor,
+    keyword: String,
+    message: String,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} ({})", self.path, self.message, self.keyword)
+    }
+}
+
+/**
+ * Json Schema validator (draft 2020-12 core and validation vocabularies).
+ * Only local references ("#" and "#/json/pointer") are supported for $ref, and
+ * format, content and unevaluated keywords are ignored
+ */
+struct SchemaValidator<'a> {
+    root: &'a Json,
+    path: Vec<PathStep>,
+    violations: Vec<SchemaViolation>,
+    regexes: BTreeMap<String, Result<Regex, String>>,
+}
+
+// Keywords that need the whole value at once, so the values they apply to are deserialised when streaming
+const MATERIALISED_KEYWORDS: [&str; 9] = ["enum", "const", "uniqueItems", "contains", "anyOf", "oneOf", "not", "if", "dependentSchemas"];
+
+fn json_type_name(value: &Json) -> &'static str {
+    match value {
+        Json::Null => "null",
+        Json::Boolean(_) => "boolean",
+        Json::Number(_) => "number",
+        Json::String(_) => "string",
+        Json::Array(_) => "array",
+        Json::Object(_) => "object",
+    }
+}
+
+/**
+ * Orders paths the way the values they reach appear in a json: a value comes before the values inside it,
+ * object fields by key and array entries by index
+ */
+fn compare_paths(a: &Acessor, b: &Acessor) -> Ordering {
+    match (a, b) {
+        (Acessor::End, Acessor::End) => Ordering::Equal,
+        (Acessor::End, _) => Ordering::Less,
+        (_, Acessor::End) => Ordering::Greater,
+        (Acessor::ObjectField(a_label, a_next), Acessor::ObjectField(b_label, b_next)) => {
+            a_label.cmp(b_label).then_with(|| compare_paths(a_next, b_next))
+        },
+        (Acessor::ArrayEntry(a_index, a_next), Acessor::ArrayEntry(b_index, b_next)) => {
+            a_index.cmp(b_index).then_with(|| compare_paths(a_next, b_next))
+        },
+        (Acessor::ObjectField(..), _) => Ordering::Less,
+        _ => Ordering::Greater,
+    }
+}
+
+impl<'a> SchemaValidator<'a> {
+    fn new(root: &'a Json) -> Self {
+        SchemaValidator { root, path: vec![], violations: vec![], regexes: BTreeMap::new() }
+    }
+
+    fn violation(&mut self, keyword: &str, message: String) {
+        self.violations.push(SchemaViolation { path: path_acessor(&self.path), keyword: keyword.to_string(), message });
+    }
+
+    /**
+     * The violations found, sorted by path and then by keyword. Streaming validation only finds some violations
+     * once a container ends, so sorting makes both ways of validating report them in the same order
+     */
+    fn into_violations(self) -> Vec<SchemaViolation> {
+        let mut violations = self.violations;
+        violations.sort_by(|a, b| compare_paths(&a.path, &b.path).then_with(|| a.keyword.cmp(&b.keyword)));
+        violations
+    }
+
+    fn regex_test(&mut self, pattern: &str, text: &str) -> Result<bool, String> {
+        match self.regexes.entry(pattern.to_string()).or_insert_with(|| Regex::new(pattern)) {
+            Ok(regex) => regex.is_match(text),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    /**
+     * Collects every schema that applies to the same value as the given one, following $ref and allOf
+     */
+    fn collect_schemas(&mut self, schema: &'a Json, schemas: &mut Vec<&'a Json>, depth: usize) {
+        if depth > 64 {
+            self.violation("$ref", "too many nested references".to_string());
+            return;
+        }
+        schemas.push(schema);
+        if let Json::Object(keywords) = schema {
+            if let Some(Json::String(reference)) = keywords.get("$ref") {
+                let root = self.root;
+                let target = if let Some(pointer) = reference.strip_prefix('#') {
+                    parse_pointer(pointer).ok().and_then(|tokens| pointer_get(root, &tokens, reference).ok())
+                } else {
+                    None
+                };
+                match target {
+                    Some(target) => self.collect_schemas(target, schemas, depth + 1),
+                    None => self.violation("$ref", format!("cannot resolve reference \"{}\"", reference)),
+                }
+            }
+            if let Some(Json::Array(all_of)) = keywords.get("allOf") {
+                for sub_schema in all_of {
+                    self.collect_schemas(sub_schema, schemas, depth + 1);
+                }
+            }
+        }
+    }
+
+    fn resolve(&mut self, schema: &'a Json) -> Vec<&'a Json> {
+        let mut schemas = vec![];
+        self.collect_schemas(schema, &mut schemas, 0);
+        schemas
+    }
+
+    /**
+     * Schemas that apply to the value at the given index of an array
+     */
+    fn item_schemas(&mut self, schemas: &[&'a Json], index: usize) -> Vec<&'a Json> {
+        let mut item_schemas = vec![];
+        for schema in schemas {
+            if let Json::Object(keywords) = schema {
+                let prefix_items = match keywords.get("prefixItems") {
+                    Some(Json::Array(prefix_items)) => &prefix_items[..],
+                    _ => &[],
+                };
+                if let Some(item_schema) = prefix_items.get(index) {
+                    self.collect_schemas(item_schema, &mut item_schemas, 0);
+                } else if let Some(item_schema) = keywords.get("items") {
+                    self.collect_schemas(item_schema, &mut item_schemas, 0);
+                }
+            }
+        }
+        item_schemas
+    }
+
+    /**
+     * Schemas that apply to the value of the given object field. Fields that are not matched by properties
+     * or patternProperties are checked against additionalProperties.
+     * The path must already reach the field, so that a field that is not allowed is reported at its own path
+     */
+    fn property_schemas(&mut self, schemas: &[&'a Json], key: &str) -> Vec<&'a Json> {
+        let mut property_schemas = vec![];
+        for schema in schemas {
+            if let Json::Object(keywords) = schema {
+                let mut matched = false;
+                if let Some(Json::Object(properties)) = keywords.get("properties") {
+                    if let Some(property_schema) = properties.get(key) {
+                        matched = true;
+                        self.collect_schemas(property_schema, &mut property_schemas, 0);
+                    }
+                }
+                if let Some(Json::Object(pattern_properties)) = keywords.get("patternProperties") {
+                    for (pattern, property_schema) in pattern_properties {
+                        match self.regex_test(pattern, key) {
+                            Ok(true) => {
+                                matched = true;
+                                self.collect_schemas(property_schema, &mut property_schemas, 0);
+                            },
+                            Ok(false) => (),
+                            Err(error) => self.violation("patternProperties", format!("invalid pattern \"{}\": {}", pattern, error)),
+                        }
+                    }
+                }
+                if !matched {
+                    match keywords.get("additionalProperties") {
+                        Some(Json::Boolean(false)) => {
+                            self.violation("additionalProperties", format!("property \"{}\" is not allowed", key));
+                        },
+                        Some(additional_schema) => self.collect_schemas(additional_schema, &mut property_schemas, 0),
+                        None => (),
+                    }
+                }
+            }
+        }
+        property_schemas
+    }
+
+    /**
+     * Checks the keywords that only look at the value itself and not at its contents,
+     * so they can be checked from the first packets of a stream
+     */
+    fn check_kind(&mut self, schema: &Json, type_name: &str, is_integer: bool, size: Option<usize>) {
+        let keywords = match schema {
+            Json::Boolean(false) => {
+                self.violation("false", "no value is allowed here".to_string());
+                return;
+            },
+            Json::Object(keywords) => keywords,
+            _ => return,
+        };
+        let type_matches = |name: &Json| match name {
+            Json::String(name) => name == type_name || (name == "integer" && is_integer),
+            _ => false,
+        };
+        let expected = match keywords.get("type") {
+            Some(Json::Array(names)) => {
+                if names.iter().any(type_matches) {
+                    None
+                } else {
+                    let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+                    Some(format!("one of {}", names.join(", ")))
+                }
+            },
+            Some(name) if !type_matches(name) => Some(name.to_string()),
+            _ => None,
+        };
+        if let Some(expected) = expected {
+            self.violation("type", format!("expected {} but found \"{}\"", expected, type_name));
+        }
+        if let Some(size) = size {
+            let (min_keyword, max_keyword) = if type_name == "array" { ("minItems", "maxItems") } else { ("minProperties", "maxProperties") };
+            if let Some(Json::Number(min)) = keywords.get(min_keyword) {
+                if (size as f64) < *min {
+                    self.violation(min_keyword, format!("has {} values, fewer than {}", size, min));
+                }
+            }
+            if let Some(Json::Number(max)) = keywords.get(max_keyword) {
+                if (size as f64) > *max {
+                    self.violation(max_keyword, format!("has {} values, more than {}", size, max));
+                }
+            }
+        }
+    }
+
+    /**
+     * Checks the keywords of a number or a string
+     */
+    fn check_scalar(&mut self, schema: &Json, value: &Json) {
+        let keywords = match schema {
+            Json::Object(keywords) => keywords,
+            _ => return,
+        };
+        match value {
+            Json::Number(n) => {
+                let limit = |keyword: &str| match keywords.get(keyword) {
+                    Some(Json::Number(limit)) => Some(*limit),
+                    _ => None,
+                };
+                if let Some(minimum) = limit("minimum") {
+                    if *n < minimum {
+                        self.violation("minimum", format!("{} is less than {}", n, minimum));
+                    }
+                }
+                if let Some(maximum) = limit("maximum") {
+                    if *n > maximum {
+                        self.violation("maximum", format!("{} is greater than {}", n, maximum));
+                    }
+                }
+                if let Some(minimum) = limit("exclusiveMinimum") {
+                    if *n <= minimum {
+                        self.violation("exclusiveMinimum", format!("{} is not greater than {}", n, minimum));
+                    }
+                }
+                if let Some(maximum) = limit("exclusiveMaximum") {
+                    if *n >= maximum {
+                        self.violation("exclusiveMaximum", format!("{} is not less than {}", n, maximum));
+                    }
+                }
+                if let Some(divisor) = limit("multipleOf") {
+                    let quotient = n / divisor;
+                    if (quotient - quotient.round()).abs() > 1e-9 {
+                        self.violation("multipleOf", format!("{} is not a multiple of {}", n, divisor));
+                    }
+                }
+            },
+            Json::String(s) => {
+                let length = s.chars().count() as f64;
+                if let Some(Json::Number(min_length)) = keywords.get("minLength") {
+                    if length < *min_length {
+                        self.violation("minLength", format!("is shorter than {} characters", min_length));
+                    }
+                }
+                if let Some(Json::Number(max_length)) = keywords.get("maxLength") {
+                    if length > *max_length {
+                        self.violation("maxLength", format!("is longer than {} characters", max_length));
+                    }
+                }
+                if let Some(Json::String(pattern)) = keywords.get("pattern") {
+                    match self.regex_test(pattern, s) {
+                        Ok(true) => (),
+                        Ok(false) => self.violation("pattern", format!("{} does not match \"{}\"", value, pattern)),
+                        Err(error) => self.violation("pattern", format!("invalid pattern \"{}\": {}", pattern, error)),
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /**
+     * Checks the keywords of an object once all its keys are known
+     */
+    fn check_keys(&mut self, schema: &'a Json, keys: &[String]) {
+        let keywords = match schema {
+            Json::Object(keywords) => keywords,
+            _ => return,
+        };
+        if let Some(Json::Array(required)) = keywords.get("required") {
+            for name in required {
+                if let Json::String(name) = name {
+                    if !keys.contains(name) {
+                        self.violation("required", format!("missing required property \"{}\"", name));
+                    }
+                }
+            }
+        }
+        if let Some(Json::Object(dependent_required)) = keywords.get("dependentRequired") {
+            for (key, required) in dependent_required {
+                if let (true, Json::Array(required)) = (keys.contains(key), required) {
+                    for name in required {
+                        if let Json::String(name) = name {
+                            if !keys.contains(name) {
+                                self.violation("dependentRequired", format!("property \"{}\" requires property \"{}\"", key, name));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(names_schema) = keywords.get("propertyNames") {
+            for key in keys {
+                let key_schemas = self.resolve(names_schema);
+                self.path.push(PathStep::Field(key.to_string()));
+                self.validate_value(&key_schemas, &Json::String(key.to_string()));
+                self.path.pop();
+            }
+        }
+    }
+
+    /**
+     * Whether the value follows the schema, without recording any violation
+     */
+    fn is_valid(&mut self, schema: &'a Json, value: &Json) -> bool {
+        let violations = std::mem::take(&mut self.violations);
+        let schemas = self.resolve(schema);
+        self.validate_value(&schemas, value);
+        let valid = self.violations.is_empty();
+        self.violations = violations;
+        valid
+    }
+
+    /**
+     * Checks the keywords that need the whole value
+     */
+    fn check_materialised(&mut self, schema: &'a Json, value: &Json) {
+        let keywords = match schema {
+            Json::Object(keywords) => keywords,
+            _ => return,
+        };
+        if let Some(Json::Array(options)) = keywords.get("enum") {
+            if !options.contains(value) {
+                self.violation("enum", format!("{} is not one of the allowed values", value));
+            }
+        }
+        if let Some(constant) = keywords.get("const") {
+            if constant != value {
+                self.violation("const", format!("{} is not {}", value, constant));
+            }
+        }
+        if let Json::Array(values) = value {
+            if let Some(Json::Boolean(true)) = keywords.get("uniqueItems") {
+                if (1..values.len()).any(|i| values[..i].contains(&values[i])) {
+                    self.violation("uniqueItems", "values are not unique".to_string());
+                }
+            }
+            if let Some(contains_schema) = keywords.get("contains") {
+                let count = values.iter().filter(|item| self.is_valid(contains_schema, item)).count() as f64;
+                let min_contains = match keywords.get("minContains") { Some(Json::Number(n)) => *n, _ => 1.0 };
+                if count < min_contains {
+                    self.violation("contains", format!("{} values match the contains schema, expected at least {}", count, min_contains));
+                }
+                if let Some(Json::Number(max_contains)) = keywords.get("maxContains") {
+                    if count > *max_contains {
+                        self.violation("maxContains", format!("{} values match the contains schema, expected at most {}", count, max_contains));
+                    }
+                }
+            }
+        }
+        if let Some(Json::Array(options)) = keywords.get("anyOf") {
+            if !options.iter().any(|option| self.is_valid(option, value)) {
+                self.violation("anyOf", "does not match any of the schemas".to_string());
+            }
+        }
+        if let Some(Json::Array(options)) = keywords.get("oneOf") {
+            let matches = options.iter().filter(|option| self.is_valid(option, value)).count();
+            if matches != 1 {
+                self.violation("oneOf", format!("matches {} of the schemas instead of exactly one", matches));
+            }
+        }
+        if let Some(not_schema) = keywords.get("not") {
+            if self.is_valid(not_schema, value) {
+                self.violation("not", "matches a schema it must not match".to_string());
+            }
+        }
+        if let Some(if_schema) = keywords.get("if") {
+            let branch = if self.is_valid(if_schema, value) { keywords.get("then") } else { keywords.get("else") };
+            if let Some(branch_schema) = branch {
+                let schemas = self.resolve(branch_schema);
+                self.validate_value(&schemas, value);
+            }
+        }
+        if let (Some(Json::Object(dependent_schemas)), Json::Object(fields)) = (keywords.get("dependentSchemas"), value) {
+            for (key, dependent_schema) in dependent_schemas {
+                if fields.contains_key(key) {
+                    let schemas = self.resolve(dependent_schema);
+                    self.validate_value(&schemas, value);
+                }
+            }
+        }
+    }
+
+    /**
+     * Validates a deserialised value against every schema in schemas
+     */
+    fn validate_value(&mut self, schemas: &[&'a Json], value: &Json) {
+        let is_integer = match value { Json::Number(n) => n.fract() == 0.0, _ => false };
+        let size = match value {
+            Json::Array(values) => Some(values.len()),
+            Json::Object(fields) => Some(fields.len()),
+            _ => None,
+        };
+        for &schema in schemas {
+            self.check_kind(schema, json_type_name(value), is_integer, size);
+            self.check_scalar(schema, value);
+            self.check_materialised(schema, value);
+        }
+        match value {
+            Json::Array(values) => {
+                for (i, item) in values.iter().enumerate() {
+                    self.path.push(PathStep::Index(i));
+                    let item_schemas = self.item_schemas(schemas, i);
+                    self.validate_value(&item_schemas, item);
+                    self.path.pop();
+                }
+            },
+            Json::Object(fields) => {
+                let keys: Vec<String> = fields.keys().cloned().collect();
+                for &schema in schemas {
+                    self.check_keys(schema, &keys);
+                }
+                for (key, field) in fields {
+                    self.path.push(PathStep::Field(key.to_string()));
+                    let field_schemas = self.property_schemas(schemas, key);
+                    self.validate_value(&field_schemas, field);
+                    self.path.pop();
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /**
+     * Validates a value as it is streamed through the receiver channel, passing it along to the sender channel.
+     * Every value is only read once, and only the values that a schema with one of the MATERIALISED_KEYWORDS
+     * applies to are deserialised
+     */
+    fn validate_stream(&mut self, schemas: Vec<&'a Json>, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) {
+        let mut visitor = SchemaStreamVisitor {
+            validator: self,
+            writer: JcWriter::new(sender),
+            frames: vec![],
+            root: Some(schemas),
+            materialised: None,
+        };
+        walk_jc(receiver, &mut visitor);
+    }
+}
+
+fn needs_value(schemas: &[&Json]) -> bool {
+    schemas.iter().any(|schema| match schema {
+        Json::Object(keywords) => MATERIALISED_KEYWORDS.iter().any(|keyword| keywords.contains_key(*keyword)),
+        _ => false,
+    })
+}
+
+/**
+ * Visitor behind SchemaValidator::validate_stream. Every event is passed along to the writer, while the schemas
+ * of the arrays and objects being walked are kept in frames. Skipped values are passed along without being checked
+ */
+struct SchemaStreamVisitor<'v, 'a> {
+    validator: &'v mut SchemaValidator<'a>,
+    writer: JcWriter,
+    frames: Vec<SchemaStreamFrame<'a>>,
+    root: Option<Vec<&'a Json>>, // schemas of the walked value, taken when it starts
+    materialised: Option<MaterialisedValue<'a>>,
+}
+
+struct SchemaStreamFrame<'a> {
+    schemas: Vec<&'a Json>,
+    is_object: bool,
+    index: usize,      // index of the next array value
+    keys: Vec<String>, // keys of the object, the last one being the key of the current value
+}
+
+/**
+ * Value that is being deserialised because a schema with one of the MATERIALISED_KEYWORDS applies to it
+ */
+struct MaterialisedValue<'a> {
+    builder: JsonBuilder,
+    schemas: Vec<&'a Json>,
+    depth: usize,   // arrays and objects of the value that were entered and not exited yet
+    complete: bool, // false when some part of the value was skipped, in which case it cannot be checked
+}
+
+impl<'v, 'a> SchemaStreamVisitor<'v, 'a> {
+    /**
+     * Schemas of a value that is starting, moving the path to it
+     */
+    fn begin_value(&mut self) -> Vec<&'a Json> {
+        match self.frames.last_mut() {
+            None => self.root.take().expect("Only one value can be validated (Validate Stream)"),
+            Some(frame) if frame.is_object => {
+                let key = frame.keys.last().expect("Expected a key for a object field (Validate Stream)").to_string();
+                self.validator.path.push(PathStep::Field(key.to_string()));
+                self.validator.property_schemas(&frame.schemas, &key)
+            },
+            Some(frame) => {
+                let index = frame.index;
+                frame.index += 1;
+                self.validator.path.push(PathStep::Index(index));
+                self.validator.item_schemas(&frame.schemas, index)
+            },
+        }
+    }
+
+    fn end_value(&mut self) {
+        if !self.frames.is_empty() {
+            self.validator.path.pop();
+        }
+    }
+
+    fn enter_container(&mut self, is_object: bool, len: usize) {
+        if let Some(materialised) = &mut self.materialised {
+            materialised.depth += 1;
+            if is_object { materialised.builder.enter_object(len) } else { materialised.builder.enter_array(len) }
+            return;
+        }
+        let schemas = self.begin_value();
+        if needs_value(&schemas) {
+            let mut builder = JsonBuilder::new();
+            if is_object { builder.enter_object(len) } else { builder.enter_array(len) }
+            self.materialised = Some(MaterialisedValue { builder, schemas, depth: 1, complete: true });
+            return;
+        }
+        for schema in &schemas {
+            self.validator.check_kind(schema, if is_object { "object" } else { "array" }, false, Some(len));
+        }
+        self.frames.push(SchemaStreamFrame { schemas, is_object, index: 0, keys: vec![] });
+    }
+
+    fn exit_container(&mut self, is_object: bool) {
+        if let Some(materialised) = &mut self.materialised {
+            materialised.depth -= 1;
+            if is_object { materialised.builder.exit_object() } else { materialised.builder.exit_array() }
+            if materialised.depth == 0 {
+                let materialised = self.materialised.take().unwrap();
+                if materialised.complete {
+                    self.validator.validate_value(&materialised.schemas, &materialised.builder.result.unwrap());
+                }
+                self.end_value();
+            }
+            return;
+        }
+        let frame = self.frames.pop().unwrap();
+        if frame.is_object {
+            for &schema in &frame.schemas {
+                self.validator.check_keys(schema, &frame.keys);
+            }
+        }
+        self.end_value();
+    }
+}
+
+impl<'v, 'a> JcVisitor for SchemaStreamVisitor<'v, 'a> {
+    fn on_scalar(&mut self, value: Json) {
+        self.writer.on_scalar(value.clone());
+        if let Some(materialised) = &mut self.materialised {
+            materialised.builder.on_scalar(value);
+            return;
+        }
+        let schemas = self.begin_value();
+        self.validator.validate_value(&schemas, &value);
+        self.end_value();
+    }
+
+    fn enter_array(&mut self, len: usize) {
+        self.writer.enter_array(len);
+        self.enter_container(false, len);
+    }
+
+    fn exit_array(&mut self) {
+        self.writer.exit_array();
+        self.exit_container(false);
+    }
+
+    fn enter_object(&mut self, len: usize) {
+        self.writer.enter_object(len);
+        self.enter_container(true, len);
+    }
+
+    fn on_key(&mut self, key: String) {
+        self.writer.on_key(key.to_string());
+        match (&mut self.materialised, self.frames.last_mut()) {
+            (Some(materialised), _) => materialised.builder.on_key(key),
+            (None, Some(frame)) => frame.keys.push(key),
+            (None, None) => panic!("Expected a key inside an object (Validate Stream)"),
+        }
+    }
+
+    fn exit_object(&mut self) {
+        self.writer.exit_object();
+        self.exit_container(true);
+    }
+
+    fn on_skipped(&mut self) {
+        self.writer.on_skipped();
+        if let Some(materialised) = &mut self.materialised {
+            materialised.complete = false;
+            return;
+        }
+        self.begin_value();
+        self.end_value();
+    }
+}
+
+
+/**
+ * Validation stage of the pipeline, meant to sit between serialise_json and eval. The json received through
+ * the receiver channel is passed along unchanged to the sender channel while it is checked against the schema,
+ * and once the whole json went through every violation that was found is returned
+ */
+fn validate_schema(schema: &Json, receiver: mpsc::Receiver<JC>, sender: mpsc::Sender<JC>) -> Vec<SchemaViolation> {
+    let mut validator = SchemaValidator::new(schema);
+    let schemas = validator.resolve(schema);
+    validator.validate_stream(schemas, receiver, sender);
+    validator.into_violations()
+}
+
+/**
+ * Validates a json value that is already deserialised
+ */
+fn validate_json(schema: &Json, value: &Json) -> Vec<SchemaViolation> {
+    let mut validator = SchemaValidator::new(schema);
+    let schemas = validator.resolve(schema);
+    validator.validate_value(&schemas, value);
+    validator.into_violations()
+}
+
+#[cfg(test)]
+mod schema_tests {
+    use super::*;
+
+    fn schema() -> Json {
+        json!({
+            "type": "object",
+            "required": ["name", "age"],
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "age": {"type": ["integer", "null"], "minimum": 0},
+                "tags": {"type": "array", "items": {"type": "string", "pattern": "^[a-z]+$"}, "uniqueItems": true},
+                "address": {"$ref": "#/$defs/address"}
+            },
+            "additionalProperties": false,
+            "$defs": {
+                "address": {"type": "object", "properties": {"city": {"type": "string"}}, "additionalProperties": {"type": "string"}}
+            }
+        })
+    }
+
+    fn validate_both(schema: &Json, value: &Json) -> Vec<String> {
+        let (sender, receiver) = mpsc::channel();
+        let violations = validate_schema(schema, jc_stream(value), sender);
+        assert_eq!(&deserialise_json(receiver), value); // the value is passed along unchanged
+        let violations: Vec<String> = violations.iter().map(|violation| violation.to_string()).collect();
+        let in_memory: Vec<String> = validate_json(schema, value).iter().map(|violation| violation.to_string()).collect();
+        assert_eq!(violations, in_memory);
+        violations
+    }
+
+    #[test]
+    fn valid_values_have_no_violations() {
+        let value = json!({"name": "Jason", "age": null, "tags": ["a", "b"], "address": {"city": "New York", "zip": "64780"}});
+        assert!(validate_both(&schema(), &value).is_empty());
+        assert!(validate_both(&json!(true), &value).is_empty());
+    }
+
+    #[test]
+    fn violations_are_reported_at_the_path_of_the_value() {
+        let value = json!({"name": "", "age": 1.5, "tags": ["a", "B", "a"], "address": {"zip": 64780}, "extra": 1});
+        assert_eq!(validate_both(&schema(), &value), vec![
+            r#"."address"."zip": expected "string" but found "number" (type)"#,
+            r#"."age": expected one of "integer", "null" but found "number" (type)"#,
+            r#"."extra": property "extra" is not allowed (additionalProperties)"#,
+            r#"."name": is shorter than 1 characters (minLength)"#,
+            r#"."tags": values are not unique (uniqueItems)"#,
+            r#"."tags"[1]: "B" does not match "^[a-z]+$" (pattern)"#,
+        ]);
+        assert_eq!(validate_both(&schema(), &json!([])), vec![r#".: expected "object" but found "array" (type)"#]);
+    }
+
+    #[test]
+    fn violations_of_a_container_come_before_the_violations_inside_it() {
+        let schema = json!({"required": ["name"], "properties": {"age": {"minimum": 0}}});
+        assert_eq!(validate_both(&schema, &json!({"age": -1})), vec![
+            r#".: missing required property "name" (required)"#,
+            r#"."age": -1 is less than 0 (minimum)"#,
+        ]);
+    }
+
+    #[test]
+    fn invalid_patterns_are_reported() {
+        let schema = json!({"patternProperties": {"(": {"type": "string"}}, "properties": {"a": {"pattern": "("}}});
+        let violations = validate_both(&schema, &json!({"a": "x"}));
+        assert_eq!(violations.len(), 2);
+        assert!(violations[0].starts_with(r#"."a": invalid pattern "(": "#) && violations[0].ends_with("(pattern)"));
+        assert!(violations[1].starts_with(r#"."a": invalid pattern "(": "#) && violations[1].ends_with("(patternProperties)"));
+    }
+}
+
+
+// Order in which the types of an inferred schema are listed
+const SCHEMA_TYPE_NAMES: [&str; 7] = ["null", "boolean", "integer", "number", "string", "array", "object"];
+
+/**
+ * Infers a json schema from sample documents. The schema describes the observed types, the object fields
+ * (required when they were present in every sampled object), the union of the values of arrays and
+ * the range of the numbers.
+ * Inferred schemas can be combined with merge_schemas, so a large NDJSON file can be inferred incrementally,
+ * one line or one chunk of lines at a time
+ */
+fn infer_schema<I: Iterator<Item = Json>>(docs: I) -> Json {
+    docs.fold(None, |schema: Option<Json>, doc| {
+        let doc_schema = schema_of(&doc);
+        Some(match schema {
+            Some(schema) => merge_schemas(&schema, &doc_schema),
+            None => doc_schema,
+        })
+    }).unwrap_or_else(|| Json::Object(BTreeMap::new()))
+}
+
+/**
+ * Schema of a single json value
+ */
+fn schema_of(value: &Json) -> Json {
+    let mut schema = BTreeMap::new();
+    match value {
+        Json::Number(n) => {
+            let type_name = if n.fract() == 0.0 { "integer" } else { "number" };
+            schema.insert("type".to_string(), Json::String(type_name.to_string()));
+            schema.insert("minimum".to_string(), Json::Number(*n));
+            schema.insert("maximum".to_string(), Json::Number(*n));
+        },
+        Json::Array(values) => {
+            schema.insert("type".to_string(), Json::String("array".to_string()));
+            if let Some(items) = values.iter().map(schema_of).fold(None, |items: Option<Json>, item| {
+                Some(match items {
+                    Some(items) => merge_schemas(&items, &item),
+                    None => item,
+                })
+            }) {
+                schema.insert("items".to_string(), items);
+            }
+        },
+        Json::Object(fields) => {
+            schema.insert("type".to_string(), Json::String("object".to_string()));
+            let properties = fields.iter().map(|(key, field)| (key.to_string(), schema_of(field))).collect();
+            schema.insert("properties".to_string(), Json::Object(properties));
+            let required = fields.keys().map(|key| Json::String(key.to_string())).collect();
+            schema.insert("required".to_string(), Json::Array(required));
+        },
+        _ => {
+            schema.insert("type".to_string(), Json::String(json_type_name(value).to_string()));
+        },
+    }
+    Json::Object(schema)
+}
+
+/**
+ * Schema of a json value streamed through the receiver channel, without deserialising it
+ */
+fn schema_of_stream(receiver: mpsc::Receiver<JC>) -> Json {
+    let mut inferrer = SchemaInferrer { stack: vec![], result: None };
+    walk_jc(receiver, &mut inferrer);
+    inferrer.result.unwrap()
+}
+
+/**
+ * Visitor that builds the same schema as schema_of. Only the schemas of the values are kept, merging the
+ * items of arrays as they arrive. Skipped values are left out, since nothing is known about them
+ */
+struct SchemaInferrer {
+    stack: Vec<SchemaFrame>,
+    result: Option<Json>,
+}
+
+enum SchemaFrame {
+    Array(Option<Json>),                              // merged schema of the items
+    Object(BTreeMap<String, Json>, Option<String>),   // properties, with the key of the next value
+}
+
+impl SchemaInferrer {
+    fn add_schema(&mut self, value_schema: Json) {
+        match self.stack.last_mut() {
+            Some(SchemaFrame::Array(items)) => {
+                *items = Some(match items.take() {
+                    Some(items) => merge_schemas(&items, &value_schema),
+                    None => value_schema,
+                });
+            },
+            Some(SchemaFrame::Object(properties, key)) => {
+                properties.insert(key.take().expect("Expected a key for a object field (Infer Schema)"), value_schema);
+            },
+            None => self.result = Some(value_schema),
+        }
+    }
+}
+
+impl JcVisitor for SchemaInferrer {
+    fn on_scalar(&mut self, value: Json) {
+        self.add_schema(schema_of(&value));
+    }
+
+    fn enter_array(&mut self, _len: usize) {
+        self.stack.push(SchemaFrame::Array(None));
+    }
+
+    fn enter_object(&mut self, _len: usize) {
+        self.stack.push(SchemaFrame::Object(BTreeMap::new(), None));
+    }
+
+    fn on_key(&mut self, key: String) {
+        if let Some(SchemaFrame::Object(_, pending_key)) = self.stack.last_mut() {
+            *pending_key = Some(key);
+        }
+    }
+
+    fn exit_array(&mut self) {
+        let mut schema = BTreeMap::new();
+        schema.insert("type".to_string(), Json::String("array".to_string()));
+        if let Some(SchemaFrame::Array(Some(items))) = self.stack.pop() {
+            schema.insert("items".to_string(), items);
+        }
+        self.add_schema(Json::Object(schema));
+    }
+
+    fn exit_object(&mut self) {
+        let mut schema = BTreeMap::new();
+        schema.insert("type".to_string(), Json::String("object".to_string()));
+        if let Some(SchemaFrame::Object(properties, _)) = self.stack.pop() {
+            let required = properties.keys().map(|key| Json::String(key.to_string())).collect();
+            schema.insert("properties".to_string(), Json::Object(properties));
+            schema.insert("required".to_string(), Json::Array(required));
+        }
+        self.add_schema(Json::Object(schema));
+    }
+
+    fn on_skipped(&mut self) {
+        if let Some(SchemaFrame::Object(_, pending_key)) = self.stack.last_mut() {
+            *pending_key = None;
+        }
+    }
+}
+
+fn inferred_types(schema: &BTreeMap<String, Json>) -> Vec<String> {
+    match schema.get("type") {
+        Some(Json::String(name)) => vec![name.to_string()],
+        Some(Json::Array(names)) => names.iter().filter_map(|name| match name {
+            Json::String(name) => Some(name.to_string()),
+            _ => None,
+        }).collect(),
+        _ => vec![],
+    }
+}
+
+/**
+ * Combines two inferred schemas into a schema that describes the values of both.
+ * Fields are only kept as required if both schemas require them, unless one of the schemas never saw an object
+ */
+fn merge_schemas(a: &Json, b: &Json) -> Json {
+    let (a, b) = match (a, b) {
+        (Json::Object(a), Json::Object(b)) => (a, b),
+        (Json::Object(_), _) => return a.clone(),
+        _ => return b.clone(),
+    };
+    let (a_types, b_types) = (inferred_types(a), inferred_types(b));
+    let mut types: Vec<&str> = SCHEMA_TYPE_NAMES.iter().cloned()
+        .filter(|name| a_types.iter().chain(&b_types).any(|t| t == name))
+        .collect();
+    if types.contains(&"number") {
+        types.retain(|name| *name != "integer"); // integers are numbers
+    }
+
+    let mut merged = BTreeMap::new();
+    merged.insert("type".to_string(), match types.len() {
+        1 => Json::String(types[0].to_string()),
+        _ => Json::Array(types.iter().map(|name| Json::String(name.to_string())).collect()),
+    });
+
+    let number = |types: &[String]| types.iter().any(|name| name == "number" || name == "integer");
+    let object = |types: &[String]| types.iter().any(|name| name == "object");
+    for (keyword, pick) in [("minimum", f64::min as fn(f64, f64) -> f64), ("maximum", f64::max)].iter() {
+        let limit = match (a.get(*keyword), b.get(*keyword)) {
+            (Some(Json::Number(x)), Some(Json::Number(y))) => Some(pick(*x, *y)),
+            (Some(Json::Number(x)), _) if !number(&b_types) => Some(*x),
+            (_, Some(Json::Number(y))) if !number(&a_types) => Some(*y),
+            _ => None, // one of the schemas saw numbers without limits, so there are none
+        };
+        if let Some(limit) = limit {
+            merged.insert(keyword.to_string(), Json::Number(limit));
+        }
+    }
+
+    match (a.get("items"), b.get("items")) {
+        (Some(x), Some(y)) => {
+            merged.insert("items".to_string(), merge_schemas(x, y));
+        },
+        (Some(items), None) | (None, Some(items)) => {
+            merged.insert("items".to_string(), items.clone());
+        },
+        (None, None) => (),
+    }
+
+    if object(&a_types) || object(&b_types) {
+        let empty = BTreeMap::new();
+        let properties_of = |schema: &BTreeMap<String, Json>| match schema.get("properties") {
+            Some(Json::Object(properties)) => properties.clone(),
+            _ => empty.clone(),
+        };
+        let (a_properties, b_properties) = (properties_of(a), properties_of(b));
+        let mut properties = a_properties.clone();
+        for (key, b_property) in b_properties {
+            let property = match a_properties.get(&key) {
+                Some(a_property) => merge_schemas(a_property, &b_property),
+                None => b_property,
+            };
+            properties.insert(key, property);
+        }
+        merged.insert("properties".to_string(), Json::Object(properties));
+
+        let required_of = |schema: &BTreeMap<String, Json>, types: &[String]| match schema.get("required") {
+            Some(Json::Array(required)) => Some(required.clone()),
+            _ if object(types) => Some(vec![]),
+            _ => None, // no object was seen, so nothing constrains the required fields
+        };
+        let required = match (required_of(a, &a_types), required_of(b, &b_types)) {
+            (Some(x), Some(y)) => x.into_iter().filter(|key| y.contains(key)).collect(),
+            (Some(x), None) => x,
+            (None, Some(y)) => y,
+            (None, None) => vec![],
+        };
+        merged.insert("required".to_string(), Json::Array(required));
+    }
+    Json::Object(merged)
+}
+
+#[cfg(test)]
+mod infer_schema_tests {
+    use super::*;
+
+    #[test]
+    fn infers_types_fields_and_ranges() {
+        let schema = infer_schema(vec![
+            json!({"name": "Jason", "age": 31, "tags": ["a"]}),
+            json!({"name": "Ray", "age": 40.5, "tags": [1, "b"], "city": null}),
+        ].into_iter());
+        assert_eq!(schema["properties"]["age"], json!({"type": "number", "minimum": 31, "maximum": 40.5}));
+        assert_eq!(schema["properties"]["tags"]["items"]["type"], json!(["integer", "string"]));
+        assert_eq!(schema["properties"]["city"], json!({"type": "null"}));
+        assert_eq!(schema["required"], json!(["age", "name", "tags"]));
+        assert_eq!(infer_schema(vec![json!(1), json!("a")].into_iter())["type"], json!(["integer", "string"]));
+    }
+
+    #[test]
+    fn streamed_and_merged_schemas_agree_and_accept_their_samples() {
+        let mut rng = TestRng(0x2545_F491_4F6C_DD1D);
+        for _ in 0..500 {
+            let docs: Vec<Json> = (0..3).map(|_| rng.json(3)).collect();
+            for doc in &docs {
+                assert_eq!(schema_of_stream(jc_stream(doc)), schema_of(doc), "{}", doc);
+            }
+            let schema = infer_schema(docs.iter().cloned());
+            let merged = merge_schemas(&infer_schema(docs[..1].iter().cloned()), &infer_schema(docs[1..].iter().cloned()));
+            assert_eq!(merged, schema);
+            for doc in &docs {
+                assert!(validate_json(&schema, doc).is_empty(), "{} does not follow {}", doc, schema);
+            }
+        }
+    }
+}
+
+/**
+ * Bridge between Json and serde, only compiled with the serde feature
+ * Numbers are f64, so integers above 2^53 lose precision. Enums use the same representation as serde_json:
+ * unit variants are strings and other variants are objects with the variant name as their only field
+ */
+#[cfg(feature = "serde")]
+mod serde_bridge {
+    use super::{consume_value, consume_value_from, path_acessor, Acessor, Json, PathStep, JC};
+    use serde::de::{self, DeserializeOwned, IntoDeserializer};
+    use serde::ser::{self, Serialize};
+    use std::collections::{btree_map, BTreeMap};
+    use std::fmt;
+    use std::sync::mpsc;
+    use std::vec;
+
+    /**
+     * Error of the serde bridge. The path is the acessor that reaches the value that caused the error
+     */
+    #[derive(Debug)]
+    pub(crate) struct SerdeError {
+        pub(crate) path: Acessor,
+        pub(crate) message: String,
+    }
+
+    impl fmt::Display for SerdeError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.path {
+                Acessor::End => write!(f, "{}", self.message),
+                _ => write!(f, "{}: {}", self.path, self.message),
+            }
+        }
+    }
+
+    impl std::error::Error for SerdeError {}
+
+    impl ser::Error for SerdeError {
+        fn custom<T: fmt::Display>(msg: T) -> Self {
+            SerdeError { path: Acessor::End, message: msg.to_string() }
+        }
+    }
+
+    impl de::Error for SerdeError {
+        fn custom<T: fmt::Display>(msg: T) -> Self {
+            SerdeError { path: Acessor::End, message: msg.to_string() }
+        }
+    }
+
+
+    impl Serialize for Json {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::{SerializeMap, SerializeSeq};
+            match self {
+                Json::Null => serializer.serialize_unit(),
+                Json::Boolean(b) => serializer.serialize_bool(*b),
+                Json::Number(n) => serializer.serialize_f64(*n),
+                Json::String(s) => serializer.serialize_str(s),
+                Json::Array(array) => {
+                    let mut seq = serializer.serialize_seq(Some(array.len()))?;
+                    for value in array {
+                        seq.serialize_element(value)?;
+                    }
+                    seq.end()
+                },
+                Json::Object(map) => {
+                    let mut object = serializer.serialize_map(Some(map.len()))?;
+                    for (key, value) in map {
+                        object.serialize_entry(key, value)?;
+                    }
+                    object.end()
+                },
+            }
+        }
+    }
+
+    struct JsonVisitor;
+
+    impl<'de> de::Visitor<'de> for JsonVisitor {
+        type Value = Json;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "any json value")
+        }
+
+        fn visit_bool<E>(self, b: bool) -> Result<Json, E> { Ok(Json::Boolean(b)) }
+        fn visit_i64<E>(self, n: i64) -> Result<Json, E> { Ok(Json::Number(n as f64)) }
+        fn visit_u64<E>(self, n: u64) -> Result<Json, E> { Ok(Json::Number(n as f64)) }
+        fn visit_f64<E>(self, n: f64) -> Result<Json, E> { Ok(Json::Number(n)) }
+        fn visit_str<E>(self, s: &str) -> Result<Json, E> { Ok(Json::String(s.to_string())) }
+        fn visit_string<E>(self, s: String) -> Result<Json, E> { Ok(Json::String(s)) }
+        fn visit_unit<E>(self) -> Result<Json, E> { Ok(Json::Null) }
+        fn visit_none<E>(self) -> Result<Json, E> { Ok(Json::Null) }
+
+        fn visit_some<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Json, D::Error> {
+            de::Deserialize::deserialize(deserializer)
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Json, A::Error> {
+            let mut array = vec![];
+            while let Some(value) = seq.next_element()? {
+                array.push(value);
+            }
+            Ok(Json::Array(array))
+        }
+
+        fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Json, A::Error> {
+            let mut object = BTreeMap::new();
+            while let Some((key, value)) = map.next_entry::<String, Json>()? {
+                object.insert(key, value);
+            }
+            Ok(Json::Object(object))
+        }
+    }
+
+    impl<'de> de::Deserialize<'de> for Json {
+        fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Json, D::Error> {
+            deserializer.deserialize_any(JsonVisitor)
+        }
+    }
+
+
+    /**
+     * Converts any serializable value into a Json. Panics when the value can not be represented as json, which only
+     * happens for maps with keys that are not strings, numbers, booleans or chars, or when its Serialize fails
+     */
+    pub(crate) fn to_json<T: Serialize + ?Sized>(value: &T) -> Json {
+        value.serialize(JsonSerializer).unwrap_or_else(|error| panic!("Cannot convert the value to Json: {} (To Json)", error))
+    }
+
+    /**
+     * Builds any deserializable value from a Json
+     */
+    pub(crate) fn from_json<T: DeserializeOwned>(json: Json) -> Result<T, SerdeError> {
+        T::deserialize(json)
+    }
+
+
+    /**
+     * Object keys must be strings, numbers, booleans and chars are converted to strings
+     */
+    fn key_to_string(key: Json) -> Result<String, SerdeError> {
+        match key {
+            Json::String(s) => Ok(s),
+            Json::Number(_) | Json::Boolean(_) => Ok(key.to_string()),
+            _ => Err(ser::Error::custom("object keys must be strings")),
+        }
+    }
+
+    struct JsonSerializer;
+
+    struct JsonSeq {
+        array: Vec<Json>,
+        variant: Option<&'static str>, // tuple variants are wrapped in an object with the variant name
+    }
+
+    struct JsonMap {
+        object: BTreeMap<String, Json>,
+        pending_key: Option<String>,
+        variant: Option<&'static str>, // struct variants are wrapped in an object with the variant name
+    }
+
+    fn wrap_variant(variant: Option<&'static str>, value: Json) -> Json {
+        match variant {
+            Some(name) => Json::Object(BTreeMap::from([(name.to_string(), value)])),
+            None => value,
+        }
+    }
+
+    impl ser::Serializer for JsonSerializer {
+        type Ok = Json;
+        type Error = SerdeError;
+        type SerializeSeq = JsonSeq;
+        type SerializeTuple = JsonSeq;
+        type SerializeTupleStruct = JsonSeq;
+        type SerializeTupleVariant = JsonSeq;
+        type SerializeMap = JsonMap;
+        type SerializeStruct = JsonMap;
+        type SerializeStructVariant = JsonMap;
+
+        fn serialize_bool(self, v: bool) -> Result<Json, SerdeError> { Ok(Json::Boolean(v)) }
+        fn serialize_i8(self, v: i8) -> Result<Json, SerdeError> { Ok(Json::Number(v as f64)) }
+        fn serialize_i16(self, v: i16) -> Result<Json, SerdeError> { Ok(Json::Number(v as f64)) }
+        fn serialize_i32(self, v: i32) -> Result<Json, SerdeError> { Ok(Json::Number(v as f64)) }
+        fn serialize_i64(self, v: i64) -> Result<Json, SerdeError> { Ok(Json::Number(v as f64)) }
+        fn serialize_u8(self, v: u8) -> Result<Json, SerdeError> { Ok(Json::Number(v as f64)) }
+        fn serialize_u16(self, v: u16) -> Result<Json, SerdeError> { Ok(Json::Number(v as f64)) }
+        fn serialize_u32(self, v: u32) -> Result<Json, SerdeError> { Ok(Json::Number(v as f64)) }
+        fn serialize_u64(self, v: u64) -> Result<Json, SerdeError> { Ok(Json::Number(v as f64)) }
+        fn serialize_f32(self, v: f32) -> Result<Json, SerdeError> { Ok(Json::Number(v as f64)) }
+        fn serialize_f64(self, v: f64) -> Result<Json, SerdeError> { Ok(Json::Number(v)) }
+        fn serialize_char(self, v: char) -> Result<Json, SerdeError> { Ok(Json::String(v.to_string())) }
+        fn serialize_str(self, v: &str) -> Result<Json, SerdeError> { Ok(Json::String(v.to_string())) }
+
+        fn serialize_bytes(self, v: &[u8]) -> Result<Json, SerdeError> {
+            Ok(Json::Array(v.iter().map(|b| Json::Number(*b as f64)).collect()))
+        }
+
+        fn serialize_none(self) -> Result<Json, SerdeError> { Ok(Json::Null) }
+
+        fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Json, SerdeError> {
+            value.serialize(self)
+        }
+
+        fn serialize_unit(self) -> Result<Json, SerdeError> { Ok(Json::Null) }
+        fn serialize_unit_struct(self, _name: &'static str) -> Result<Json, SerdeError> { Ok(Json::Null) }
+
+        fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<Json, SerdeError> {
+            Ok(Json::String(variant.to_string()))
+        }
+
+        fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<Json, SerdeError> {
+            value.serialize(self)
+        }
+
+        fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, variant: &'static str,
+                                                            value: &T) -> Result<Json, SerdeError> {
+            Ok(wrap_variant(Some(variant), value.serialize(self)?))
+        }
+
+        fn serialize_seq(self, len: Option<usize>) -> Result<JsonSeq, SerdeError> {
+            Ok(JsonSeq { array: Vec::with_capacity(len.unwrap_or(0)), variant: None })
+        }
+
+        fn serialize_tuple(self, len: usize) -> Result<JsonSeq, SerdeError> {
+            self.serialize_seq(Some(len))
+        }
+
+        fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<JsonSeq, SerdeError> {
+            self.serialize_seq(Some(len))
+        }
+
+        fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                                   len: usize) -> Result<JsonSeq, SerdeError> {
+            Ok(JsonSeq { array: Vec::with_capacity(len), variant: Some(variant) })
+        }
+
+        fn serialize_map(self, _len: Option<usize>) -> Result<JsonMap, SerdeError> {
+            Ok(JsonMap { object: BTreeMap::new(), pending_key: None, variant: None })
+        }
+
+        fn serialize_struct(self, _name: &'static str, len: usize) -> Result<JsonMap, SerdeError> {
+            self.serialize_map(Some(len))
+        }
+
+        fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                                    _len: usize) -> Result<JsonMap, SerdeError> {
+            Ok(JsonMap { object: BTreeMap::new(), pending_key: None, variant: Some(variant) })
+        }
+    }
+
+    impl ser::SerializeSeq for JsonSeq {
+        type Ok = Json;
+        type Error = SerdeError;
+
+        fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+            self.array.push(value.serialize(JsonSerializer)?);
+            Ok(())
+        }
+
+        fn end(self) -> Result<Json, SerdeError> {
+            Ok(wrap_variant(self.variant, Json::Array(self.array)))
+        }
+    }
+
+    impl ser::SerializeTuple for JsonSeq {
+        type Ok = Json;
+        type Error = SerdeError;
+
+        fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+            ser::SerializeSeq::serialize_element(self, value)
+        }
+
+        fn end(self) -> Result<Json, SerdeError> {
+            ser::SerializeSeq::end(self)
+        }
+    }
+
+    impl ser::SerializeTupleStruct for JsonSeq {
+        type Ok = Json;
+        type Error = SerdeError;
+
+        fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+            ser::SerializeSeq::serialize_element(self, value)
+        }
+
+        fn end(self) -> Result<Json, SerdeError> {
+            ser::SerializeSeq::end(self)
+        }
+    }
+
+    impl ser::SerializeTupleVariant for JsonSeq {
+        type Ok = Json;
+        type Error = SerdeError;
+
+        fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+            ser::SerializeSeq::serialize_element(self, value)
+        }
+
+        fn end(self) -> Result<Json, SerdeError> {
+            ser::SerializeSeq::end(self)
+        }
+    }
+
+    impl ser::SerializeMap for JsonMap {
+        type Ok = Json;
+        type Error = SerdeError;
+
+        fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+            self.pending_key = Some(key_to_string(key.serialize(JsonSerializer)?)?);
+            Ok(())
+        }
+
+        fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+            let key = self.pending_key.take().ok_or_else(|| ser::Error::custom("object value without a key"))?;
+            self.object.insert(key, value.serialize(JsonSerializer)?);
+            Ok(())
+        }
+
+        fn end(self) -> Result<Json, SerdeError> {
+            Ok(wrap_variant(self.variant, Json::Object(self.object)))
+        }
+    }
+
+    impl ser::SerializeStruct for JsonMap {
+        type Ok = Json;
+        type Error = SerdeError;
+
+        fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError> {
+            self.object.insert(key.to_string(), value.serialize(JsonSerializer)?);
+            Ok(())
+        }
+
+        fn end(self) -> Result<Json, SerdeError> {
+            ser::SerializeMap::end(self)
+        }
+    }
+
+    impl ser::SerializeStructVariant for JsonMap {
+        type Ok = Json;
+        type Error = SerdeError;
+
+        fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError> {
+            ser::SerializeStruct::serialize_field(self, key, value)
+        }
+
+        fn end(self) -> Result<Json, SerdeError> {
+            ser::SerializeMap::end(self)
+        }
+    }
+
+
+    impl<'de> de::Deserializer<'de> for Json {
+        type Error = SerdeError;
+
+        fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+            match self {
+                Json::Null => visitor.visit_unit(),
+                Json::Boolean(b) => visitor.visit_bool(b),
+                // Integral numbers are visited as integers, since integer fields do not accept floats
+                Json::Number(n) if n.fract() == 0.0 && (0.0..18446744073709551616.0).contains(&n) => visitor.visit_u64(n as u64),
+                Json::Number(n) if n.fract() == 0.0 && (-9223372036854775808.0..0.0).contains(&n) => visitor.visit_i64(n as i64),
+                Json::Number(n) => visitor.visit_f64(n),
+                Json::String(s) => visitor.visit_string(s),
+                Json::Array(array) => visitor.visit_seq(JsonSeqAccess { values: array.into_iter() }),
+                Json::Object(map) => visitor.visit_map(JsonMapAccess { fields: map.into_iter(), pending_value: None }),
+            }
+        }
+
+        fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+            match self {
+                Json::Null => visitor.visit_none(),
+                value => visitor.visit_some(value),
+            }
+        }
+
+        fn deserialize_newtype_struct<V: de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+            visitor.visit_newtype_struct(self)
+        }
+
+        fn deserialize_enum<V: de::Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str],
+                                                 visitor: V) -> Result<V::Value, SerdeError> {
+            match self {
+                Json::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+                Json::Object(map) if map.len() == 1 => {
+                    let (variant, value) = map.into_iter().next().unwrap();
+                    visitor.visit_enum(JsonEnumAccess { variant, value })
+                },
+                other => Err(de::Error::invalid_type(unexpected(&other), &"a string or an object with a single field")),
+            }
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+        }
+    }
+
+    fn unexpected<'a>(json: &'a Json) -> de::Unexpected<'a> {
+        match json {
+            Json::Null => de::Unexpected::Unit,
+            Json::Boolean(b) => de::Unexpected::Bool(*b),
+            Json::Number(n) => de::Unexpected::Float(*n),
+            Json::String(s) => de::Unexpected::Str(s),
+            Json::Array(_) => de::Unexpected::Seq,
+            Json::Object(_) => de::Unexpected::Map,
+        }
+    }
+
+    struct JsonSeqAccess {
+        values: vec::IntoIter<Json>,
+    }
+
+    impl<'de> de::SeqAccess<'de> for JsonSeqAccess {
+        type Error = SerdeError;
+
+        fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, SerdeError> {
+            match self.values.next() {
+                Some(value) => seed.deserialize(value).map(Some),
+                None => Ok(None),
+            }
+        }
+
+        fn size_hint(&self) -> Option<usize> {
+            Some(self.values.len())
+        }
+    }
+
+    struct JsonMapAccess {
+        fields: btree_map::IntoIter<String, Json>,
+        pending_value: Option<Json>,
+    }
+
+    impl<'de> de::MapAccess<'de> for JsonMapAccess {
+        type Error = SerdeError;
+
+        fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, SerdeError> {
+            match self.fields.next() {
+                Some((key, value)) => {
+                    self.pending_value = Some(value);
+                    seed.deserialize(Json::String(key)).map(Some)
+                },
+                None => Ok(None),
+            }
+        }
+
+        fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, SerdeError> {
+            match self.pending_value.take() {
+                Some(value) => seed.deserialize(value),
+                None => Err(de::Error::custom("object value without a key")),
+            }
+        }
+    }
+
+    struct JsonEnumAccess {
+        variant: String,
+        value: Json,
+    }
+
+    impl<'de> de::EnumAccess<'de> for JsonEnumAccess {
+        type Error = SerdeError;
+        type Variant = Json;
+
+        fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Json), SerdeError> {
+            let variant = seed.deserialize(Json::String(self.variant))?;
+            Ok((variant, self.value))
+        }
+    }
+
+    impl<'de> de::VariantAccess<'de> for Json {
+        type Error = SerdeError;
+
+        fn unit_variant(self) -> Result<(), SerdeError> {
+            de::Deserialize::deserialize(self)
+        }
+
+        fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, SerdeError> {
+            seed.deserialize(self)
+        }
+
+        fn tuple_variant<V: de::Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+            de::Deserializer::deserialize_seq(self, visitor)
+        }
+
+        fn struct_variant<V: de::Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, SerdeError> {
+            de::Deserializer::deserialize_map(self, visitor)
+        }
+    }
+
+
+    /**
+     * Serializer that sends the JC packets of a value straight into a channel, following the same protocols
+     * as serialise_json, so any serializable value can be sent to eval without building a Json first.
+     * Arrays and objects whose size is not known in advance are sent once all their values were serialised
+     */
+    pub(crate) struct JcSerializer {
+        pub(crate) sender: mpsc::Sender<JC>,
+    }
+
+    /**
+     * Serialises a value into the sender channel, the same way serialise_json does for a Json
+     */
+    pub(crate) fn serialise_to_jc<T: Serialize + ?Sized>(value: &T, sender: mpsc::Sender<JC>) -> Result<(), SerdeError> {
+        value.serialize(JcSerializer { sender })
+    }
+
+    fn send(sender: &mpsc::Sender<JC>, packet: JC) -> Result<(), SerdeError> {
+        sender.send(packet).map_err(|_| ser::Error::custom("the receiving end of the JC channel was dropped"))
+    }
+
+    /**
+     * Opens the single field object that holds the value of a tuple or struct variant,
+     * and returns the channel of the value
+     */
+    fn open_variant(sender: &mpsc::Sender<JC>, variant: &'static str) -> Result<mpsc::Sender<JC>, SerdeError> {
+        let (value_sender, value_receiver) = mpsc::channel::<JC>();
+        send(sender, JC::ObjectStart)?;
+        send(sender, JC::ArrayLen(1))?;
+        send(sender, JC::String(variant.to_string()))?;
+        send(sender, JC::Stream(value_receiver))?;
+        Ok(value_sender)
+    }
+
+    pub(crate) struct JcSeq {
+        sender: mpsc::Sender<JC>,
+        buffered: Option<Vec<mpsc::Receiver<JC>>>, // used when the length was not known in advance
+        variant_sender: Option<mpsc::Sender<JC>>,   // channel of the object that wraps a tuple variant
+    }
+
+    pub(crate) struct JcMap {
+        sender: mpsc::Sender<JC>,
+        buffered: Option<Vec<(String, mpsc::Receiver<JC>)>>, // used when the length was not known in advance
+        pending_key: Option<String>,
+        variant_sender: Option<mpsc::Sender<JC>>, // channel of the object that wraps a struct variant
+    }
+
+    impl JcSeq {
+        fn new(sender: mpsc::Sender<JC>, len: Option<usize>, variant_sender: Option<mpsc::Sender<JC>>) -> Result<JcSeq, SerdeError> {
+            if let Some(len) = len {
+                send(&sender, JC::ArrayStart)?;
+                send(&sender, JC::ArrayLen(len))?;
+            }
+            Ok(JcSeq { sender, buffered: if len.is_none() { Some(vec![]) } else { None }, variant_sender })
+        }
+
+        fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+            let (value_sender, value_receiver) = mpsc::channel::<JC>();
+            match &mut self.buffered {
+                Some(buffered) => buffered.push(value_receiver),
+                None => send(&self.sender, JC::Stream(value_receiver))?,
+            }
+            value.serialize(JcSerializer { sender: value_sender })
+        }
+
+        fn finish(self) -> Result<(), SerdeError> {
+            if let Some(buffered) = self.buffered {
+                send(&self.sender, JC::ArrayStart)?;
+                send(&self.sender, JC::ArrayLen(buffered.len()))?;
+                for value_receiver in buffered {
+                    send(&self.sender, JC::Stream(value_receiver))?;
+                }
+            }
+            send(&self.sender, JC::ArrayEnd)?;
+            match self.variant_sender {
+                Some(variant_sender) => send(&variant_sender, JC::ObjectEnd),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl JcMap {
+        fn new(sender: mpsc::Sender<JC>, len: Option<usize>, variant_sender: Option<mpsc::Sender<JC>>) -> Result<JcMap, SerdeError> {
+            if let Some(len) = len {
+                send(&sender, JC::ObjectStart)?;
+                send(&sender, JC::ArrayLen(len))?;
+            }
+            Ok(JcMap { sender, buffered: if len.is_none() { Some(vec![]) } else { None }, pending_key: None, variant_sender })
+        }
+
+        fn field<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<(), SerdeError> {
+            let (value_sender, value_receiver) = mpsc::channel::<JC>();
+            match &mut self.buffered {
+                Some(buffered) => buffered.push((key, value_receiver)),
+                None => {
+                    send(&self.sender, JC::String(key))?;
+                    send(&self.sender, JC::Stream(value_receiver))?;
+                }
+            }
+            value.serialize(JcSerializer { sender: value_sender })
+        }
+
+        fn finish(self) -> Result<(), SerdeError> {
+            if let Some(buffered) = self.buffered {
+                send(&self.sender, JC::ObjectStart)?;
+                send(&self.sender, JC::ArrayLen(buffered.len()))?;
+                for (key, value_receiver) in buffered {
+                    send(&self.sender, JC::String(key))?;
+                    send(&self.sender, JC::Stream(value_receiver))?;
+                }
+            }
+            send(&self.sender, JC::ObjectEnd)?;
+            match self.variant_sender {
+                Some(variant_sender) => send(&variant_sender, JC::ObjectEnd),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl ser::Serializer for JcSerializer {
+        type Ok = ();
+        type Error = SerdeError;
+        type SerializeSeq = JcSeq;
+        type SerializeTuple = JcSeq;
+        type SerializeTupleStruct = JcSeq;
+        type SerializeTupleVariant = JcSeq;
+        type SerializeMap = JcMap;
+        type SerializeStruct = JcMap;
+        type SerializeStructVariant = JcMap;
+
+        fn serialize_bool(self, v: bool) -> Result<(), SerdeError> { send(&self.sender, JC::Boolean(v)) }
+        fn serialize_i8(self, v: i8) -> Result<(), SerdeError> { send(&self.sender, JC::Number(v as f64)) }
+        fn serialize_i16(self, v: i16) -> Result<(), SerdeError> { send(&self.sender, JC::Number(v as f64)) }
+        fn serialize_i32(self, v: i32) -> Result<(), SerdeError> { send(&self.sender, JC::Number(v as f64)) }
+        fn serialize_i64(self, v: i64) -> Result<(), SerdeError> { send(&self.sender, JC::Number(v as f64)) }
+        fn serialize_u8(self, v: u8) -> Result<(), SerdeError> { send(&self.sender, JC::Number(v as f64)) }
+        fn serialize_u16(self, v: u16) -> Result<(), SerdeError> { send(&self.sender, JC::Number(v as f64)) }
+        fn serialize_u32(self, v: u32) -> Result<(), SerdeError> { send(&self.sender, JC::Number(v as f64)) }
+        fn serialize_u64(self, v: u64) -> Result<(), SerdeError> { send(&self.sender, JC::Number(v as f64)) }
+        fn serialize_f32(self, v: f32) -> Result<(), SerdeError> { send(&self.sender, JC::Number(v as f64)) }
+        fn serialize_f64(self, v: f64) -> Result<(), SerdeError> { send(&self.sender, JC::Number(v)) }
+        fn serialize_char(self, v: char) -> Result<(), SerdeError> { send(&self.sender, JC::String(v.to_string())) }
+        fn serialize_str(self, v: &str) -> Result<(), SerdeError> { send(&self.sender, JC::String(v.to_string())) }
+
+        fn serialize_bytes(self, v: &[u8]) -> Result<(), SerdeError> {
+            use serde::ser::SerializeSeq;
+            let mut seq = self.serialize_seq(Some(v.len()))?;
+            for b in v {
+                seq.serialize_element(b)?;
+            }
+            seq.end()
+        }
+
+        fn serialize_none(self) -> Result<(), SerdeError> { send(&self.sender, JC::Null) }
+
+        fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), SerdeError> {
+            value.serialize(self)
+        }
+
+        fn serialize_unit(self) -> Result<(), SerdeError> { send(&self.sender, JC::Null) }
+        fn serialize_unit_struct(self, _name: &'static str) -> Result<(), SerdeError> { send(&self.sender, JC::Null) }
+
+        fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), SerdeError> {
+            send(&self.sender, JC::String(variant.to_string()))
+        }
+
+        fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), SerdeError> {
+            value.serialize(self)
+        }
+
+        fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, variant: &'static str,
+                                                            value: &T) -> Result<(), SerdeError> {
+            let value_sender = open_variant(&self.sender, variant)?;
+            value.serialize(JcSerializer { sender: value_sender })?;
+            send(&self.sender, JC::ObjectEnd)
+        }
+
+        fn serialize_seq(self, len: Option<usize>) -> Result<JcSeq, SerdeError> {
+            JcSeq::new(self.sender, len, None)
+        }
+
+        fn serialize_tuple(self, len: usize) -> Result<JcSeq, SerdeError> {
+            JcSeq::new(self.sender, Some(len), None)
+        }
+
+        fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<JcSeq, SerdeError> {
+            JcSeq::new(self.sender, Some(len), None)
+        }
+
+        fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                                   len: usize) -> Result<JcSeq, SerdeError> {
+            let value_sender = open_variant(&self.sender, variant)?;
+            JcSeq::new(value_sender, Some(len), Some(self.sender))
+        }
+
+        fn serialize_map(self, len: Option<usize>) -> Result<JcMap, SerdeError> {
+            JcMap::new(self.sender, len, None)
+        }
+
+        fn serialize_struct(self, _name: &'static str, len: usize) -> Result<JcMap, SerdeError> {
+            JcMap::new(self.sender, Some(len), None)
+        }
+
+        fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                                    len: usize) -> Result<JcMap, SerdeError> {
+            let value_sender = open_variant(&self.sender, variant)?;
+            JcMap::new(value_sender, Some(len), Some(self.sender))
+        }
+    }
+
+    impl ser::SerializeSeq for JcSeq {
+        type Ok = ();
+        type Error = SerdeError;
+
+        fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+            self.element(value)
+        }
+
+        fn end(self) -> Result<(), SerdeError> {
+            self.finish()
+        }
+    }
+
+    impl ser::SerializeTuple for JcSeq {
+        type Ok = ();
+        type Error = SerdeError;
+
+        fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+            self.element(value)
+        }
+
+        fn end(self) -> Result<(), SerdeError> {
+            self.finish()
+        }
+    }
 
-    let socialProfiles = Json::Array(vec![
-            Json::Object(BTreeMap::from([
-                ("name".to_string(), Json::String("Twitter".to_string())),
-                ("link".to_string(), Json::String("https://twitter.com".to_string()))
-            ])),
-            Json::Object(BTreeMap::from([
-                ("name".to_string(), Json::String("Facebook".to_string())),
-                ("link".to_string(), Json::String("https://www.facebook.com".to_string()))
-            ]))
-        ]);
+    impl ser::SerializeTupleStruct for JcSeq {
+        type Ok = ();
+        type Error = SerdeError;
+
+        fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+            self.element(value)
+        }
+
+        fn end(self) -> Result<(), SerdeError> {
+            self.finish()
+        }
+    }
+
+    impl ser::SerializeTupleVariant for JcSeq {
+        type Ok = ();
+        type Error = SerdeError;
+
+        fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+            self.element(value)
+        }
+
+        fn end(self) -> Result<(), SerdeError> {
+            self.finish()
+        }
+    }
+
+    impl ser::SerializeMap for JcMap {
+        type Ok = ();
+        type Error = SerdeError;
+
+        fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+            self.pending_key = Some(key_to_string(key.serialize(JsonSerializer)?)?);
+            Ok(())
+        }
+
+        fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+            let key = self.pending_key.take().ok_or_else(|| ser::Error::custom("object value without a key"))?;
+            self.field(key, value)
+        }
+
+        fn end(self) -> Result<(), SerdeError> {
+            self.finish()
+        }
+    }
+
+    impl ser::SerializeStruct for JcMap {
+        type Ok = ();
+        type Error = SerdeError;
+
+        fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError> {
+            self.field(key.to_string(), value)
+        }
+
+        fn end(self) -> Result<(), SerdeError> {
+            self.finish()
+        }
+    }
+
+    impl ser::SerializeStructVariant for JcMap {
+        type Ok = ();
+        type Error = SerdeError;
+
+        fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError> {
+            self.field(key.to_string(), value)
+        }
+
+        fn end(self) -> Result<(), SerdeError> {
+            self.finish()
+        }
+    }
+
+    impl SerdeError {
+        /**
+         * Sets the path of errors that do not have one yet. Errors are created without a path by serde,
+         * so the innermost deserializer that sees the error is the one that knows where it happened
+         */
+        fn at(mut self, path: &[PathStep]) -> SerdeError {
+            if let Acessor::End = self.path {
+                self.path = path_acessor(path);
+            }
+            self
+        }
+    }
+
+    /**
+     * Builds any deserializable value straight from the JC packets received through the receiver channel,
+     * such as the output of eval, without deserialising it into a Json first.
+     * Errors carry the acessor that reaches the value that could not be deserialised
+     */
+    pub(crate) fn from_jc_stream<T: DeserializeOwned>(receiver: mpsc::Receiver<JC>) -> Result<T, SerdeError> {
+        T::deserialize(JcDeserializer { receiver, first: None, path: vec![] })
+    }
+
+    /**
+     * Deserializer over the packets of a single json value
+     */
+    struct JcDeserializer {
+        receiver: mpsc::Receiver<JC>,
+        first: Option<JC>, // first packet of the value, when it was already received
+        path: Vec<PathStep>,
+    }
+
+    fn receive(receiver: &mpsc::Receiver<JC>) -> Result<JC, SerdeError> {
+        receiver.recv().map_err(|_| de::Error::custom("the JC stream ended before the value was complete"))
+    }
+
+    impl JcDeserializer {
+        fn next_packet(&mut self) -> Result<JC, SerdeError> {
+            match self.first.take() {
+                Some(packet) => Ok(packet),
+                None => receive(&self.receiver),
+            }
+        }
+
+        fn error(&self, message: &str) -> SerdeError {
+            SerdeError { path: path_acessor(&self.path), message: message.to_string() }
+        }
+
+        fn expect_len(&self) -> Result<usize, SerdeError> {
+            match receive(&self.receiver)? {
+                JC::ArrayLen(len) => Ok(len),
+                _ => Err(self.error("expected ArrayLen")),
+            }
+        }
+
+        /**
+         * Deserializer for a value of an array or object, which is normally sent through its own stream.
+         * Simple values sent without a stream, like the ones from the Map acessor, are accepted as well
+         */
+        fn value(&self, packet: JC, step: PathStep) -> Result<JcDeserializer, SerdeError> {
+            let mut path = self.path.clone();
+            path.push(step);
+            match packet {
+                JC::Stream(receiver) => Ok(JcDeserializer { receiver, first: None, path }),
+                packet @ JC::Number(_) | packet @ JC::String(_) | packet @ JC::Boolean(_) | packet @ JC::Null => {
+                    let (_, receiver) = mpsc::channel::<JC>();
+                    Ok(JcDeserializer { receiver, first: Some(packet), path })
+                },
+                _ => Err(self.error("expected a JC Stream or a simple value")),
+            }
+        }
+    }
+
+    impl<'de> de::Deserializer<'de> for JcDeserializer {
+        type Error = SerdeError;
+
+        fn deserialize_any<V: de::Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, SerdeError> {
+            let path = self.path.clone();
+            let result = match self.next_packet()? {
+                JC::Null => visitor.visit_unit(),
+                JC::Boolean(b) => visitor.visit_bool(b),
+                JC::Number(n) => Json::Number(n).deserialize_any(visitor),
+                JC::String(s) => visitor.visit_string(s),
+                JC::ArrayStart => {
+                    let remaining = self.expect_len()?;
+                    let mut seq = JcSeqAccess { parent: &self, remaining, index: 0 };
+                    let value = visitor.visit_seq(&mut seq)?;
+                    // Values the visitor did not ask for are thrown away
+                    while seq.remaining > 0 {
+                        seq.remaining -= 1;
+                        if let JC::Stream(value_stream) = receive(&self.receiver)? {
+                            consume_value(value_stream);
+                        }
+                    }
+                    match receive(&self.receiver)? {
+                        JC::ArrayEnd => Ok(value),
+                        _ => Err(self.error("expected ArrayEnd")),
+                    }
+                },
+                JC::ObjectStart => {
+                    let remaining = self.expect_len()?;
+                    let mut map = JcMapAccess { parent: &self, remaining, key: None };
+                    let value = visitor.visit_map(&mut map)?;
+                    while map.remaining > 0 {
+                        map.remaining -= 1;
+                        receive(&self.receiver)?; // key
+                        if let JC::Stream(value_stream) = receive(&self.receiver)? {
+                            consume_value(value_stream);
+                        }
+                    }
+                    match receive(&self.receiver)? {
+                        JC::ObjectEnd => Ok(value),
+                        _ => Err(self.error("expected ObjectEnd")),
+                    }
+                },
+                _ => Err(self.error("unexpected ArrayEnd, ObjectEnd, ArrayLen or Stream")),
+            };
+            result.map_err(|error| error.at(&path))
+        }
+
+        fn deserialize_option<V: de::Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, SerdeError> {
+            let path = self.path.clone();
+            match self.next_packet()? {
+                JC::Null => visitor.visit_none(),
+                packet => {
+                    self.first = Some(packet);
+                    visitor.visit_some(self)
+                },
+            }.map_err(|error| error.at(&path))
+        }
+
+        fn deserialize_newtype_struct<V: de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+            let path = self.path.clone();
+            visitor.visit_newtype_struct(self).map_err(|error| error.at(&path))
+        }
+
+        fn deserialize_enum<V: de::Visitor<'de>>(mut self, _name: &'static str, _variants: &'static [&'static str],
+                                                 visitor: V) -> Result<V::Value, SerdeError> {
+            let path = self.path.clone();
+            match self.next_packet()? {
+                JC::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+                JC::ObjectStart => {
+                    if self.expect_len()? != 1 {
+                        return Err(self.error("an enum object must have a single field"));
+                    }
+                    let variant = match receive(&self.receiver)? {
+                        JC::String(variant) => variant,
+                        _ => return Err(self.error("expected a key for an object field")),
+                    };
+                    let packet = receive(&self.receiver)?;
+                    let value = self.value(packet, PathStep::Field(variant.to_string()))?;
+                    let result = visitor.visit_enum(JcEnumAccess { variant, value })?;
+                    match receive(&self.receiver)? {
+                        JC::ObjectEnd => Ok(result),
+                        _ => Err(self.error("expected ObjectEnd")),
+                    }
+                },
+                _ => Err(self.error("expected a string or an object with a single field")),
+            }.map_err(|error| error.at(&path))
+        }
+
+        fn deserialize_ignored_any<V: de::Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, SerdeError> {
+            let packet = self.next_packet()?;
+            consume_value_from(packet, self.receiver);
+            visitor.visit_unit()
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier
+        }
+    }
+
+    struct JcSeqAccess<'a> {
+        parent: &'a JcDeserializer,
+        remaining: usize,
+        index: usize,
+    }
+
+    impl<'de, 'a> de::SeqAccess<'de> for &mut JcSeqAccess<'a> {
+        type Error = SerdeError;
+
+        fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, SerdeError> {
+            if self.remaining == 0 {
+                return Ok(None);
+            }
+            self.remaining -= 1;
+            let packet = receive(&self.parent.receiver)?;
+            let value = self.parent.value(packet, PathStep::Index(self.index))?;
+            self.index += 1;
+            seed.deserialize(value).map(Some)
+        }
+
+        fn size_hint(&self) -> Option<usize> {
+            Some(self.remaining)
+        }
+    }
+
+    struct JcMapAccess<'a> {
+        parent: &'a JcDeserializer,
+        remaining: usize,
+        key: Option<String>,
+    }
+
+    impl<'de, 'a> de::MapAccess<'de> for &mut JcMapAccess<'a> {
+        type Error = SerdeError;
+
+        fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, SerdeError> {
+            if self.remaining == 0 {
+                return Ok(None);
+            }
+            self.remaining -= 1;
+            match receive(&self.parent.receiver)? {
+                JC::String(key) => {
+                    self.key = Some(key.to_string());
+                    seed.deserialize(key.into_deserializer()).map(Some)
+                },
+                _ => Err(self.parent.error("expected a key for an object field")),
+            }
+        }
+
+        fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, SerdeError> {
+            let key = self.key.take().ok_or_else(|| self.parent.error("object value without a key"))?;
+            let packet = receive(&self.parent.receiver)?;
+            seed.deserialize(self.parent.value(packet, PathStep::Field(key))?)
+        }
+    }
+
+    struct JcEnumAccess {
+        variant: String,
+        value: JcDeserializer,
+    }
+
+    impl<'de> de::EnumAccess<'de> for JcEnumAccess {
+        type Error = SerdeError;
+        type Variant = JcDeserializer;
+
+        fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, JcDeserializer), SerdeError> {
+            let variant = seed.deserialize(self.variant.into_deserializer())?;
+            Ok((variant, self.value))
+        }
+    }
+
+    impl<'de> de::VariantAccess<'de> for JcDeserializer {
+        type Error = SerdeError;
+
+        fn unit_variant(self) -> Result<(), SerdeError> {
+            de::Deserialize::deserialize(self)
+        }
+
+        fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, SerdeError> {
+            seed.deserialize(self)
+        }
+
+        fn tuple_variant<V: de::Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+            de::Deserializer::deserialize_seq(self, visitor)
+        }
+
+        fn struct_variant<V: de::Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, SerdeError> {
+            de::Deserializer::deserialize_map(self, visitor)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use super::super::{deserialise_json, eval, jc_stream};
+        use serde::{Deserialize, Serialize};
+        use std::thread;
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Network {
+            Twitter,
+            Other { name: String },
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Profile {
+            network: Network,
+            link: String,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Person {
+            name: String,
+            age: u32,
+            languages: Vec<String>,
+            nickname: Option<String>,
+            profiles: Vec<Profile>,
+        }
+
+        fn person() -> Person {
+            Person {
+                name: String::from("Jason Ray"),
+                age: 31,
+                languages: vec![String::from("Java"), String::from("Rust")],
+                nickname: None,
+                profiles: vec![
+                    Profile { network: Network::Twitter, link: String::from("https://twitter.com/jasonray") },
+                    Profile { network: Network::Other { name: String::from("Mastodon") }, link: String::from("https://mastodon.social/@jason") },
+                ],
+            }
+        }
+
+        fn person_json() -> Json {
+            json!({
+                "name": "Jason Ray",
+                "age": 31,
+                "languages": ["Java", "Rust"],
+                "nickname": null,
+                "profiles": [
+                    {"network": "Twitter", "link": "https://twitter.com/jasonray"},
+                    {"network": {"Other": {"name": "Mastodon"}}, "link": "https://mastodon.social/@jason"}
+                ]
+            })
+        }
+
+        #[test]
+        fn to_json_and_from_json_round_trip() {
+            assert_eq!(to_json(&person()), person_json());
+            assert_eq!(from_json::<Person>(person_json()).unwrap(), person());
+            assert_eq!(from_json::<Json>(person_json()).unwrap(), person_json());
+        }
+
+        #[test]
+        fn from_json_rejects_values_of_the_wrong_type() {
+            let mut json = person_json();
+            if let Json::Object(ref mut fields) = json {
+                fields.insert(String::from("age"), Json::from("old"));
+            }
+            assert!(from_json::<Person>(json).is_err());
+            assert!(from_json::<Vec<u32>>(json!([1, -2])).is_err());
+        }
+
+        #[test]
+        #[should_panic(expected = "(To Json)")]
+        fn to_json_panics_for_keys_that_are_not_json() {
+            let mut map = BTreeMap::new();
+            map.insert(vec![1], 1);
+            to_json(&map);
+        }
+
+        #[test]
+        fn serialise_to_jc_matches_serialise_json() {
+            let (tx, rx) = mpsc::channel();
+            serialise_to_jc(&person(), tx).unwrap();
+            assert_eq!(deserialise_json(rx), person_json());
+        }
+
+        #[test]
+        fn from_jc_stream_reads_serialised_json() {
+            assert_eq!(from_jc_stream::<Person>(jc_stream(&person_json())).unwrap(), person());
+        }
+
+        #[test]
+        fn from_jc_stream_reads_the_output_of_eval() {
+            let acessor: Acessor = r#"."profiles"[1]"#.parse().unwrap();
+            let (tx, rx) = mpsc::channel();
+            let handle = thread::spawn(move || eval(&acessor, jc_stream(&person_json()), tx));
+            let profile = from_jc_stream::<Profile>(rx).unwrap();
+            assert_eq!(profile, Profile { network: Network::Other { name: String::from("Mastodon") }, link: String::from("https://mastodon.social/@jason") });
+            handle.join().unwrap().unwrap();
+        }
+
+        #[test]
+        fn from_jc_stream_reports_the_path_of_the_error() {
+            let mut json = person_json();
+            if let Json::Object(ref mut fields) = json {
+                fields.insert(String::from("profiles"), json!([{"network": "Twitter", "link": "a"}, {"network": "Twitter", "link": 3}]));
+            }
+            let error = from_jc_stream::<Person>(jc_stream(&json)).unwrap_err();
+            assert_eq!(error.path.to_string(), r#"."profiles"[1]."link""#);
+        }
+
+        #[test]
+        fn from_jc_stream_rejects_incomplete_streams() {
+            let (tx, rx) = mpsc::channel();
+            tx.send(JC::ArrayStart).unwrap();
+            tx.send(JC::ArrayLen(2)).unwrap();
+            drop(tx);
+            assert!(from_jc_stream::<Vec<u32>>(rx).is_err());
+        }
+    }
+}
+
+
+
+
+#[allow(non_snake_case)] // Kept under its original name
+fn Printer(json: &Json, depth: usize) {
+    walk_json(json, &mut PrintVisitor { depth });
+}
+
+/**
+ * Visitor that prints the values it walks, used by Printer. It can also print a JC stream directly with walk_jc
+ */
+struct PrintVisitor {
+    depth: usize,
+}
+
+impl PrintVisitor {
+    fn indent(&self) -> String {
+        " ".repeat(self.depth)
+    }
+}
+
+impl JcVisitor for PrintVisitor {
+    fn on_scalar(&mut self, value: Json) {
+        match value {
+            Json::Null => println!("{}", self.indent()),
+            Json::String(s) => println!("{}\"{}\"", self.indent(), s),
+            Json::Number(n) => println!("{}{}", self.indent(), n),
+            Json::Boolean(b) => println!("{}{}", self.indent(), b),
+            _ => (),
+        }
+    }
+
+    fn enter_array(&mut self, _len: usize) {
+        println!("{}[", self.indent());
+        self.depth += 1;
+    }
+
+    fn exit_array(&mut self) {
+        self.depth -= 1;
+        println!("{}]", self.indent());
+    }
+
+    fn enter_object(&mut self, _len: usize) {
+        println!("{}{{", self.indent());
+        self.depth += 1;
+    }
+
+    fn on_key(&mut self, key: String) {
+        print!(" {}\"{}\": ", " ".repeat(self.depth - 1), key);
+    }
 
-    let address = Json::Object(BTreeMap::from([
-        ("city".to_string(), Json::String("New York".to_string())),
-        ("postalCode".to_string(), Json::Number(64780.0)),
-        ("Country".to_string(), Json::String("USA".to_string())),
-    ]));
-
-    let languages = Json::Array(vec![
-        Json::String("Java".to_string()),
-        Json::String("Node.js".to_string()),
-        Json::String("Javascript".to_string()),
-        Json::String("JSON".to_string()),
-    ]);
-
-    let json_test = Json::Object(BTreeMap::from([
-        ("name".to_string(), Json::String("Jason Ray".to_string())),
-        ("profession".to_string(), Json::String("Software Enginner".to_string())),
-        ("age".to_string(), Json::Number(31.0)),
-        ("address".to_string(), address),
-        ("languages".to_string(), languages),
-        ("socialProfiles".to_string(), socialProfiles),
-    ]));
+    fn exit_object(&mut self) {
+        self.depth -= 1;
+        println!("{}}}", self.indent());
+    }
+}
+
+fn main() {
+    
+
+    let json_test = json!({
+        "name": "Jason Ray",
+        "profession": "Software Enginner",
+        "age": 31,
+        "address": {
+            "city": "New York",
+            "postalCode": 64780,
+            "Country": "USA"
+        },
+        "languages": ["Java", "Node.js", "Javascript", "JSON"],
+        "socialProfiles": [
+            { "name": "Twitter", "link": "https://twitter.com" },
+            { "name": "Facebook", "link": "https://www.facebook.com" }
+        ]
+    });
 
 
     // Prints Json object
     println!("Original Json:");
     Printer(&json_test, 0);
 
+    // Values can be read in memory, without matching on the variants of Json or starting the pipeline threads
+    let profiles_acessor = Acessor::ObjectField("socialProfiles".to_string(), Box::new(Acessor::Map(Box::new(Acessor::ObjectField("name".to_string(), Box::new(Acessor::End))))));
+    let profile_names = json_test.get_path(&profiles_acessor).map_or(Json::Null, |names| names.into_owned());
+    println!("\n{} ({}) lives in {} and is on {}",
+        json_test["name"].as_str().unwrap_or("Someone"),
+        json_test["age"].as_i64().map_or("unknown age".to_string(), |age| age.to_string()),
+        json_test["address"]["city"].as_str().unwrap_or("an unknown city"),
+        profile_names.as_array().map_or(vec![], |names| names.iter().filter_map(Json::as_str).collect::<Vec<_>>()).join(", "));
+    let field_kinds: Vec<String> = json_test.as_object().into_iter().flatten().map(|(key, value)| {
+        let kind = if value.is_object() {
+            "object"
+        } else if value.is_array() {
+            "array"
+        } else if value.is_string() {
+            "string"
+        } else if value.is_number() {
+            "number"
+        } else if value.is_boolean() {
+            "boolean"
+        } else {
+            "null"
+        };
+        format!("{} is {} {}", key, if kind == "object" || kind == "array" { "an" } else { "a" }, kind)
+    }).collect();
+    println!("{}, and the first language is {}", field_kinds.join(", "), json_test["languages"][0]);
+
 
     // Acessor to apply to the json object
     // Uncomment the one you which to apply and leave the others commented
@@ -473,6 +9531,9 @@ fn main() {
     // ."socialProfiles" Map ."name" End
     //let acessor = Acessor::ObjectField("socialProfiles".to_string(), Box::new(Acessor::Map(Box::new(Acessor::ObjectField("name".to_string(), Box::new(Acessor::End))))));
 
+    // ."socialProfiles" Map ."link" End
+    //let acessor = Acessor::ObjectField("socialProfiles".to_string(), Box::new(Acessor::Map(Box::new(Acessor::ObjectField("link".to_string(), Box::new(Acessor::End))))));
+
     // ."address" ."postalCode" End
     //let acessor = Acessor::ObjectField("address".to_string(), Box::new(Acessor::ObjectField("postalCode".to_string(), Box::new(Acessor::End))));
 
@@ -488,15 +9549,42 @@ fn main() {
     println!("{:?}\n", acessor);
 
 
+    // Schema the json is checked against while it goes through the pipeline
+    let schema = json!({
+        "type": "object",
+        "required": ["name", "age"],
+        "properties": {
+            "age": { "type": "integer", "minimum": 0 },
+            "languages": { "type": "array", "items": { "type": "string" }, "uniqueItems": true },
+            "socialProfiles": {
+                "type": "array",
+                "items": { "type": "object", "required": ["name", "link"], "properties": { "link": { "pattern": "^https://" } } }
+            }
+        }
+    });
+
     let (sender_1, receiver_1) = mpsc::channel::<JC>();
     let (sender_2, receiver_2) = mpsc::channel::<JC>();
+    let (sender_3, receiver_3) = mpsc::channel::<JC>();
+    let (sender_4, receiver_4) = mpsc::channel::<JC>();
 
+    let original_json = json_test.clone();
+    let json_to_update = json_test.clone();
     let handle_serialiser = thread::spawn(move || {
         serialise_json(&json_test, sender_1);
     });
 
+    // The stream is checked against the JC protocols before anything else reads it
+    let handle_protocol = thread::spawn(move || {
+        validate_jc(receiver_1, sender_4)
+    });
+
+    let handle_validator = thread::spawn(move || {
+        validate_schema(&schema, receiver_4, sender_3)
+    });
+
     let handle_eval = thread::spawn(move || {
-        eval(&acessor, receiver_1, sender_2);
+        eval(&acessor, receiver_3, sender_2).unwrap();
     });
 
     let handle_deserialiser = thread::spawn(move || {
@@ -509,5 +9597,213 @@ fn main() {
     handle_serialiser.join().unwrap();
     handle_eval.join().unwrap();
     handle_deserialiser.join().unwrap();
+    match handle_protocol.join().unwrap() {
+        Ok(validated) => println!("The serialised json followed the protocols in {} packets", validated.packets),
+        Err(error) => println!("Protocol error at {}", error),
+    }
+    for violation in handle_validator.join().unwrap() {
+        println!("Schema violation at {}", violation);
+    }
+
+
+    // Acessors can be chained into a pipeline with |, each stage evaluated in its own thread
+    let names_pipeline = Acessor::ObjectField("socialProfiles".to_string(), Box::new(Acessor::End))
+        | Acessor::Map(Box::new(Acessor::ObjectField("name".to_string(), Box::new(Acessor::End))))
+        | Acessor::ArrayEntry(1, Box::new(Acessor::End));
+    println!("\nSecond social profile name (in memory): {}", names_pipeline.apply(&original_json).unwrap());
+    // The stages can run one after the other in the current thread, or each in its own thread
+    let (sender_1, receiver_1) = mpsc::channel::<JC>();
+    let (sender_2, receiver_2) = mpsc::channel::<JC>();
+    serialise_json(&original_json, sender_1);
+    names_pipeline.eval(receiver_1, sender_2).unwrap();
+    println!("Second social profile name (evaluated in order): {}", deserialise_json(receiver_2));
+    let (sender_1, receiver_1) = mpsc::channel::<JC>();
+    let (sender_2, receiver_2) = mpsc::channel::<JC>();
+    let pipeline_json = original_json.clone();
+    let handle_serialiser = thread::spawn(move || {
+        serialise_json(&pipeline_json, sender_1);
+    });
+    let handle_stages = names_pipeline.with_options(EvalOptions { parallelism: 2, ..EvalOptions::default() }).spawn(receiver_1, sender_2);
+    println!("Second social profile name (streamed): {}", deserialise_json(receiver_2));
+    handle_serialiser.join().unwrap();
+    for handle_stage in handle_stages {
+        handle_stage.join().unwrap().unwrap();
+    }
+
+
+    // Queries written as text are parsed, checked and compiled once, and reused from the cache when asked again
+    let mut query_cache = QueryCache::new(2);
+    println!();
+    for text in [r#"."address"."city""#, r#"."languages" | length"#, r#"."address"."city""#, r#"."age" + 1 >= 18"#, "length | map(.)"] {
+        let query = match query_cache.get(text) {
+            Ok(query) => query,
+            Err(error) => {
+                println!("{} is rejected: {}", text, error);
+                continue;
+            },
+        };
+        let (sender_1, receiver_1) = mpsc::channel::<JC>();
+        let (sender_2, receiver_2) = mpsc::channel::<JC>();
+        let query_json = original_json.clone();
+        let projected_query = query.clone();
+        // Only the parts of the json the query reads are serialised
+        let handle_serialiser = thread::spawn(move || {
+            serialise_json_with_projection(&query_json, &projected_query, sender_1);
+        });
+        query.eval(receiver_1, sender_2).unwrap();
+        handle_serialiser.join().unwrap();
+        let result = deserialise_json(receiver_2);
+        assert_eq!(query.apply(&original_json).unwrap().into_owned(), result);
+        println!("{} reaches {}: {}", query, query.shape(), result);
+    }
+    println!("{} compiled queries are cached", query_cache.len());
+
+
+    // Variables are bound when the acessor is evaluated, so the same acessor serves different requests
+    let is_on: Acessor = r#"."socialProfiles" | map(."name" == $network) | any"#.parse().unwrap();
+    for network in ["Twitter", "Instagram"] {
+        let mut bindings = Bindings::new();
+        bindings.insert(String::from("network"), Json::String(network.to_string()));
+        let (sender_1, receiver_1) = mpsc::channel::<JC>();
+        let (sender_2, receiver_2) = mpsc::channel::<JC>();
+        serialise_json(&original_json, sender_1);
+        eval_with(&is_on, &bindings, receiver_1, sender_2).unwrap();
+        println!("Is on {}: {}", network, deserialise_json(receiver_2));
+    }
+
+
+    // Acessors can also update the json, in which case the whole json is sent with only the targeted values changed.
+    // Each update runs in its own thread, so the json streams through all of them at once
+    let updates = vec![
+        // ."socialProfiles" Map ."link" End
+        (Acessor::ObjectField("socialProfiles".to_string(), Box::new(Acessor::Map(Box::new(Acessor::ObjectField("link".to_string(), Box::new(Acessor::End)))))),
+         Update::Set(Json::String("<redacted>".to_string()))),
+        // ."address" ."postalCode" End
+        (Acessor::ObjectField("address".to_string(), Box::new(Acessor::ObjectField("postalCode".to_string(), Box::new(Acessor::End)))),
+         Update::Delete),
+        // ."profession" End
+        (Acessor::ObjectField("profession".to_string(), Box::new(Acessor::End)), Update::Rename("job".to_string())),
+        // ."languages" Map End
+        (Acessor::ObjectField("languages".to_string(), Box::new(Acessor::Map(Box::new(Acessor::End)))),
+         Update::Apply(Box::new(|language| Json::String(language.as_str().unwrap_or_default().to_lowercase())))),
+    ];
+    println!("\nJson after redacting, deleting, renaming and lowercasing: ");
+
+    let (sender_1, mut receiver_1) = mpsc::channel::<JC>();
+
+    let handle_serialiser = thread::spawn(move || {
+        serialise_json(&json_to_update, sender_1);
+    });
+
+    let mut handle_updates = vec![];
+    for (update_acessor, update) in updates {
+        let (sender_2, receiver_2) = mpsc::channel::<JC>();
+        handle_updates.push(thread::spawn(move || {
+            eval_update(&update_acessor, &update, receiver_1, sender_2).unwrap();
+        }));
+        receiver_1 = receiver_2;
+    }
+
+    let handle_deserialiser = thread::spawn(move || deserialise_json(receiver_1));
+
+    handle_serialiser.join().unwrap();
+    for handle_update in handle_updates {
+        handle_update.join().unwrap();
+    }
+    let updated_json = handle_deserialiser.join().unwrap();
+    Printer(&updated_json, 0);
+
+
+    println!("\nDifferences after the updates:");
+    for difference in json_diff(&original_json, &updated_json) {
+        println!("{}", difference);
+    }
+
+    // The updates as a Json Patch, which turns the original json into the updated one
+    let patch = diff(&original_json, &updated_json);
+    println!("\nJson Patch of the updates: {}", patch_to_json(&patch));
+    let mut patched_json = original_json.clone();
+    apply_patch(&mut patched_json, &patch_from_json(&patch_to_json(&patch)).unwrap()).unwrap();
+    assert_eq!(patched_json, updated_json);
+
+
+    // Json Merge Patch, streamed so that only the touched fields of the json are read
+    let merge_patch = json!({ "job": "Software Engineer", "address": { "Country": null } });
+    println!("\nJson after merging {}: ", merge_patch);
+
+    let (sender_1, receiver_1) = mpsc::channel::<JC>();
+    let (sender_2, receiver_2) = mpsc::channel::<JC>();
+
+    let json_to_merge = updated_json.clone();
+    let handle_serialiser = thread::spawn(move || {
+        serialise_json(&json_to_merge, sender_1);
+    });
+
+    let stream_patch = merge_patch.clone();
+    let handle_merge = thread::spawn(move || {
+        merge_patch_stream(&stream_patch, receiver_1, sender_2);
+    });
+
+    let handle_deserialiser = thread::spawn(move || deserialise_json(receiver_2));
+
+    handle_serialiser.join().unwrap();
+    handle_merge.join().unwrap();
+    let merged_json = handle_deserialiser.join().unwrap();
+    Printer(&merged_json, 0);
+
+    let mut merged_in_memory = updated_json.clone();
+    merged_in_memory.merge_patch(&merge_patch);
+    assert_eq!(merged_json, merged_in_memory);
+
+
+    // Schema inferred from every version of the json, the last one straight from its stream
+    let (sender_1, receiver_1) = mpsc::channel::<JC>();
+    let json_to_infer = merged_json.clone();
+    let handle_serialiser = thread::spawn(move || {
+        serialise_json(&json_to_infer, sender_1);
+    });
+    let streamed_schema = schema_of_stream(receiver_1);
+    handle_serialiser.join().unwrap();
+
+    let inferred_schema = merge_schemas(&infer_schema(vec![original_json, updated_json].into_iter()), &streamed_schema);
+    println!("\nSchema inferred from the json:");
+    Printer(&inferred_schema, 0);
+    assert!(validate_json(&inferred_schema, &merged_json).is_empty());
+
+
+    // Typed values convert to and from Json through serde, and can be streamed as JC packets without a Json in between
+    #[cfg(feature = "serde")]
+    {
+        #[derive(Debug, serde::Serialize, serde::Deserialize)]
+        struct SocialProfile {
+            name: String,
+            link: String,
+        }
+
+        let profiles: Vec<SocialProfile> = serde_bridge::from_json(merged_json["socialProfiles"].clone()).unwrap();
+        println!("\nTyped social profiles: {:?}", profiles);
+        assert_eq!(serde_bridge::to_json(&profiles), merged_json["socialProfiles"]);
+
+        let (sender_1, receiver_1) = mpsc::channel::<JC>();
+        serde_bridge::serialise_to_jc(&profiles, sender_1).unwrap();
+        println!("Typed social profiles streamed as JC:");
+        Printer(&deserialise_json(receiver_1), 0);
+
+        // The output of eval can be read straight into typed values
+        let (sender_1, receiver_1) = mpsc::channel::<JC>();
+        let (sender_2, receiver_2) = mpsc::channel::<JC>();
+        let json_to_decode = merged_json.clone();
+        let handle_serialiser = thread::spawn(move || {
+            serialise_json(&json_to_decode, sender_1);
+        });
+        let first_profile = Acessor::ObjectField("socialProfiles".to_string(), Box::new(Acessor::ArrayEntry(0, Box::new(Acessor::End))));
+        let handle_eval = thread::spawn(move || {
+            eval(&first_profile, receiver_1, sender_2).unwrap();
+        });
+        let profile: SocialProfile = serde_bridge::from_jc_stream(receiver_2).unwrap();
+        println!("First social profile decoded from the eval output: {:?}", profile);
+        handle_serialiser.join().unwrap();
+        handle_eval.join().unwrap();
+    }
 
 }